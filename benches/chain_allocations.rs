@@ -0,0 +1,87 @@
+// Counts the heap allocations of one turn with the chain reused across turns, against
+// rebuilding it (and cloning the backend) before every prompt as the REPL used to.
+// Run with `cargo bench --bench chain_allocations`.
+use aichat_cli::llm::LlmBackend;
+use aichat_cli::mock::MockLlm;
+use aichat_cli::{ChatEngine, EngineConfig};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Allocator counting every allocation and the bytes requested
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const TURNS: usize = 200;
+
+// Function to build an engine on the mock backend with about 20 KB of knowledge loaded
+fn engine(dir: &tempfile::TempDir) -> ChatEngine {
+    let entries: Vec<String> = (0..200)
+        .map(|i| {
+            format!(
+                r#"{{"name": "field_{}", "description": "Description of PII field number {}"}}"#,
+                i, i
+            )
+        })
+        .collect();
+    let path = dir.path().join("knowledge.json");
+    std::fs::write(&path, format!("[{}]", entries.join(","))).unwrap();
+
+    let mut config = EngineConfig::from_env();
+    config.cache = None;
+    let mut engine =
+        ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(MockLlm::new()))).unwrap();
+    engine
+        .set_knowledge(&[path.to_string_lossy().into_owned()])
+        .unwrap();
+    engine
+}
+
+// Function to run TURNS prompts and return the average allocations and bytes per turn
+fn measure(engine: &mut ChatEngine, rebuild_each_turn: bool) -> (usize, usize) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let system_prompt = engine.system_prompt.clone();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = BYTES.load(Ordering::Relaxed);
+    for _ in 0..TURNS {
+        if rebuild_each_turn {
+            engine.set_system_prompt(&system_prompt).unwrap();
+        }
+        runtime
+            .block_on(engine.ask("Which fields are PII?"))
+            .unwrap();
+        engine.clear_history();
+    }
+    (
+        (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / TURNS,
+        (BYTES.load(Ordering::Relaxed) - bytes) / TURNS,
+    )
+}
+
+fn main() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut engine = engine(&dir);
+    let (rebuilt_allocations, rebuilt_bytes) = measure(&mut engine, true);
+    let (reused_allocations, reused_bytes) = measure(&mut engine, false);
+    println!("per turn, chain rebuilt: {rebuilt_allocations} allocations, {rebuilt_bytes} bytes");
+    println!("per turn, chain reused:  {reused_allocations} allocations, {reused_bytes} bytes");
+}
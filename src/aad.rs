@@ -0,0 +1,256 @@
+use crate::error::AppError;
+use async_trait::async_trait;
+use azure_core::auth::TokenCredential;
+use azure_identity::DefaultAzureCredential;
+use langchain_rust::llm::openai::{AzureConfig, Config};
+use log::{debug, error};
+use reqwest::header::{HeaderMap, AUTHORIZATION};
+use secrecy::Secret;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Scope of the Entra ID tokens accepted by Azure OpenAI
+const COGNITIVE_SERVICES_SCOPE: &str = "https://cognitiveservices.azure.com/.default";
+
+// Tokens are refreshed this long before they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+// Wait before trying again after a failed refresh
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Source of bearer tokens for AUTH_MODE=aad: a token and how long it stays valid
+#[async_trait]
+pub trait TokenProvider: Send + Sync {
+    async fn fetch_token(&self) -> Result<(String, Duration), AppError>;
+}
+
+// Token provider backed by DefaultAzureCredential (environment, managed identity, Azure CLI)
+pub struct AzureCredentialProvider {
+    credential: DefaultAzureCredential,
+}
+
+impl AzureCredentialProvider {
+    // Function to create the provider from the ambient Azure credentials
+    pub fn new() -> Result<Self, AppError> {
+        let credential = DefaultAzureCredential::create(Default::default())
+            .map_err(|e| AppError::Auth(e.to_string()))?;
+        Ok(AzureCredentialProvider { credential })
+    }
+}
+
+#[async_trait]
+impl TokenProvider for AzureCredentialProvider {
+    async fn fetch_token(&self) -> Result<(String, Duration), AppError> {
+        let token = self
+            .credential
+            .get_token(&[COGNITIVE_SERVICES_SCOPE])
+            .await
+            .map_err(|e| AppError::Auth(e.to_string()))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as i64)
+            .unwrap_or_default();
+        let valid_for = (token.expires_on.unix_timestamp() - now).max(0) as u64;
+        Ok((
+            token.token.secret().to_string(),
+            Duration::from_secs(valid_for),
+        ))
+    }
+}
+
+// Azure OpenAI settings that send an Entra ID bearer token instead of the api-key header;
+// the token is kept fresh by a background task for as long as the config is in use
+#[derive(Clone, Debug)]
+pub struct AadAzureConfig {
+    azure: AzureConfig,
+    token: Arc<RwLock<String>>,
+}
+
+impl AadAzureConfig {
+    // Function to fetch the first token and start refreshing it before it expires
+    pub async fn new(
+        azure: AzureConfig,
+        provider: Arc<dyn TokenProvider>,
+    ) -> Result<Self, AppError> {
+        let (token, valid_for) = provider.fetch_token().await?;
+        let token = Arc::new(RwLock::new(token));
+        tokio::spawn(refresh_token(Arc::downgrade(&token), provider, valid_for));
+        Ok(AadAzureConfig { azure, token })
+    }
+
+    // Function to point the same credentials at another deployment; the token and its
+    // refresher are shared, so nothing is fetched again
+    pub fn with_deployment(&self, deployment_id: &str) -> Self {
+        AadAzureConfig {
+            azure: self.azure.clone().with_deployment_id(deployment_id),
+            token: Arc::clone(&self.token),
+        }
+    }
+}
+
+// Function to replace the shared token shortly before each expiry, stopping once every
+// config holding it has been dropped
+async fn refresh_token(
+    token: Weak<RwLock<String>>,
+    provider: Arc<dyn TokenProvider>,
+    mut valid_for: Duration,
+) {
+    loop {
+        tokio::time::sleep(valid_for.saturating_sub(REFRESH_MARGIN).max(RETRY_DELAY)).await;
+        let Some(shared) = token.upgrade() else {
+            break;
+        };
+        match provider.fetch_token().await {
+            Ok((fresh, next)) => {
+                *shared.write().unwrap_or_else(|e| e.into_inner()) = fresh;
+                valid_for = next;
+                debug!(
+                    "Refreshed the Entra ID token, valid for {}s",
+                    next.as_secs()
+                );
+            }
+            Err(e) => {
+                error!("Failed to refresh the Entra ID token: {}", e);
+                valid_for = Duration::ZERO;
+            }
+        }
+    }
+}
+
+impl Config for AadAzureConfig {
+    fn headers(&self) -> HeaderMap {
+        let mut headers = self.azure.headers();
+        headers.remove("api-key");
+        let token = self.token.read().unwrap_or_else(|e| e.into_inner());
+        if let Ok(value) = format!("Bearer {}", token).parse() {
+            headers.insert(AUTHORIZATION, value);
+        }
+        headers
+    }
+
+    fn url(&self, path: &str) -> String {
+        self.azure.url(path)
+    }
+
+    fn query(&self) -> Vec<(&str, &str)> {
+        self.azure.query()
+    }
+
+    fn api_base(&self) -> &str {
+        self.azure.api_base()
+    }
+
+    fn api_key(&self) -> &Secret<String> {
+        self.azure.api_key()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{ChatEngine, EngineConfig};
+    use crate::llm::{self, SamplingOptions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Hands out "token-1", "token-2", ... and counts how often it was asked
+    struct StubTokens {
+        calls: AtomicUsize,
+        valid_for: Duration,
+    }
+
+    impl StubTokens {
+        fn new(valid_for: Duration) -> Arc<Self> {
+            Arc::new(StubTokens {
+                calls: AtomicUsize::new(0),
+                valid_for,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl TokenProvider for StubTokens {
+        async fn fetch_token(&self) -> Result<(String, Duration), AppError> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok((format!("token-{}", call), self.valid_for))
+        }
+    }
+
+    fn azure(deployment_id: &str) -> AzureConfig {
+        AzureConfig::default()
+            .with_api_base("https://example.openai.azure.com")
+            .with_api_version("2024-02-01")
+            .with_deployment_id(deployment_id)
+    }
+
+    fn authorization(config: &AadAzureConfig) -> String {
+        config.headers()[AUTHORIZATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn the_stub_token_is_sent_as_a_bearer_header() {
+        let tokens = StubTokens::new(Duration::from_secs(3600));
+        let config = AadAzureConfig::new(azure("gpt-4o"), tokens.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(authorization(&config), "Bearer token-1");
+        assert!(!config.headers().contains_key("api-key"));
+        assert_eq!(tokens.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn another_deployment_shares_the_token() {
+        let tokens = StubTokens::new(Duration::from_secs(3600));
+        let config = AadAzureConfig::new(azure("gpt-4o"), tokens.clone())
+            .await
+            .unwrap();
+        let other = config.with_deployment("gpt-4o-mini");
+
+        assert!(other.url("/chat/completions").contains("/gpt-4o-mini/"));
+        assert_eq!(authorization(&other), "Bearer token-1");
+        assert_eq!(tokens.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_token_is_refreshed_before_it_expires() {
+        let tokens = StubTokens::new(REFRESH_MARGIN + Duration::from_secs(60));
+        let config = AadAzureConfig::new(azure("gpt-4o"), tokens.clone())
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(authorization(&config), "Bearer token-2");
+        assert_eq!(tokens.calls.load(Ordering::SeqCst), 2);
+    }
+
+    // A current_thread runtime, as #[tokio::test] uses, must not panic on an Entra ID backend
+    #[tokio::test]
+    async fn rebuilding_the_engine_fetches_no_new_token() {
+        let tokens = StubTokens::new(Duration::from_secs(3600));
+        let config = AadAzureConfig::new(azure("gpt-4o"), tokens.clone())
+            .await
+            .unwrap();
+        let mut engine_config = EngineConfig::from_env();
+        engine_config.cache = None;
+        let mut engine = ChatEngine::with_backend(
+            engine_config,
+            llm::create_openai_aad(config, SamplingOptions::default()).unwrap(),
+        )
+        .unwrap();
+
+        engine.switch_model("gpt-4o-mini").await.unwrap();
+        engine
+            .set_sampling(SamplingOptions {
+                temperature: Some(0.2),
+                max_tokens: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(engine.model, "gpt-4o-mini");
+        assert_eq!(tokens.calls.load(Ordering::SeqCst), 1);
+    }
+}
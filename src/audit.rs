@@ -0,0 +1,51 @@
+use crate::output::ExchangeRecord;
+use log::debug;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// One audited exchange: the --format json record plus when it happened
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    exchange: &'a ExchangeRecord<'a>,
+}
+
+// Append-only JSONL log of every answered prompt (AUDIT_LOG), independent of the
+// debug logging
+pub struct AuditLog {
+    writer: BufWriter<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        debug!("Auditing exchanges to {}", path);
+        Ok(AuditLog {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    // Function to open the log named by AUDIT_LOG, if set
+    pub fn from_env() -> Option<io::Result<Self>> {
+        std::env::var("AUDIT_LOG")
+            .ok()
+            .map(|path| Self::open(&path))
+    }
+
+    // Function to append one exchange; flushed per turn so a crash loses at most one entry
+    pub fn record(&mut self, exchange: &ExchangeRecord) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let line = serde_json::to_string(&AuditRecord {
+            timestamp,
+            exchange,
+        })?;
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,118 @@
+use crate::engine::ChatEngine;
+use futures::{future, stream, StreamExt};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// One answered line of a --batch file, written as a JSON line
+#[derive(Serialize, Debug)]
+pub struct BatchRecord {
+    pub index: usize,
+    pub input: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Function to read the number of prompts answered at once from BATCH_CONCURRENCY (default 4)
+pub fn concurrency_from_env() -> usize {
+    std::env::var("BATCH_CONCURRENCY")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&concurrency| concurrency > 0)
+        .unwrap_or(4)
+}
+
+// Function to answer every non-blank line with bounded concurrency; each prompt is answered
+// without history. Once `active` is cleared (Ctrl-C) no new prompts are started. Records
+// are returned in input order.
+pub async fn run_batch(
+    engine: &ChatEngine,
+    content: &str,
+    concurrency: usize,
+    active: &AtomicBool,
+) -> Vec<BatchRecord> {
+    let prompts = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .enumerate();
+
+    let mut records: Vec<BatchRecord> = stream::iter(prompts)
+        .take_while(|_| future::ready(active.load(Ordering::SeqCst)))
+        .map(|(index, input)| async move {
+            let result = engine.complete_detached(input).await;
+            BatchRecord {
+                index,
+                input: input.to_string(),
+                output: result.as_ref().ok().cloned(),
+                error: result.err().map(|e| e.to_string()),
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    records.sort_by_key(|record| record.index);
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineConfig;
+    use crate::llm::LlmBackend;
+    use crate::mock::MockLlm;
+
+    #[tokio::test]
+    async fn three_lines_are_answered_in_order_without_history() {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(MockLlm::new()))).unwrap();
+        let content = "Is email PII?\n\nIs a zip code PII?\nIs a user id PII?\n";
+
+        let records = run_batch(&engine, content, 2, &AtomicBool::new(true)).await;
+
+        let answered: Vec<(usize, &str, Option<&str>)> = records
+            .iter()
+            .map(|record| {
+                (
+                    record.index,
+                    record.input.as_str(),
+                    record.output.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            answered,
+            [
+                (0, "Is email PII?", Some("Mock answer: Is email PII?")),
+                (
+                    1,
+                    "Is a zip code PII?",
+                    Some("Mock answer: Is a zip code PII?")
+                ),
+                (
+                    2,
+                    "Is a user id PII?",
+                    Some("Mock answer: Is a user id PII?")
+                ),
+            ]
+        );
+        assert!(records.iter().all(|record| record.error.is_none()));
+        assert!(engine.history_list.is_empty());
+    }
+
+    #[tokio::test]
+    async fn nothing_is_started_once_interrupted() {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(MockLlm::new()))).unwrap();
+
+        let records = run_batch(&engine, "one\ntwo\nthree\n", 4, &AtomicBool::new(false)).await;
+
+        assert!(records.is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+use crate::llm::SamplingOptions;
+use langchain_rust::schemas::Message;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+
+// Cached answer, with the model that gave it
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    model: String,
+    response: String,
+}
+
+// On-disk cache of answers keyed on the SHA-256 of the model, sampling settings and
+// assembled prompt
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+}
+
+impl ResponseCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ResponseCache { dir: dir.into() }
+    }
+
+    // Function to enable the cache when LLM_CACHE_DIR is set
+    pub fn from_env() -> Option<Self> {
+        std::env::var("LLM_CACHE_DIR").ok().map(ResponseCache::new)
+    }
+
+    // Function to hash the model, temperature, max_tokens and the system message,
+    // knowledge, history and input, so a different model or sampling is a different entry
+    pub fn key(model: &str, sampling: SamplingOptions, messages: &[Message]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!(
+            "{}\n{:?}\n{:?}\n",
+            model, sampling.temperature, sampling.max_tokens
+        ));
+        for message in messages {
+            hasher.update(serde_json::to_string(message).unwrap_or_default());
+            hasher.update(b"\n");
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn entry_path(&self, model: &str, sampling: SamplingOptions, messages: &[Message]) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", Self::key(model, sampling, messages)))
+    }
+
+    // Function to look up a cached answer for the prompt, model and sampling settings
+    pub fn get(
+        &self,
+        model: &str,
+        sampling: SamplingOptions,
+        messages: &[Message],
+    ) -> Option<String> {
+        let path = self.entry_path(model, sampling, messages);
+        let content = fs::read_to_string(&path).ok()?;
+        match serde_json::from_str::<CacheEntry>(&content) {
+            Ok(entry) => {
+                debug!("Cache hit: {}", path.display());
+                Some(entry.response)
+            }
+            Err(e) => {
+                error!("Ignoring unreadable cache entry {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    // Function to store an answer; failures are logged and otherwise ignored
+    pub fn put(
+        &self,
+        model: &str,
+        sampling: SamplingOptions,
+        messages: &[Message],
+        response: &str,
+    ) {
+        let path = self.entry_path(model, sampling, messages);
+        let entry = CacheEntry {
+            model: model.to_string(),
+            response: response.to_string(),
+        };
+        let result = fs::create_dir_all(&self.dir)
+            .map_err(|e| e.to_string())
+            .and_then(|_| serde_json::to_string_pretty(&entry).map_err(|e| e.to_string()))
+            .and_then(|content| fs::write(&path, content).map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => debug!("Cached answer in {}", path.display()),
+            Err(e) => error!("Failed to write cache entry {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Function to build a one-message prompt
+    fn prompt(input: &str) -> Vec<Message> {
+        vec![Message::new_human_message(input)]
+    }
+
+    #[test]
+    fn a_stored_answer_is_a_hit_and_another_prompt_a_miss() {
+        let dir = TempDir::new().unwrap();
+        let cache = ResponseCache::new(dir.path());
+        let sampling = SamplingOptions::default();
+        cache.put("gpt-4", sampling, &prompt("hello"), "hi there");
+
+        assert_eq!(
+            cache.get("gpt-4", sampling, &prompt("hello")).as_deref(),
+            Some("hi there")
+        );
+        assert_eq!(cache.get("gpt-4", sampling, &prompt("goodbye")), None);
+    }
+
+    #[test]
+    fn each_model_keeps_its_own_entry() {
+        let dir = TempDir::new().unwrap();
+        let cache = ResponseCache::new(dir.path());
+        let sampling = SamplingOptions::default();
+        cache.put("gpt-4", sampling, &prompt("hello"), "from gpt-4");
+        assert_eq!(cache.get("gpt-4o", sampling, &prompt("hello")), None);
+
+        cache.put("gpt-4o", sampling, &prompt("hello"), "from gpt-4o");
+        assert_eq!(
+            cache.get("gpt-4", sampling, &prompt("hello")).as_deref(),
+            Some("from gpt-4")
+        );
+        assert_eq!(
+            cache.get("gpt-4o", sampling, &prompt("hello")).as_deref(),
+            Some("from gpt-4o")
+        );
+    }
+
+    #[test]
+    fn sampling_settings_are_part_of_the_key() {
+        let dir = TempDir::new().unwrap();
+        let cache = ResponseCache::new(dir.path());
+        let cold = SamplingOptions {
+            temperature: Some(0.0),
+            max_tokens: None,
+        };
+        cache.put("gpt-4", cold, &prompt("hello"), "deterministic");
+
+        let warm = SamplingOptions {
+            temperature: Some(1.0),
+            ..cold
+        };
+        let limited = SamplingOptions {
+            max_tokens: Some(50),
+            ..cold
+        };
+        assert_eq!(cache.get("gpt-4", warm, &prompt("hello")), None);
+        assert_eq!(cache.get("gpt-4", limited, &prompt("hello")), None);
+        assert_eq!(
+            cache.get("gpt-4", cold, &prompt("hello")).as_deref(),
+            Some("deterministic")
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use aichat_cli::config::DEFAULT_CONFIG_PATH;
+use aichat_cli::output::OutputFormat;
+use aichat_cli::schema::ResponseSchema;
+use clap::Parser;
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+
+// Command line arguments; with no --prompt the interactive REPL starts
+#[derive(Parser, Debug)]
+#[command(version, about = "AI Chat CLI is command line interface")]
+pub struct Args {
+    /// Config file with default settings (environment variables take precedence)
+    #[arg(long, default_value = DEFAULT_CONFIG_PATH)]
+    pub config: String,
+
+    /// Serve POST /chat on this address (e.g. 127.0.0.1:8080), streaming answers as
+    /// Server-Sent Events, instead of starting the REPL
+    #[cfg(feature = "server")]
+    #[arg(long, conflicts_with_all = ["prompt", "prompt_file", "batch"])]
+    pub serve: Option<String>,
+
+    /// Run a single prompt, print the raw answer and exit
+    #[arg(long)]
+    pub prompt: Option<String>,
+
+    /// Read a single prompt from a file, print the raw answer and exit
+    #[arg(long, conflicts_with = "prompt")]
+    pub prompt_file: Option<String>,
+
+    /// Answer each line of a file as a separate prompt and print JSON lines
+    #[arg(long, conflicts_with_all = ["prompt", "prompt_file"])]
+    pub batch: Option<String>,
+
+    /// Knowledge file (JSON, YAML or CSV) or directory of such files to load before answering;
+    /// repeat to combine sources
+    #[arg(long)]
+    pub knowledge: Vec<String>,
+
+    /// Start with a named persona (system message and, for some, a knowledge file from
+    /// KNOWLEDGE_DIR that --knowledge overrides); .persona lists them. Defaults to PERSONA
+    #[arg(long)]
+    pub persona: Option<String>,
+
+    /// Check that every knowledge file loads, print a table of the results and exit
+    /// (nonzero if any failed)
+    #[arg(long, requires = "knowledge")]
+    pub check_knowledge: bool,
+
+    /// Send only the knowledge entries most relevant to each prompt (RAG_TOP_K of them),
+    /// found by embedding similarity
+    #[arg(long, requires = "knowledge")]
+    pub rag: bool,
+
+    /// Reload the knowledge when its files change on disk
+    #[arg(long, requires = "knowledge")]
+    pub watch_knowledge: bool,
+
+    /// Print a plain "Asking..." line instead of an animated spinner (automatic when
+    /// stdout isn't a terminal)
+    #[arg(long)]
+    pub no_spinner: bool,
+
+    /// Print the answer as it streams in instead of using the typewriter
+    #[arg(long)]
+    pub stream: bool,
+
+    /// Render completed answers as Markdown (ignored when streaming)
+    #[arg(long)]
+    pub markdown: bool,
+
+    /// Output format: colored text, or one JSON object per answer (JSONL)
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Show the active knowledge source and its size before each answer
+    #[arg(long)]
+    pub verbose: bool,
+
+    /// Constrain answers to a fixed JSON shape, asking once more if the model strays
+    #[arg(long, value_enum)]
+    pub schema: Option<ResponseSchema>,
+
+    /// Print the assembled prompt instead of calling the LLM
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Record every line typed into the REPL, with the answers and their latency, to a
+    /// JSON file for --replay
+    #[arg(long, conflicts_with_all = ["prompt", "prompt_file", "batch"])]
+    pub record: Option<String>,
+
+    /// Feed the lines of a --record file back through the REPL and compare the answers
+    /// with the recorded ones; with LLM_PROVIDER=mock the recorded answers are served
+    #[arg(long, conflicts_with_all = ["prompt", "prompt_file", "batch"])]
+    pub replay: Option<String>,
+
+    /// Start the REPL even when stdin is piped, reading its lines as typed input instead
+    /// of taking the whole of stdin as a single prompt
+    #[arg(long, conflicts_with_all = ["prompt", "prompt_file", "batch"])]
+    pub repl: bool,
+}
+
+impl Args {
+    // Function to resolve the one-shot prompt: --prompt, --prompt-file, or piped stdin
+    pub fn one_shot_prompt(&self) -> io::Result<Option<String>> {
+        let stdin = io::stdin();
+        let piped = !stdin.is_terminal();
+        self.one_shot_prompt_from(piped, stdin)
+    }
+
+    // Function to resolve the one-shot prompt given whether stdin is piped. Never in
+    // --batch mode, and piped stdin is left to the REPL with --record, --replay or --repl.
+    fn one_shot_prompt_from(
+        &self,
+        piped: bool,
+        mut stdin: impl Read,
+    ) -> io::Result<Option<String>> {
+        if self.batch.is_some() {
+            return Ok(None);
+        }
+
+        let repl = self.repl || self.record.is_some() || self.replay.is_some();
+        let prompt = if let Some(prompt) = &self.prompt {
+            prompt.clone()
+        } else if let Some(path) = &self.prompt_file {
+            fs::read_to_string(path)?
+        } else if piped && !repl {
+            let mut piped = String::new();
+            stdin.read_to_string(&mut piped)?;
+            piped
+        } else {
+            return Ok(None);
+        };
+
+        let prompt = prompt.trim();
+        Ok((!prompt.is_empty()).then(|| prompt.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aichat_cli::engine::build_messages;
+    use tempfile::TempDir;
+
+    #[test]
+    fn a_prompt_file_reaches_the_prompt_builder() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("question.txt");
+        fs::write(&path, "Which fields hold PII?\nList them.\n").unwrap();
+        let args = Args::parse_from(["aichat-cli", "--prompt-file", &path.to_string_lossy()]);
+
+        let prompt = args.one_shot_prompt().unwrap().unwrap();
+        let messages = build_messages("Be brief.", "", &[], &prompt);
+
+        assert_eq!(
+            messages.last().unwrap().content,
+            "Which fields hold PII?\nList them."
+        );
+    }
+
+    #[test]
+    fn piped_stdin_is_a_prompt_unless_the_repl_reads_it() {
+        let piped = || io::Cursor::new("hello\n.stats\n");
+        let one_shot = |args: &[&str]| {
+            let args = Args::parse_from([&["aichat-cli"], args].concat());
+            args.one_shot_prompt_from(true, piped()).unwrap()
+        };
+
+        assert_eq!(one_shot(&[]).as_deref(), Some("hello\n.stats"));
+        assert_eq!(one_shot(&["--repl"]), None);
+        assert_eq!(one_shot(&["--record", "session.json"]), None);
+        assert_eq!(one_shot(&["--replay", "session.json"]), None);
+        assert_eq!(one_shot(&["--batch", "prompts.txt"]), None);
+    }
+}
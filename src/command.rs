@@ -0,0 +1,214 @@
+// Command names, offered as tab completions and as suggestions for mistyped commands
+pub const COMMANDS: &[&str] = &[
+    "clear",
+    "exit",
+    "quit",
+    ".save",
+    ".save-config",
+    ".load",
+    ".export",
+    ".speed",
+    ".system",
+    ".model",
+    ".models",
+    ".temp",
+    ".maxtokens",
+    ".dryrun",
+    ".tokens",
+    ".retry",
+    ".undo",
+    ".history",
+    ".scrollback",
+    ".copy",
+    ".reload",
+    ".lang",
+    ".persona",
+    ".template",
+    ".templates",
+    ".stats",
+    ".time",
+    ".sources",
+    ".browse",
+    ".diff",
+    ".knowledge",
+    ".check",
+    ".addknowledge",
+    ".clearknowledge",
+];
+
+// A line entered at the REPL prompt
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Exit,
+    Clear,
+    Save(&'a str),
+    SaveConfig(&'a str),
+    Load(&'a str),
+    Export(&'a str),
+    System(&'a str),
+    Models,
+    Model(&'a str),
+    Temp(&'a str),
+    MaxTokens(&'a str),
+    Speed(&'a str),
+    Tokens,
+    DryRun,
+    Retry(&'a str),
+    Undo,
+    History(&'a str),
+    Scrollback,
+    Copy,
+    Reload,
+    Lang(&'a str),
+    Persona(&'a str),
+    Template(&'a str),
+    Templates,
+    Stats,
+    Time(&'a str),
+    Sources,
+    Browse,
+    Diff(&'a str),
+    Knowledge(&'a str),
+    Check,
+    AddKnowledge(&'a str),
+    ClearKnowledge,
+    Unknown(&'a str, Option<&'static str>),
+    Prompt(&'a str),
+}
+
+// Function to parse a line into a command; command names are matched case-insensitively
+// and anything that isn't a command is a prompt for the LLM
+pub fn parse_command(input: &str) -> Command<'_> {
+    let input = input.trim();
+    let (name, arg) = match input.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (input, ""),
+    };
+
+    match (name.to_lowercase().as_str(), arg.is_empty()) {
+        ("exit" | "quit" | ":q", true) => Command::Exit,
+        ("clear" | "reset", true) => Command::Clear,
+        (".save", _) => Command::Save(arg),
+        (".save-config", _) => Command::SaveConfig(arg),
+        (".load", _) => Command::Load(arg),
+        (".export", _) => Command::Export(arg),
+        (".system", _) => Command::System(arg),
+        (".models", true) => Command::Models,
+        (".model", _) => Command::Model(arg),
+        (".temp", _) => Command::Temp(arg),
+        (".maxtokens", _) => Command::MaxTokens(arg),
+        (".speed", _) => Command::Speed(arg),
+        (".tokens", true) => Command::Tokens,
+        (".dryrun", true) => Command::DryRun,
+        (".retry", _) => Command::Retry(arg),
+        (".undo", true) => Command::Undo,
+        (".history", _) => Command::History(arg),
+        (".scrollback", true) => Command::Scrollback,
+        (".copy", true) => Command::Copy,
+        (".reload", true) => Command::Reload,
+        (".lang", _) => Command::Lang(arg),
+        (".persona", _) => Command::Persona(arg),
+        (".template", _) => Command::Template(arg),
+        (".templates", true) => Command::Templates,
+        (".stats", true) => Command::Stats,
+        (".time", _) => Command::Time(arg),
+        (".sources", true) => Command::Sources,
+        (".browse", true) => Command::Browse,
+        (".diff", _) => Command::Diff(arg),
+        (".knowledge", _) => Command::Knowledge(arg),
+        (".check", true) => Command::Check,
+        (".addknowledge", false) => Command::AddKnowledge(arg),
+        (".clearknowledge", true) => Command::ClearKnowledge,
+        // A leading dot is a command attempt, never a prompt
+        (name, _) if name.starts_with('.') => Command::Unknown(input, closest_command(name)),
+        _ => Command::Prompt(input),
+    }
+}
+
+// Function to find the known command closest to a mistyped one (at most 2 edits away);
+// names are compared without their leading dot, so `.clera` still finds `clear`
+fn closest_command(name: &str) -> Option<&'static str> {
+    let name = name.trim_start_matches('.');
+    COMMANDS
+        .iter()
+        .map(|command| (levenshtein(name, command.trim_start_matches('.')), *command))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, command)| command)
+}
+
+// Function to count the single-character edits between two strings
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_aliases_are_accepted_in_any_case() {
+        for input in [
+            "exit", "Exit", "EXIT", "quit", "QUIT", ":q", ":Q", "  exit  ",
+        ] {
+            assert_eq!(parse_command(input), Command::Exit, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn clear_aliases_are_accepted_in_any_case() {
+        for input in ["clear", "Clear", "CLEAR", "reset", "Reset"] {
+            assert_eq!(parse_command(input), Command::Clear, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn mistyped_commands_get_a_suggestion() {
+        assert_eq!(
+            parse_command(".clera"),
+            Command::Unknown(".clera", Some("clear"))
+        );
+        assert_eq!(
+            parse_command(".exti"),
+            Command::Unknown(".exti", Some("exit"))
+        );
+        assert_eq!(
+            parse_command(".hisotry 5"),
+            Command::Unknown(".hisotry 5", Some(".history"))
+        );
+        assert_eq!(
+            parse_command(".sats"),
+            Command::Unknown(".sats", Some(".stats"))
+        );
+    }
+
+    #[test]
+    fn unrelated_commands_get_no_suggestion() {
+        assert_eq!(
+            parse_command(".frobnicate"),
+            Command::Unknown(".frobnicate", None)
+        );
+    }
+
+    #[test]
+    fn aliases_followed_by_text_are_prompts() {
+        assert_eq!(
+            parse_command("exit strategy for Kafka"),
+            Command::Prompt("exit strategy for Kafka")
+        );
+        assert_eq!(
+            parse_command("reset my password"),
+            Command::Prompt("reset my password")
+        );
+    }
+}
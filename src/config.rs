@@ -0,0 +1,229 @@
+use crate::error::AppError;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Config file read when --config is not given
+pub const DEFAULT_CONFIG_PATH: &str = "aichat.toml";
+
+// Placeholder written for the API key by .save-config, so the key itself never lands in
+// the file; a `${VAR}` value is read from that environment variable when loaded
+pub const API_KEY_REFERENCE: &str = "${OPEN_AI_SERVICE_KEY}";
+
+// Settings that can be kept in aichat.toml instead of environment variables
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_base: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployment_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub openai_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ollama_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typewriter_delay_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub knowledge_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+impl AppConfig {
+    // Function to read a config file; a missing file gives an empty config
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(AppConfig::default()),
+            Err(e) => return Err(e.into()),
+        };
+        toml::from_str(&content)
+            .map_err(|e| AppError::Config(format!("Invalid config {}: {}", path.display(), e)))
+    }
+
+    // Function to write the config as TOML (.save-config); values that are None are left out
+    pub fn save(&self, path: &Path) -> Result<(), AppError> {
+        let content = toml::to_string_pretty(self)
+            .map_err(|e| AppError::Config(format!("Cannot serialize config: {}", e)))?;
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    // Function to store the model under the key the provider reads it from; the mock
+    // provider's model isn't kept
+    pub fn set_model(&mut self, provider: &str, model: &str) {
+        let setting = match provider {
+            "azure" => &mut self.deployment_id,
+            "openai" => &mut self.openai_model,
+            "ollama" => &mut self.ollama_model,
+            _ => return,
+        };
+        *setting = Some(model.to_string());
+    }
+
+    // Function to pair each setting with the environment variable it stands in for
+    fn env_values(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("OPEN_AI_SERVICE_URL", self.api_base.clone()),
+            ("OPEN_AI_SERVICE_KEY", self.api_key.clone()),
+            ("OPEN_AI_DEPLOYMENT_ID", self.deployment_id.clone()),
+            ("OPENAI_MODEL", self.openai_model.clone()),
+            ("OLLAMA_MODEL", self.ollama_model.clone()),
+            ("OPEN_AI_API_VERSION", self.api_version.clone()),
+            ("LLM_TEMPERATURE", self.temperature.map(|t| t.to_string())),
+            ("LLM_MAX_TOKENS", self.max_tokens.map(|max| max.to_string())),
+            (
+                "TYPEWRITER_DELAY_MS",
+                self.typewriter_delay_ms.map(|ms| ms.to_string()),
+            ),
+            ("KNOWLEDGE_DIR", self.knowledge_dir.clone()),
+            ("PERSONA", self.persona.clone()),
+            ("RESPONSE_LANGUAGE", self.language.clone()),
+        ]
+    }
+
+    // Function to pick the environment variables the file fills in: those it sets that
+    // `is_set` says the environment doesn't already have
+    fn env_overrides(&self, is_set: impl Fn(&str) -> bool) -> Vec<(&'static str, String)> {
+        self.env_values()
+            .into_iter()
+            .filter(|(var, _)| !is_set(var))
+            .filter_map(|(var, value)| Some((var, expand_reference(&value?)?)))
+            .collect()
+    }
+
+    // Function to fill in the environment variables that are not already set, so the
+    // environment wins over the file and the file over the built-in defaults (command line
+    // flags are applied later and win over both). Changing the environment is only sound
+    // while no other thread reads it, so this must run before the async runtime starts.
+    pub fn apply_to_env(&self) {
+        for (var, value) in self.env_overrides(|var| std::env::var_os(var).is_some()) {
+            std::env::set_var(var, value);
+        }
+    }
+}
+
+// Function to resolve a `${VAR}` value from the environment (None when VAR is unset);
+// any other value is returned as is
+fn expand_reference(value: &str) -> Option<String> {
+    match value
+        .strip_prefix("${")
+        .and_then(|rest| rest.strip_suffix('}'))
+    {
+        Some(var) => std::env::var(var).ok(),
+        None => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const SAMPLE: &str = r#"
+api_base = "https://file.openai.azure.com"
+deployment_id = "gpt-4-from-file"
+temperature = 0.2
+typewriter_delay_ms = 0
+knowledge_dir = "file-dataset"
+"#;
+
+    #[test]
+    fn the_environment_wins_over_the_file() {
+        let config: AppConfig = toml::from_str(SAMPLE).unwrap();
+
+        let overrides = config.env_overrides(|var| var == "OPEN_AI_DEPLOYMENT_ID");
+
+        assert_eq!(
+            overrides,
+            [
+                (
+                    "OPEN_AI_SERVICE_URL",
+                    "https://file.openai.azure.com".to_string()
+                ),
+                ("LLM_TEMPERATURE", "0.2".to_string()),
+                ("TYPEWRITER_DELAY_MS", "0".to_string()),
+                ("KNOWLEDGE_DIR", "file-dataset".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn settings_missing_from_the_file_keep_their_defaults() {
+        let config: AppConfig = toml::from_str(SAMPLE).unwrap();
+
+        let overrides = config.env_overrides(|_| false);
+
+        // Nothing is set for what the file leaves out, so the built-in defaults apply
+        let vars: Vec<&str> = overrides.iter().map(|(var, _)| *var).collect();
+        assert!(!vars.contains(&"OPEN_AI_API_VERSION"));
+        assert!(!vars.contains(&"LLM_MAX_TOKENS"));
+        assert!(vars.contains(&"OPEN_AI_DEPLOYMENT_ID"));
+    }
+
+    #[test]
+    fn a_missing_file_is_an_empty_config() {
+        let dir = TempDir::new().unwrap();
+
+        let config = AppConfig::load(&dir.path().join("aichat.toml")).unwrap();
+
+        assert_eq!(config, AppConfig::default());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("aichat.toml");
+        fs::write(&path, "deployment = \"gpt-4\"\n").unwrap();
+
+        assert!(matches!(AppConfig::load(&path), Err(AppError::Config(_))));
+    }
+
+    #[test]
+    fn the_model_is_kept_under_the_provider_key() {
+        let mut config = AppConfig::default();
+        config.set_model("openai", "gpt-4o");
+        config.set_model("ollama", "llama3");
+        config.set_model("mock", "mock");
+
+        assert_eq!(config.deployment_id, None);
+        assert_eq!(config.openai_model.as_deref(), Some("gpt-4o"));
+        assert_eq!(config.ollama_model.as_deref(), Some("llama3"));
+    }
+
+    #[test]
+    fn a_saved_config_loads_back_the_same() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("aichat.toml");
+        let mut config = AppConfig {
+            api_base: Some("https://example.openai.azure.com".to_string()),
+            api_key: Some(API_KEY_REFERENCE.to_string()),
+            temperature: Some(0.5),
+            max_tokens: Some(256),
+            typewriter_delay_ms: Some(0),
+            persona: Some("pii-classifier".to_string()),
+            language: Some("Korean".to_string()),
+            ..AppConfig::default()
+        };
+        config.set_model("azure", "gpt-4o-deployment");
+
+        config.save(&path).unwrap();
+
+        assert_eq!(AppConfig::load(&path).unwrap(), config);
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("deployment_id = \"gpt-4o-deployment\""));
+        assert!(content.contains("api_key = \"${OPEN_AI_SERVICE_KEY}\""));
+    }
+}
@@ -0,0 +1,1275 @@
+use log::{debug, warn};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+use thiserror::Error;
+
+// Errors that can occur while loading a knowledge file
+#[derive(Debug, Error)]
+pub enum KnowledgeError {
+    #[error("failed to read knowledge file: {0}")]
+    Io(#[from] io::Error),
+    #[error("failed to parse knowledge file: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("failed to parse knowledge YAML: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("failed to parse knowledge CSV: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("knowledge file contains no entries")]
+    Empty,
+    #[error("knowledge file not found: {0} (also looked in KNOWLEDGE_DIR)")]
+    NotFound(String),
+    #[error("failed to fetch knowledge from {url}: {reason}")]
+    Fetch { url: String, reason: String },
+}
+
+// Function to read the base directory for knowledge files from KNOWLEDGE_DIR (default dataset)
+pub fn knowledge_dir() -> PathBuf {
+    PathBuf::from(std::env::var("KNOWLEDGE_DIR").unwrap_or_else(|_| "dataset".to_string()))
+}
+
+// Function to resolve a knowledge source: the path as given if it exists, otherwise a
+// relative path under KNOWLEDGE_DIR
+pub fn resolve_knowledge_path(source: &str) -> Result<String, KnowledgeError> {
+    resolve_knowledge_path_in(source, &knowledge_dir())
+}
+
+// Function to resolve a knowledge source against the given base directory
+pub(crate) fn resolve_knowledge_path_in(
+    source: &str,
+    base: &Path,
+) -> Result<String, KnowledgeError> {
+    if is_url(source) {
+        return Ok(source.to_string());
+    }
+    let path = Path::new(source);
+    if path.exists() {
+        return Ok(source.to_string());
+    }
+    if path.is_relative() {
+        let candidate = base.join(path);
+        if candidate.exists() {
+            debug!("Resolved {} to {}", source, candidate.display());
+            return Ok(candidate.to_string_lossy().into_owned());
+        }
+    }
+    Err(KnowledgeError::NotFound(source.to_string()))
+}
+
+// Function to read a knowledge file's extension in lowercase
+fn file_extension(file_path: &str) -> String {
+    // URLs are judged by their path, without query or fragment
+    let file_path = file_path.split(['?', '#']).next().unwrap_or_default();
+    Path::new(file_path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .unwrap_or_default()
+}
+
+// Function to check whether a directory entry is a knowledge file (JSON, YAML or CSV)
+fn is_knowledge_file(path: &Path) -> bool {
+    path.is_file()
+        && matches!(
+            file_extension(&path.to_string_lossy()).as_str(),
+            "json" | "yaml" | "yml" | "csv"
+        )
+}
+
+// Function to parse CSV rows into JSON objects keyed by lowercase header, skipping blank rows
+fn parse_csv(content: &str) -> Result<Value, KnowledgeError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let headers: Vec<String> = reader
+        .headers()?
+        .iter()
+        .map(|header| header.to_lowercase())
+        .collect();
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        if record.iter().all(|field| field.is_empty()) {
+            continue;
+        }
+
+        let row: Map<String, Value> = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(header, field)| (header.clone(), Value::String(field.to_string())))
+            .collect();
+        rows.push(Value::Object(row));
+    }
+    Ok(Value::Array(rows))
+}
+
+// Key of an optional top-level string in a knowledge file with instructions for the
+// system message; it is taken out of the knowledge itself
+const SYSTEM_HINT_KEY: &str = "system_hint";
+
+// Key of optional behavioural instructions in a knowledge file (a string or a list of
+// strings, e.g. formatting rules); they join the system message instead of the knowledge
+const BEHAVIOR_KEY: &str = "behavior";
+
+// Knowledge ready for the prompt, with the number of entries left out to respect
+// KNOWLEDGE_MAX_CHARS, and the system hints and behaviour instructions of its sources
+#[derive(Debug, Clone, Default)]
+pub struct LoadedKnowledge {
+    pub text: String,
+    pub omitted: usize,
+    pub hints: Vec<String>,
+    pub behavior: Vec<String>,
+}
+
+// Function to read the knowledge size limit from KNOWLEDGE_MAX_CHARS (unset: no limit)
+pub fn max_chars_from_env() -> Option<usize> {
+    std::env::var("KNOWLEDGE_MAX_CHARS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+}
+
+// Function to measure a value as it appears in the prompt (pretty-printed)
+fn rendered_len(value: &Value) -> usize {
+    serde_json::to_string_pretty(value)
+        .map(|text| text.chars().count())
+        .unwrap_or_default()
+}
+
+// Function to find the largest array (by rendered size) anywhere in a value, as a JSON
+// pointer; for knowledge like {"topics": [...]} that is where the bulk of it is
+fn largest_array(value: &Value) -> Option<String> {
+    fn visit(value: &Value, pointer: String, largest: &mut Option<(usize, String)>) {
+        match value {
+            Value::Array(items) => {
+                let size = rendered_len(value);
+                if largest.as_ref().is_none_or(|(largest, _)| size > *largest) {
+                    *largest = Some((size, pointer.clone()));
+                }
+                for (index, item) in items.iter().enumerate() {
+                    visit(item, format!("{}/{}", pointer, index), largest);
+                }
+            }
+            Value::Object(map) => {
+                for (key, member) in map {
+                    let key = key.replace('~', "~0").replace('/', "~1");
+                    visit(member, format!("{}/{}", pointer, key), largest);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut largest = None;
+    visit(value, String::new(), &mut largest);
+    largest.map(|(_, pointer)| pointer)
+}
+
+// Function to keep the most leading entries of the array or object at `pointer` that let
+// the whole value fit in max_chars. Returns the number of entries dropped.
+fn truncate_to_fit(value: &mut Value, pointer: &str, max_chars: usize) -> usize {
+    let Some(full) = value.pointer(pointer).cloned() else {
+        return 0;
+    };
+    let leading = |count: usize| match &full {
+        Value::Array(items) => Value::Array(items[..count].to_vec()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .take(count)
+                .map(|(key, member)| (key.clone(), member.clone()))
+                .collect(),
+        ),
+        other => other.clone(),
+    };
+    let len = match &full {
+        Value::Array(items) => items.len(),
+        Value::Object(map) => map.len(),
+        _ => return 0,
+    };
+
+    // Binary search for the largest count that fits, measuring the whole rendered value
+    let (mut fits, mut too_many) = (0, len + 1);
+    while too_many - fits > 1 {
+        let count = (fits + too_many) / 2;
+        if let Some(target) = value.pointer_mut(pointer) {
+            *target = leading(count);
+        }
+        if rendered_len(value) <= max_chars {
+            fits = count;
+        } else {
+            too_many = count;
+        }
+    }
+    if let Some(target) = value.pointer_mut(pointer) {
+        *target = leading(fits);
+    }
+    len - fits
+}
+
+// Function to keep only the leading entries that fit in max_chars once rendered, so
+// truncation never cuts an entry in half. The largest array is shortened first, so a
+// single long list keeps its first items; if the rest is still too long, trailing
+// top-level entries go too. Returns the number of entries dropped.
+fn limit_entries(value: &mut Value, max_chars: usize) -> usize {
+    if rendered_len(value) <= max_chars {
+        return 0;
+    }
+    let mut omitted = 0;
+    if let Some(pointer) = largest_array(value) {
+        omitted += truncate_to_fit(value, &pointer, max_chars);
+    }
+    if rendered_len(value) > max_chars {
+        omitted += truncate_to_fit(value, "", max_chars);
+    }
+    omitted
+}
+
+// Keys of the PII description lists deduplicated on load
+const DESCRIPTION_KEYS: &[&str] = &["pii_descriptions", "exclude_pii_descriptions"];
+
+// Key of a business module's MQ topic list, whose topic names are deduplicated on load
+const TOPICS_KEY: &str = "topics";
+
+// Function to drop repeated PII descriptions and, within each business module, repeated MQ
+// topic names, comparing them trimmed and case-insensitively and keeping the first
+// occurrence; other lists are left as they are. Returns the number of duplicates dropped.
+fn dedupe_entries(value: &mut Value) -> usize {
+    match value {
+        Value::Object(map) => {
+            let mut dropped = 0;
+            for (key, item) in map.iter_mut() {
+                if let Value::Array(items) = item {
+                    if DESCRIPTION_KEYS.contains(&key.as_str()) {
+                        dropped += retain_first(items, Value::as_str);
+                    } else if key == TOPICS_KEY {
+                        dropped += retain_first(items, topic_name);
+                    }
+                }
+                dropped += dedupe_entries(item);
+            }
+            dropped
+        }
+        Value::Array(items) => items.iter_mut().map(dedupe_entries).sum(),
+        _ => 0,
+    }
+}
+
+// Function to keep the first of the items sharing a name (trimmed, case-insensitive);
+// items without a name are kept. Returns the number of items dropped.
+fn retain_first(items: &mut Vec<Value>, name: impl Fn(&Value) -> Option<&str>) -> usize {
+    let mut seen = std::collections::HashSet::new();
+    let before = items.len();
+    items.retain(|item| match name(item) {
+        Some(text) => seen.insert(text.trim().to_lowercase()),
+        None => true,
+    });
+    before - items.len()
+}
+
+// Function to read the name of an MQ topic given as a string or as an object with a
+// `name` or `topic` field
+fn topic_name(topic: &Value) -> Option<&str> {
+    match topic {
+        Value::String(name) => Some(name),
+        Value::Object(fields) => fields
+            .get("name")
+            .or_else(|| fields.get("topic"))
+            .and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+// Function to check whether a knowledge source is an http(s) URL rather than a path
+pub fn is_url(source: &str) -> bool {
+    let lower = source.to_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+// Bodies downloaded from knowledge URLs, keyed by URL; each engine keeps its own, so a
+// URL is fetched once per session
+pub type FetchedUrls = HashMap<String, String>;
+
+// Function to download the URL sources among `sources` into `fetched` ahead of loading
+// them, which reads the bodies from there. A URL already in `fetched` is only downloaded
+// again if `refresh` is set (.reload); paths are left alone.
+pub async fn fetch_knowledge_urls(
+    fetched: &mut FetchedUrls,
+    sources: &[String],
+    refresh: bool,
+) -> Result<(), KnowledgeError> {
+    let missing: Vec<&String> = sources
+        .iter()
+        .filter(|source| is_url(source) && (refresh || !fetched.contains_key(*source)))
+        .collect();
+    let bodies = futures::future::join_all(missing.iter().map(|url| fetch_body(url))).await;
+    for (url, body) in missing.into_iter().zip(bodies) {
+        let body = body?;
+        debug!("Fetched {} bytes of knowledge from {}", body.len(), url);
+        fetched.insert(url.clone(), body);
+    }
+    Ok(())
+}
+
+// Function to take the body of a knowledge URL from the fetched bodies
+fn fetched_url(fetched: &FetchedUrls, url: &str) -> Result<String, KnowledgeError> {
+    fetched
+        .get(url)
+        .cloned()
+        .ok_or_else(|| KnowledgeError::Fetch {
+            url: url.to_string(),
+            reason: "not fetched yet".to_string(),
+        })
+}
+
+// Function to download a knowledge body, failing on non-success statuses and after
+// KNOWLEDGE_FETCH_TIMEOUT_SECS (default 30)
+async fn fetch_body(url: &str) -> Result<String, KnowledgeError> {
+    let timeout = Duration::from_secs(
+        std::env::var("KNOWLEDGE_FETCH_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(30),
+    );
+    let fetch_error = |reason: String| KnowledgeError::Fetch {
+        url: url.to_string(),
+        reason,
+    };
+    let describe = |e: reqwest::Error| {
+        if e.is_timeout() {
+            fetch_error(format!("timed out after {}s", timeout.as_secs()))
+        } else {
+            fetch_error(e.to_string())
+        }
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(describe)?;
+    let response = client.get(url).send().await.map_err(describe)?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(fetch_error(format!("HTTP {}", status)));
+    }
+    response.text().await.map_err(describe)
+}
+
+// Function to parse a JSON, YAML or CSV file (or URL) by its extension
+fn parse_file(file_path: &str, fetched: &FetchedUrls) -> Result<Value, KnowledgeError> {
+    let file_content = if is_url(file_path) {
+        fetched_url(fetched, file_path)?
+    } else {
+        fs::read_to_string(file_path)?
+    };
+    Ok(match file_extension(file_path).as_str() {
+        "yaml" | "yml" => serde_yaml::from_str(&file_content)?,
+        "csv" => parse_csv(&file_content)?,
+        _ => serde_json::from_str(&file_content)?,
+    })
+}
+
+// A knowledge file parsed once: its entries, and the system hint and behaviour
+// instructions taken out of them
+struct ParsedKnowledge {
+    value: Value,
+    hint: Option<String>,
+    behavior: Vec<String>,
+}
+
+// Function to parse a JSON, YAML or CSV knowledge file, taking out its system_hint and
+// behavior, rejecting files without entries and dropping duplicate strings
+fn parse_knowledge_file(
+    file_path: &str,
+    fetched: &FetchedUrls,
+) -> Result<ParsedKnowledge, KnowledgeError> {
+    let mut parsed_json = parse_file(file_path, fetched)?;
+    let (hint, behavior) = match &mut parsed_json {
+        Value::Object(map) => (map.remove(SYSTEM_HINT_KEY), map.remove(BEHAVIOR_KEY)),
+        _ => (None, None),
+    };
+    let hint = match hint {
+        Some(Value::String(hint)) if !hint.trim().is_empty() => Some(hint),
+        _ => None,
+    };
+    let behavior = match behavior {
+        Some(Value::String(text)) => vec![text],
+        Some(Value::Array(items)) => items
+            .into_iter()
+            .filter_map(|item| match item {
+                Value::String(text) => Some(text),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let is_empty = match &parsed_json {
+        Value::Null => true,
+        Value::Object(map) => map.is_empty(),
+        Value::Array(items) => items.is_empty(),
+        Value::String(text) => text.trim().is_empty(),
+        _ => false,
+    };
+    if is_empty {
+        return Err(KnowledgeError::Empty);
+    }
+
+    let duplicates = dedupe_entries(&mut parsed_json);
+    if duplicates > 0 {
+        debug!(
+            "Dropped {} duplicate entries from {}",
+            duplicates, file_path
+        );
+    }
+    Ok(ParsedKnowledge {
+        value: parsed_json,
+        hint,
+        behavior,
+    })
+}
+
+impl LoadedKnowledge {
+    // Function to add the system hint and behaviour instructions of another file, in order
+    // and without repeats
+    fn add_instructions(&mut self, hint: Option<String>, behavior: Vec<String>) {
+        if let Some(hint) = hint {
+            if !self.hints.contains(&hint) {
+                self.hints.push(hint);
+            }
+        }
+        for instruction in behavior {
+            if !instruction.trim().is_empty() && !self.behavior.contains(&instruction) {
+                self.behavior.push(instruction);
+            }
+        }
+    }
+}
+
+// Function to load knowledge from a JSON, YAML or CSV file (Refactor knowledge loading logic)
+pub fn load_knowledge(file_path: &str) -> Result<String, KnowledgeError> {
+    load_knowledge_limited(
+        file_path,
+        None,
+        &FetchedUrls::new(),
+        &mut LoadedKnowledge::default(),
+    )
+}
+
+// Function to load a knowledge file, keeping it within max_chars by dropping trailing
+// entries; the number dropped is added to `loaded` and noted at the end of the text, and
+// so are the file's system hint and behaviour instructions
+fn load_knowledge_limited(
+    file_path: &str,
+    max_chars: Option<usize>,
+    fetched: &FetchedUrls,
+    loaded: &mut LoadedKnowledge,
+) -> Result<String, KnowledgeError> {
+    let ParsedKnowledge {
+        value: mut parsed_json,
+        hint,
+        behavior,
+    } = parse_knowledge_file(file_path, fetched)?;
+    loaded.add_instructions(hint, behavior);
+    let dropped = max_chars.map_or(0, |max_chars| limit_entries(&mut parsed_json, max_chars));
+
+    let mut knowledge = String::new();
+    knowledge.push_str(&serde_json::to_string_pretty(&parsed_json)?);
+    if dropped > 0 {
+        debug!(
+            "Omitted {} entries of {} (KNOWLEDGE_MAX_CHARS)",
+            dropped, file_path
+        );
+        knowledge.push_str(&format!(
+            "\n\n[{} more entries omitted to respect KNOWLEDGE_MAX_CHARS]",
+            dropped
+        ));
+        loaded.omitted += dropped;
+    }
+
+    Ok(knowledge)
+}
+
+// Function to load several knowledge files into one buffer with a section per source;
+// the combined text is held to KNOWLEDGE_MAX_CHARS, so earlier sources keep more. Each
+// file is parsed once for its entries, system hint and behaviour instructions; URL sources
+// are read from `fetched`.
+pub fn load_combined_knowledge(
+    sources: &[String],
+    fetched: &FetchedUrls,
+) -> Result<LoadedKnowledge, KnowledgeError> {
+    let max_chars = max_chars_from_env();
+    let mut loaded = LoadedKnowledge::default();
+    if let [source] = sources {
+        loaded.text = load_source(source, max_chars, fetched, &mut loaded)?;
+        return Ok(loaded);
+    }
+
+    for source in sources {
+        let remaining = remaining_chars(max_chars, &loaded.text);
+        let content = load_source(source, remaining, fetched, &mut loaded)?;
+        push_section(&mut loaded.text, source, &content);
+    }
+    Ok(loaded)
+}
+
+// Function to work out how much of the size limit is left after the text so far
+fn remaining_chars(max_chars: Option<usize>, text: &str) -> Option<usize> {
+    max_chars.map(|max_chars| max_chars.saturating_sub(text.chars().count()))
+}
+
+// Function to load a knowledge source, which may be a single file or a directory
+fn load_source(
+    source: &str,
+    max_chars: Option<usize>,
+    fetched: &FetchedUrls,
+    loaded: &mut LoadedKnowledge,
+) -> Result<String, KnowledgeError> {
+    if Path::new(source).is_dir() {
+        load_dir_limited(source, max_chars, loaded)
+    } else {
+        load_knowledge_limited(source, max_chars, fetched, loaded)
+    }
+}
+
+// Function to load every knowledge file in a directory with a section per file name
+pub fn load_knowledge_dir(dir_path: &str) -> Result<String, KnowledgeError> {
+    load_dir_limited(dir_path, None, &mut LoadedKnowledge::default())
+}
+
+// Function to load a directory of knowledge files, held to max_chars together
+fn load_dir_limited(
+    dir_path: &str,
+    max_chars: Option<usize>,
+    loaded: &mut LoadedKnowledge,
+) -> Result<String, KnowledgeError> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir_path)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    paths.sort();
+
+    let mut knowledge = String::new();
+    for path in paths {
+        if !is_knowledge_file(&path) {
+            debug!("Skipping non-knowledge file {}", path.display());
+            continue;
+        }
+
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let remaining = remaining_chars(max_chars, &knowledge);
+        let content = load_knowledge_limited(
+            &path.to_string_lossy(),
+            remaining,
+            &FetchedUrls::new(),
+            loaded,
+        )?;
+        push_section(&mut knowledge, &file_name, &content);
+    }
+
+    if knowledge.is_empty() {
+        return Err(KnowledgeError::Empty);
+    }
+    Ok(knowledge)
+}
+
+// Function to load every knowledge file in KNOWLEDGE_DIR in parallel, keyed by path, so
+// switching sources later needs no disk access; files that fail to load are left out with
+// a warning
+pub async fn preload_knowledge_dir() -> HashMap<String, LoadedKnowledge> {
+    preload_knowledge_in(&knowledge_dir()).await
+}
+
+// Function to preload the knowledge files of a given directory (see preload_knowledge_dir)
+pub(crate) async fn preload_knowledge_in(dir: &Path) -> HashMap<String, LoadedKnowledge> {
+    let dir = dir.to_string_lossy().into_owned();
+    let files = match source_files(std::slice::from_ref(&dir)) {
+        Ok(files) => files,
+        Err(e) => {
+            warn!("Not preloading knowledge from {}: {}", dir, e);
+            return HashMap::new();
+        }
+    };
+
+    let tasks = files.into_iter().map(|file| {
+        tokio::task::spawn_blocking(move || {
+            let loaded = load_combined_knowledge(std::slice::from_ref(&file), &FetchedUrls::new());
+            (file, loaded)
+        })
+    });
+    let mut preloaded = HashMap::new();
+    for task in futures::future::join_all(tasks).await {
+        match task {
+            Ok((file, Ok(loaded))) => {
+                preloaded.insert(file, loaded);
+            }
+            Ok((file, Err(e))) => warn!("Skipping knowledge file {}: {}", file, e),
+            Err(e) => warn!("Knowledge preload task failed: {}", e),
+        }
+    }
+    debug!("Preloaded {} knowledge files from {}", preloaded.len(), dir);
+    preloaded
+}
+
+// Function to list the knowledge files in KNOWLEDGE_DIR, offered by the `.knowledge` picker
+pub fn available_knowledge_sources() -> Vec<String> {
+    available_knowledge_sources_in(&knowledge_dir())
+}
+
+// Function to list the knowledge files of a given directory in name order; a missing or
+// unreadable directory offers none
+fn available_knowledge_sources_in(dir: &Path) -> Vec<String> {
+    if !dir.is_dir() {
+        return Vec::new();
+    }
+    source_files(&[dir.to_string_lossy().into_owned()]).unwrap_or_else(|e| {
+        warn!("Cannot list knowledge files in {}: {}", dir.display(), e);
+        Vec::new()
+    })
+}
+
+// Function to list the files behind knowledge sources: a file as-is, a directory as its
+// JSON, YAML and CSV files in name order
+fn source_files(sources: &[String]) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for source in sources {
+        if Path::new(source).is_dir() {
+            let mut paths: Vec<PathBuf> = fs::read_dir(source)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| is_knowledge_file(path))
+                .collect();
+            paths.sort();
+            files.extend(paths.iter().map(|path| path.to_string_lossy().into_owned()));
+        } else {
+            files.push(source.clone());
+        }
+    }
+    Ok(files)
+}
+
+// Function to count the top-level entries (array items or object members) of a knowledge
+// source by parsing it, without rendering it
+pub fn count_entries(source: &str, fetched: &FetchedUrls) -> Result<usize, KnowledgeError> {
+    let mut count = 0;
+    for file in source_files(&[source.to_string()])? {
+        count += count_file_entries(&file, fetched)?;
+    }
+    Ok(count)
+}
+
+// Function to count the top-level entries of a single knowledge file
+fn count_file_entries(file: &str, fetched: &FetchedUrls) -> Result<usize, KnowledgeError> {
+    Ok(match parse_knowledge_file(file, fetched)?.value {
+        Value::Array(items) => items.len(),
+        Value::Object(map) => map.len(),
+        _ => 1,
+    })
+}
+
+// Outcome of checking one knowledge file: its entry count or why it can't be loaded
+#[derive(Debug)]
+pub struct SourceCheck {
+    pub path: String,
+    pub result: Result<usize, KnowledgeError>,
+}
+
+// Function to try loading every file behind the given sources (--check-knowledge, .check),
+// reporting each file separately so one broken file doesn't hide the others
+pub fn check_sources(sources: &[String], fetched: &FetchedUrls) -> Vec<SourceCheck> {
+    let mut checks = Vec::new();
+    for source in sources {
+        let files = resolve_knowledge_path(source)
+            .and_then(|path| source_files(&[path]).map_err(KnowledgeError::from));
+        match files {
+            Ok(files) => checks.extend(files.into_iter().map(|path| SourceCheck {
+                result: count_file_entries(&path, fetched),
+                path,
+            })),
+            Err(e) => checks.push(SourceCheck {
+                path: source.clone(),
+                result: Err(e),
+            }),
+        }
+    }
+    checks
+}
+
+// One top-level entry of a knowledge file (array item or object member)
+#[derive(Debug, Clone)]
+pub struct KnowledgeEntry {
+    pub file: String,
+    pub label: String,
+    pub value: Value,
+}
+
+// Function to list the top-level entries of the knowledge sources (.browse, --rag); an
+// object member is labelled with its key, an array item with its first string field
+pub fn load_knowledge_entries(
+    sources: &[String],
+    fetched: &FetchedUrls,
+) -> Result<Vec<KnowledgeEntry>, KnowledgeError> {
+    let files = source_files(sources)?;
+
+    let mut entries = Vec::new();
+    for file in &files {
+        let values: Vec<(Option<String>, Value)> = match parse_knowledge_file(file, fetched)?.value
+        {
+            Value::Array(items) => items.into_iter().map(|item| (None, item)).collect(),
+            Value::Object(map) => map
+                .into_iter()
+                .map(|(key, value)| {
+                    (
+                        Some(key.clone()),
+                        Value::Object(Map::from_iter([(key, value)])),
+                    )
+                })
+                .collect(),
+            other => vec![(None, other)],
+        };
+        for (index, (key, value)) in values.into_iter().enumerate() {
+            let label = key
+                .or_else(|| entry_label(&value))
+                .unwrap_or_else(|| format!("#{}", index + 1));
+            entries.push(KnowledgeEntry {
+                file: file.clone(),
+                label,
+                value,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+// Function to pick a short label for an entry: its first non-empty string field, or the
+// entry itself when it is a string
+fn entry_label(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Object(map) => map.values().find_map(|field| match field {
+            Value::String(text) if !text.trim().is_empty() => Some(text.clone()),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+// Function to split knowledge sources into one chunk per top-level entry, each labelled
+// with its file, for retrieval (--rag)
+pub fn load_knowledge_chunks(
+    sources: &[String],
+    fetched: &FetchedUrls,
+) -> Result<Vec<String>, KnowledgeError> {
+    let mut chunks = Vec::new();
+    for entry in load_knowledge_entries(sources, fetched)? {
+        let mut chunk = String::new();
+        push_section(
+            &mut chunk,
+            &entry.file,
+            &serde_json::to_string_pretty(&entry.value)?,
+        );
+        chunks.push(chunk);
+    }
+    Ok(chunks)
+}
+
+// Function to write the assembled knowledge verbatim to a file, returning the bytes written
+pub fn save_knowledge(path: &str, knowledge: &str) -> io::Result<usize> {
+    fs::write(path, knowledge)?;
+    debug!("Saved {} bytes of knowledge to {}", knowledge.len(), path);
+    Ok(knowledge.len())
+}
+
+// Function to append a knowledge section under a '===== name =====' header
+fn push_section(knowledge: &mut String, name: &str, content: &str) {
+    if !knowledge.is_empty() {
+        knowledge.push_str("\n\n");
+    }
+    knowledge.push_str(&format!("===== {} =====\n", name));
+    knowledge.push_str(content);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Function to write a fixture file into a temp dir, returning its path
+    fn write_fixture(dir: &TempDir, name: &str, content: &str) -> String {
+        let path = dir.path().join(name);
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn missing_file_is_an_io_error() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("missing.json");
+        let result = load_knowledge(&path.to_string_lossy());
+        assert!(matches!(result, Err(KnowledgeError::Io(_))));
+    }
+
+    #[test]
+    fn broken_json_is_a_parse_error() {
+        let dir = TempDir::new().unwrap();
+        let path = write_fixture(&dir, "broken.json", "{\"topics\": [");
+        assert!(matches!(
+            load_knowledge(&path),
+            Err(KnowledgeError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn yaml_knowledge_matches_the_json_equivalent() {
+        let dir = TempDir::new().unwrap();
+        let json = write_fixture(
+            &dir,
+            "pii_data.json",
+            r#"[
+  {"name": "email", "description": "E-mail address", "examples": ["a@b.com"]},
+  {"name": "ssn", "description": "Social security number", "examples": ["123-45-6789"]}
+]"#,
+        );
+        let yaml = write_fixture(
+            &dir,
+            "pii_data.yaml",
+            "- name: email\n  description: E-mail address\n  examples: [a@b.com]\n\
+             - name: ssn\n  description: Social security number\n  examples: ['123-45-6789']\n",
+        );
+        assert_eq!(
+            load_knowledge(&yaml).unwrap(),
+            load_knowledge(&json).unwrap()
+        );
+    }
+
+    #[test]
+    fn directory_sources_get_a_section_per_file() {
+        let dir = TempDir::new().unwrap();
+        write_fixture(&dir, "pii.json", r#"[{"name": "email"}]"#);
+        write_fixture(&dir, "mq.json", r#"[{"topic": "orders", "partitions": 3}]"#);
+        write_fixture(&dir, "notes.txt", "not knowledge");
+
+        let knowledge = load_knowledge_dir(&dir.path().to_string_lossy()).unwrap();
+
+        assert!(knowledge.contains("===== mq.json =====\n"));
+        assert!(knowledge.contains("===== pii.json =====\n"));
+        assert!(knowledge.contains("\"orders\""));
+        assert!(knowledge.contains("\"email\""));
+        assert!(!knowledge.contains("notes.txt"));
+        assert!(knowledge.find("mq.json") < knowledge.find("pii.json"));
+    }
+
+    #[test]
+    fn csv_knowledge_matches_the_json_equivalent() {
+        let dir = TempDir::new().unwrap();
+        let json = write_fixture(
+            &dir,
+            "topics.json",
+            r#"[{"topic": "orders", "partitions": "3"}, {"topic": "payments", "partitions": "6"}]"#,
+        );
+        let csv = write_fixture(
+            &dir,
+            "topics.csv",
+            "Topic,Partitions\norders, 3\n,\npayments,6\n",
+        );
+        assert_eq!(
+            load_knowledge(&csv).unwrap(),
+            load_knowledge(&json).unwrap()
+        );
+    }
+
+    #[test]
+    fn relative_sources_are_resolved_against_the_knowledge_dir() {
+        let dir = TempDir::new().unwrap();
+        let path = write_fixture(&dir, "resolve-me.json", r#"["email"]"#);
+
+        let resolved = resolve_knowledge_path_in("resolve-me.json", dir.path()).unwrap();
+
+        assert_eq!(resolved, path);
+        assert!(matches!(
+            resolve_knowledge_path_in("missing.json", dir.path()),
+            Err(KnowledgeError::NotFound(source)) if source == "missing.json"
+        ));
+    }
+
+    #[test]
+    fn duplicate_descriptions_are_kept_once() {
+        let dir = TempDir::new().unwrap();
+        let path = write_fixture(
+            &dir,
+            "pii.json",
+            r#"{
+                "pii_descriptions": ["Email address", " email address ", "Phone number"],
+                "exclude_pii_descriptions": ["Order ID", "ORDER ID", "Order ID"]
+            }"#,
+        );
+
+        let knowledge = load_knowledge(&path).unwrap();
+
+        assert_eq!(knowledge.to_lowercase().matches("email address").count(), 1);
+        assert_eq!(knowledge.to_lowercase().matches("order id").count(), 1);
+        // The first occurrence is the one kept, in its original position
+        let email = knowledge.find("Email address").unwrap();
+        let phone = knowledge.find("Phone number").unwrap();
+        assert!(email < phone);
+    }
+
+    #[test]
+    fn topic_names_are_kept_once_per_business_module() {
+        let dir = TempDir::new().unwrap();
+        let path = write_fixture(
+            &dir,
+            "mq.json",
+            r#"{"modules": [
+                {"name": "orders", "topics": ["orders.created", "Orders.Created ", "orders.paid"]},
+                {"name": "billing", "topics": [{"name": "orders.created"}, {"name": "orders.created"}]}
+            ]}"#,
+        );
+
+        let knowledge = load_knowledge(&path).unwrap();
+
+        // Once in each module: the second module's copy is not a duplicate of the first's
+        assert_eq!(
+            knowledge.to_lowercase().matches("orders.created").count(),
+            2
+        );
+        assert_eq!(knowledge.matches("orders.paid").count(), 1);
+    }
+
+    #[test]
+    fn other_lists_keep_their_repeated_values() {
+        let mut value = serde_json::json!({
+            "notes": ["Same", "same", "Same"],
+            "examples": {"emails": ["a@example.com", "a@example.com"]},
+            "partitions": [1, 1, 2],
+            "pii_descriptions": ["Email", "email"]
+        });
+
+        let dropped = dedupe_entries(&mut value);
+
+        assert_eq!(dropped, 1);
+        assert_eq!(value["notes"], serde_json::json!(["Same", "same", "Same"]));
+        assert_eq!(
+            value["examples"]["emails"],
+            serde_json::json!(["a@example.com", "a@example.com"])
+        );
+        assert_eq!(value["partitions"], serde_json::json!([1, 1, 2]));
+        assert_eq!(value["pii_descriptions"], serde_json::json!(["Email"]));
+    }
+
+    #[test]
+    fn entries_are_counted_at_the_top_level() {
+        let dir = TempDir::new().unwrap();
+        let array = write_fixture(
+            &dir,
+            "topics.json",
+            r#"[{"topic": "orders", "partitions": [1, 2, 3]}, {"topic": "payments"}]"#,
+        );
+        let object = write_fixture(
+            &dir,
+            "pii.yaml",
+            "pii_descriptions:\n  - Email address\nexclude_pii_descriptions:\n  - Order ID\nnotes: none\n",
+        );
+
+        let fetched = FetchedUrls::new();
+        assert_eq!(count_entries(&array, &fetched).unwrap(), 2);
+        assert_eq!(count_entries(&object, &fetched).unwrap(), 3);
+        assert!(matches!(
+            count_entries(&dir.path().join("missing.json").to_string_lossy(), &fetched),
+            Err(KnowledgeError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn saved_knowledge_equals_the_loaded_buffer() {
+        let dir = TempDir::new().unwrap();
+        write_fixture(&dir, "a.json", r#"{"pii_descriptions": ["Email address"]}"#);
+        write_fixture(&dir, "b.json", r#"["Phone number", "日本語の説明"]"#);
+        let knowledge = load_combined_knowledge(
+            &[dir.path().to_string_lossy().into_owned()],
+            &FetchedUrls::new(),
+        )
+        .unwrap()
+        .text;
+        let out = dir.path().join("out.txt");
+
+        let written = save_knowledge(&out.to_string_lossy(), &knowledge).unwrap();
+
+        assert_eq!(fs::read_to_string(&out).unwrap(), knowledge);
+        assert_eq!(written, knowledge.len());
+    }
+
+    #[test]
+    fn a_system_hint_is_taken_out_of_the_knowledge() {
+        let dir = TempDir::new().unwrap();
+        let path = write_fixture(
+            &dir,
+            "pii.yaml",
+            "system_hint: Classify fields as PII or not.\npii_descriptions:\n  - Email address\n",
+        );
+
+        let loaded = load_combined_knowledge(&[path], &FetchedUrls::new()).unwrap();
+
+        assert_eq!(loaded.hints, ["Classify fields as PII or not."]);
+        assert!(!loaded.text.contains("system_hint"));
+        assert!(loaded.text.contains("Email address"));
+    }
+
+    #[test]
+    fn a_source_without_a_hint_has_none() {
+        let dir = TempDir::new().unwrap();
+        let path = write_fixture(&dir, "mq.json", r#"{"topics": ["orders"]}"#);
+
+        let loaded = load_combined_knowledge(&[path], &FetchedUrls::new()).unwrap();
+
+        assert!(loaded.hints.is_empty());
+        assert!(loaded.behavior.is_empty());
+        assert!(loaded.text.contains("orders"));
+    }
+
+    #[test]
+    fn hints_and_behavior_of_a_directory_are_collected_in_order() {
+        let dir = TempDir::new().unwrap();
+        write_fixture(
+            &dir,
+            "a.json",
+            r#"{"system_hint": "Hint A", "behavior": ["Use tables"], "x": 1}"#,
+        );
+        write_fixture(
+            &dir,
+            "b.json",
+            r#"{"system_hint": "Hint B", "behavior": "Use tables", "y": 2}"#,
+        );
+
+        let loaded = load_combined_knowledge(
+            &[dir.path().to_string_lossy().into_owned()],
+            &FetchedUrls::new(),
+        )
+        .unwrap();
+
+        assert_eq!(loaded.hints, ["Hint A", "Hint B"]);
+        assert_eq!(loaded.behavior, ["Use tables"]);
+    }
+
+    // Function to render an MQ topic list with the given number of topics
+    fn mq_topics(count: usize) -> String {
+        let topics: Vec<Value> = (0..count)
+            .map(|index| {
+                serde_json::json!({
+                    "name": format!("orders.topic-{}", index),
+                    "description": "Order events published by the checkout service",
+                })
+            })
+            .collect();
+        serde_json::json!({ "topics": topics }).to_string()
+    }
+
+    #[test]
+    fn an_oversized_topic_list_keeps_its_leading_topics_whole() {
+        let dir = TempDir::new().unwrap();
+        let path = write_fixture(&dir, "mq.json", &mq_topics(5000));
+        let mut loaded = LoadedKnowledge::default();
+
+        let text =
+            load_knowledge_limited(&path, Some(2000), &FetchedUrls::new(), &mut loaded).unwrap();
+        let omitted = loaded.omitted;
+
+        let (knowledge, note) = text.split_once("\n\n[").unwrap();
+        assert!(knowledge.chars().count() <= 2000);
+        // The cut falls between topics, so what is left still parses
+        let kept: Value = serde_json::from_str(knowledge).unwrap();
+        let topics = kept["topics"].as_array().unwrap();
+        assert!(!topics.is_empty());
+        assert_eq!(topics[0]["name"], "orders.topic-0");
+        assert_eq!(topics.len() + omitted, 5000);
+        assert_eq!(
+            note,
+            format!(
+                "{} more entries omitted to respect KNOWLEDGE_MAX_CHARS]",
+                omitted
+            )
+        );
+    }
+
+    #[test]
+    fn the_size_limit_applies_to_the_combined_knowledge() {
+        let dir = TempDir::new().unwrap();
+        write_fixture(&dir, "a.json", &mq_topics(20));
+        write_fixture(&dir, "b.json", &mq_topics(20));
+        let mut loaded = LoadedKnowledge::default();
+
+        let text =
+            load_dir_limited(&dir.path().to_string_lossy(), Some(3000), &mut loaded).unwrap();
+
+        // Each file fits on its own, but not both together
+        assert!(rendered_len(&serde_json::from_str(&mq_topics(20)).unwrap()) < 3000);
+        assert!(loaded.omitted > 0);
+        assert!(text.starts_with("===== a.json ====="));
+        assert!(text.contains("===== b.json ====="));
+        assert!(text.chars().count() < 3200);
+    }
+
+    #[test]
+    fn entries_are_extracted_with_their_labels() {
+        let dir = TempDir::new().unwrap();
+        let pii = write_fixture(
+            &dir,
+            "pii.json",
+            r#"[
+                {"field": "email", "description": "Email address"},
+                {"id": 7},
+                "phone number"
+            ]"#,
+        );
+        let mq = write_fixture(
+            &dir,
+            "mq.json",
+            r#"{"orders.created": "Order placed", "orders.shipped": "Order shipped"}"#,
+        );
+
+        let entries =
+            load_knowledge_entries(&[pii.clone(), mq.clone()], &FetchedUrls::new()).unwrap();
+
+        let labels: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|entry| (entry.file.as_str(), entry.label.as_str()))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                (pii.as_str(), "email"),
+                (pii.as_str(), "#2"),
+                (pii.as_str(), "phone number"),
+                (mq.as_str(), "orders.created"),
+                (mq.as_str(), "orders.shipped"),
+            ]
+        );
+        assert_eq!(entries[0].value["description"], "Email address");
+        assert_eq!(
+            entries[3].value,
+            serde_json::json!({"orders.created": "Order placed"})
+        );
+    }
+
+    #[tokio::test]
+    async fn preloading_a_dir_of_two_sources_fills_the_map() {
+        let dir = TempDir::new().unwrap();
+        let pii = write_fixture(&dir, "pii.json", r#"[{"field": "email"}]"#);
+        let mq = write_fixture(&dir, "mq.yaml", "topics:\n  - name: orders.created\n");
+        write_fixture(&dir, "notes.txt", "not knowledge");
+
+        let preloaded = preload_knowledge_in(dir.path()).await;
+
+        let mut files: Vec<&String> = preloaded.keys().collect();
+        files.sort();
+        assert_eq!(files, [&mq, &pii]);
+        assert!(preloaded[&pii].text.contains("email"));
+        assert!(preloaded[&mq].text.contains("orders.created"));
+    }
+
+    #[test]
+    fn the_picker_offers_the_knowledge_files_of_a_dir_in_name_order() {
+        let dir = TempDir::new().unwrap();
+        let pii = write_fixture(&dir, "pii.json", "[]");
+        let mq = write_fixture(&dir, "mq.yaml", "topics: []\n");
+        write_fixture(&dir, "notes.txt", "not knowledge");
+
+        assert_eq!(available_knowledge_sources_in(dir.path()), [mq, pii]);
+        assert!(available_knowledge_sources_in(&dir.path().join("missing")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_url_source_is_fetched_once_and_again_on_reload() {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("GET", "/pii.json")
+            .with_header("content-type", "application/json")
+            .with_body(r#"[{"field": "email", "description": "Email address"}]"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let url = format!("{}/pii.json", server.url());
+        let sources = [url.clone()];
+        let mut fetched = FetchedUrls::new();
+
+        fetch_knowledge_urls(&mut fetched, &sources, false)
+            .await
+            .unwrap();
+        fetch_knowledge_urls(&mut fetched, &sources, false)
+            .await
+            .unwrap();
+        let loaded = load_combined_knowledge(&sources, &fetched).unwrap();
+
+        first.assert_async().await;
+        assert!(loaded.text.contains("Email address"));
+
+        first.remove_async().await;
+        server
+            .mock("GET", "/pii.json")
+            .with_header("content-type", "application/json")
+            .with_body(r#"[{"field": "phone", "description": "Phone number"}]"#)
+            .create_async()
+            .await;
+        fetch_knowledge_urls(&mut fetched, &sources, true)
+            .await
+            .unwrap();
+        let reloaded = load_combined_knowledge(&sources, &fetched).unwrap();
+
+        assert!(reloaded.text.contains("Phone number"));
+        assert!(!reloaded.text.contains("Email address"));
+    }
+
+    #[tokio::test]
+    async fn fetched_bodies_are_not_shared_between_caches() {
+        let mut server = mockito::Server::new_async().await;
+        let fetch = server
+            .mock("GET", "/pii.json")
+            .with_body(r#"[{"field": "email", "description": "Email address"}]"#)
+            .expect(2)
+            .create_async()
+            .await;
+        let sources = [format!("{}/pii.json", server.url())];
+        let (mut first, mut second) = (FetchedUrls::new(), FetchedUrls::new());
+
+        fetch_knowledge_urls(&mut first, &sources, false)
+            .await
+            .unwrap();
+        assert!(matches!(
+            load_combined_knowledge(&sources, &second),
+            Err(KnowledgeError::Fetch { .. })
+        ));
+        fetch_knowledge_urls(&mut second, &sources, false)
+            .await
+            .unwrap();
+
+        fetch.assert_async().await;
+        assert!(load_combined_knowledge(&sources, &second).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_failed_fetch_reports_the_status() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/pii.json")
+            .with_status(503)
+            .create_async()
+            .await;
+        let url = format!("{}/pii.json", server.url());
+
+        let mut fetched = FetchedUrls::new();
+        let result = fetch_knowledge_urls(&mut fetched, std::slice::from_ref(&url), false).await;
+
+        assert!(
+            matches!(&result, Err(KnowledgeError::Fetch { reason, .. }) if reason.contains("503")),
+            "{:?}",
+            result
+        );
+        assert!(matches!(
+            load_combined_knowledge(&[url], &fetched),
+            Err(KnowledgeError::Fetch { .. })
+        ));
+    }
+}
@@ -0,0 +1,47 @@
+use colored::Colorize;
+use similar::{ChangeTag, TextDiff};
+
+// Function to render a line diff between two answers (.diff): removed lines in red,
+// added lines in green and unchanged lines dimmed
+pub fn render_diff(old: &str, new: &str) -> String {
+    let diff = TextDiff::from_lines(old, new);
+    let mut rendered = String::new();
+    for change in diff.iter_all_changes() {
+        let line = change.value().trim_end_matches('\n');
+        let line = match change.tag() {
+            ChangeTag::Delete => format!("- {}", line).red().to_string(),
+            ChangeTag::Insert => format!("+ {}", line).green().to_string(),
+            ChangeTag::Equal => format!("  {}", line).dimmed().to_string(),
+        };
+        rendered.push_str(&line);
+        rendered.push('\n');
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_lines_are_marked() {
+        let old = "Email is PII.\nPhone is PII.\nOrder ID is not PII.\n";
+        let new = "Email is PII.\nPhone number is PII.\nOrder ID is not PII.\n";
+
+        let rendered = render_diff(old, new);
+
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("  Email is PII."));
+        assert!(lines[1].contains("- Phone is PII."));
+        assert!(lines[2].contains("+ Phone number is PII."));
+        assert!(lines[3].contains("  Order ID is not PII."));
+    }
+
+    #[test]
+    fn identical_answers_have_no_changes() {
+        let rendered = render_diff("same\n", "same\n");
+        assert!(!rendered.contains("- "));
+        assert!(!rendered.contains("+ "));
+    }
+}
@@ -0,0 +1,1363 @@
+use crate::audit::AuditLog;
+use crate::cache::ResponseCache;
+use crate::data::{
+    fetch_knowledge_urls, knowledge_dir, load_combined_knowledge, load_knowledge_chunks,
+    resolve_knowledge_path, resolve_knowledge_path_in, FetchedUrls, LoadedKnowledge,
+};
+use crate::error::AppError;
+use crate::history;
+use crate::language::{self, with_language};
+use crate::llm::{self, LlmBackend, SamplingOptions};
+use crate::output::ExchangeRecord;
+use crate::persona::Persona;
+use crate::preamble::PreambleTrimmer;
+use crate::rag::KnowledgeIndex;
+use crate::retry::{self, RetryPolicy, RetryWait};
+use crate::schema::{with_schema, ResponseSchema};
+use crate::tokens::{self, Usage};
+use futures::Stream;
+use langchain_rust::chain::{Chain, ChainError, LLMChain, LLMChainBuilder};
+use langchain_rust::language_models::GenerateResult;
+use langchain_rust::prompt::{HumanMessagePromptTemplate, MessageFormatterStruct};
+use langchain_rust::schemas::{Message, MessageType, StreamData};
+use langchain_rust::{prompt_args, template_fstring};
+use log::{debug, error};
+use serde_json::Value;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+// Default system message sent ahead of the knowledge on every request
+pub const SYSTEM_PROMPT: &str = "You are a world-class technical documentation writer. Use the following knowledge to answer the user's query.";
+
+// Placeholder in a SYSTEM_PROMPT_FILE template that is replaced with the knowledge
+pub const KNOWLEDGE_PLACEHOLDER: &str = "{knowledge}";
+
+// Function to read the system message from SYSTEM_PROMPT_FILE, falling back to
+// SYSTEM_PROMPT when it isn't set or can't be read
+pub fn default_system_prompt() -> String {
+    system_prompt_from_file(std::env::var("SYSTEM_PROMPT_FILE").ok())
+}
+
+// Function to read the system message from the given template file, if any
+fn system_prompt_from_file(path: Option<String>) -> String {
+    let Some(path) = path else {
+        return SYSTEM_PROMPT.to_string();
+    };
+    match std::fs::read_to_string(&path) {
+        Ok(template) => template.trim_end().to_string(),
+        Err(e) => {
+            error!("Ignoring SYSTEM_PROMPT_FILE {}: {}", path, e);
+            SYSTEM_PROMPT.to_string()
+        }
+    }
+}
+
+// Stream of answer chunks returned by ChatEngine::stream
+pub type AnswerStream = Pin<Box<dyn Stream<Item = Result<StreamData, ChainError>> + Send>>;
+
+// Settings used to create a ChatEngine
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub model: String,
+    pub sampling: SamplingOptions,
+    pub system_prompt: String,
+    pub retry_policy: RetryPolicy,
+    pub max_prompt_tokens: usize,
+    pub history_max_turns: Option<usize>,
+    pub cache: Option<ResponseCache>,
+    pub request_timeout: Duration,
+    pub schema: Option<ResponseSchema>,
+    pub language: Option<String>,
+    pub preamble: Option<PreambleTrimmer>,
+}
+
+impl EngineConfig {
+    // Function to read the configuration from the same environment variables as the CLI
+    pub fn from_env() -> Self {
+        let model = llm::default_model();
+        let sampling = SamplingOptions::from_env();
+        EngineConfig {
+            max_prompt_tokens: tokens::max_prompt_tokens_for(&model, sampling.max_tokens),
+            model,
+            sampling,
+            system_prompt: default_system_prompt(),
+            retry_policy: RetryPolicy::from_env(),
+            history_max_turns: std::env::var("HISTORY_MAX_TURNS")
+                .ok()
+                .and_then(|value| value.parse().ok()),
+            cache: ResponseCache::from_env(),
+            request_timeout: Duration::from_secs(
+                std::env::var("LLM_TIMEOUT_SECS")
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(60),
+            ),
+            schema: None,
+            language: language::language_from_env(),
+            preamble: PreambleTrimmer::from_env(),
+        }
+    }
+}
+
+// Chat engine holding the loaded knowledge, the conversation and the LLM chain; it
+// produces answers without any terminal output
+pub struct ChatEngine {
+    pub system_prompt: String,
+    pub persona: Option<&'static str>,
+    pub knowledge: String,
+    pub knowledge_sources: Vec<String>,
+    // Bodies of the URL sources fetched by this engine, read when the knowledge is loaded
+    pub fetched_urls: FetchedUrls,
+    pub knowledge_omitted: usize,
+    pub knowledge_hints: Vec<String>,
+    pub knowledge_behavior: Vec<String>,
+    pub notes: Vec<String>,
+    pub history_list: Vec<Message>,
+    pub model: String,
+    pub sampling: SamplingOptions,
+    pub max_prompt_tokens: usize,
+    pub history_max_turns: Option<usize>,
+    pub last_usage: Option<Usage>,
+    pub total_usage: Usage,
+    pub last_latency: Option<Duration>,
+    pub trimmed_preamble: Option<String>,
+    pub schema: Option<ResponseSchema>,
+    pub language: Option<String>,
+    // Knowledge chunks retrieved for the latest prompt with --rag, sent in place of the
+    // full knowledge
+    retrieved_knowledge: Option<String>,
+    backend: LlmBackend,
+    chain: LLMChain,
+    retry_policy: RetryPolicy,
+    request_timeout: Duration,
+    cache: Option<ResponseCache>,
+    audit_log: Option<AuditLog>,
+    rag: Option<KnowledgeIndex>,
+    // Set when the knowledge changes under an index; the next retrieval rebuilds it
+    rag_stale: bool,
+    preamble: Option<PreambleTrimmer>,
+}
+
+impl ChatEngine {
+    // Function to create an engine using the backend selected by LLM_PROVIDER
+    pub async fn new(config: EngineConfig) -> Result<Self, AppError> {
+        let backend = llm::create_llm(&config.model, config.sampling).await?;
+        Self::with_backend(config, backend)
+    }
+
+    // Function to create an engine around an existing backend (e.g. a custom LLM)
+    pub fn with_backend(config: EngineConfig, backend: LlmBackend) -> Result<Self, AppError> {
+        let chain = build_chain(
+            &system_message(
+                &config.system_prompt,
+                &[],
+                &[],
+                config.schema,
+                config.language.as_deref(),
+            ),
+            "",
+            &backend,
+        )?;
+
+        Ok(ChatEngine {
+            system_prompt: config.system_prompt,
+            persona: None,
+            knowledge: String::new(),
+            knowledge_sources: Vec::new(),
+            fetched_urls: FetchedUrls::new(),
+            knowledge_omitted: 0,
+            knowledge_hints: Vec::new(),
+            knowledge_behavior: Vec::new(),
+            retrieved_knowledge: None,
+            notes: Vec::new(),
+            history_list: Vec::new(),
+            model: config.model,
+            sampling: config.sampling,
+            max_prompt_tokens: config.max_prompt_tokens,
+            history_max_turns: config.history_max_turns,
+            last_usage: None,
+            total_usage: Usage::default(),
+            last_latency: None,
+            trimmed_preamble: None,
+            schema: config.schema,
+            language: config.language,
+            backend,
+            chain,
+            retry_policy: config.retry_policy,
+            request_timeout: config.request_timeout,
+            cache: config.cache,
+            audit_log: None,
+            rag: None,
+            rag_stale: false,
+            preamble: config.preamble,
+        })
+    }
+
+    // Function to download the URL sources among `sources` that this engine hasn't fetched
+    // yet, or all of them with `refresh` (.reload), for set_knowledge to read
+    pub async fn fetch_knowledge_urls(
+        &mut self,
+        sources: &[String],
+        refresh: bool,
+    ) -> Result<(), AppError> {
+        Ok(fetch_knowledge_urls(&mut self.fetched_urls, sources, refresh).await?)
+    }
+
+    // Function to load knowledge from one or more files and rebuild the chain; sources
+    // are resolved against KNOWLEDGE_DIR, duplicates are loaded once and the previous
+    // knowledge is kept if loading fails
+    pub fn set_knowledge(&mut self, sources: &[String]) -> Result<(), AppError> {
+        let mut selected: Vec<String> = Vec::new();
+        for source in sources {
+            let source = resolve_knowledge_path(source)?;
+            if !selected.contains(&source) {
+                selected.push(source);
+            }
+        }
+
+        let loaded = load_combined_knowledge(&selected, &self.fetched_urls)?;
+        self.use_loaded_knowledge(selected, loaded)
+    }
+
+    // Function to switch to knowledge that has already been loaded (e.g. preloaded with
+    // PRELOAD_KNOWLEDGE=1), without reading the sources again
+    pub fn use_loaded_knowledge(
+        &mut self,
+        selected: Vec<String>,
+        loaded: LoadedKnowledge,
+    ) -> Result<(), AppError> {
+        let system_prompt = system_message(
+            &self.system_prompt,
+            &loaded.hints,
+            &loaded.behavior,
+            self.schema,
+            self.language.as_deref(),
+        );
+        self.chain = build_chain(
+            &system_prompt,
+            &with_notes(&loaded.text, &self.notes),
+            &self.backend,
+        )?;
+        self.knowledge = loaded.text;
+        self.retrieved_knowledge = None;
+        self.rag_stale = self.rag.is_some();
+        self.knowledge_omitted = loaded.omitted;
+        self.knowledge_hints = loaded.hints;
+        self.knowledge_behavior = loaded.behavior;
+        debug!("Switched knowledge to {}", selected.join(", "));
+        self.knowledge_sources = selected;
+        Ok(())
+    }
+
+    // Function to switch to retrieval (--rag): the knowledge sources are split into chunks
+    // and embedded once, and each prompt only gets the top_k most relevant chunks
+    pub async fn enable_rag(&mut self, top_k: usize) -> Result<(), AppError> {
+        let chunks = load_knowledge_chunks(&self.knowledge_sources, &self.fetched_urls)?;
+        let embedder = llm::create_embedder(&self.backend).await?;
+        self.rag = Some(KnowledgeIndex::build(embedder, chunks, top_k).await?);
+        self.rag_stale = false;
+        Ok(())
+    }
+
+    // Function to send only the chunks relevant to a prompt instead of the full knowledge,
+    // which is kept as loaded (no-op without --rag); an index built for other sources is
+    // rebuilt first
+    pub async fn retrieve_knowledge(&mut self, input: &str) -> Result<(), AppError> {
+        let Some(rag) = &mut self.rag else {
+            return Ok(());
+        };
+        if self.rag_stale {
+            rag.rebuild(load_knowledge_chunks(
+                &self.knowledge_sources,
+                &self.fetched_urls,
+            )?)
+            .await?;
+            self.rag_stale = false;
+        }
+        let retrieved = rag.retrieve(input).await?;
+        self.chain = build_chain(
+            &self.full_system_prompt(),
+            &with_notes(&retrieved, &self.notes),
+            &self.backend,
+        )?;
+        self.retrieved_knowledge = Some(retrieved);
+        Ok(())
+    }
+
+    // Function to switch the model (Azure deployment id) used from the next prompt on
+    pub async fn switch_model(&mut self, model: &str) -> Result<(), AppError> {
+        self.rebuild_backend(model, self.sampling).await?;
+        debug!("Switched model to {}", self.model);
+        Ok(())
+    }
+
+    // Function to change temperature/max_tokens used from the next prompt on
+    pub async fn set_sampling(&mut self, sampling: SamplingOptions) -> Result<(), AppError> {
+        let model = self.model.clone();
+        self.rebuild_backend(&model, sampling).await?;
+        debug!("Sampling set to {:?}", self.sampling);
+        Ok(())
+    }
+
+    // Function to recreate the backend and chain for a model and sampling settings
+    async fn rebuild_backend(
+        &mut self,
+        model: &str,
+        sampling: SamplingOptions,
+    ) -> Result<(), AppError> {
+        let backend = llm::recreate_llm(&self.backend, model, sampling).await?;
+        self.chain = build_chain(
+            &self.full_system_prompt(),
+            &self.prompt_knowledge(),
+            &backend,
+        )?;
+        self.backend = backend;
+        self.model = model.to_string();
+        self.sampling = sampling;
+        self.max_prompt_tokens = tokens::max_prompt_tokens_for(model, sampling.max_tokens);
+        Ok(())
+    }
+
+    // Function to replace the system message for subsequent turns; history and
+    // knowledge are left untouched
+    pub fn set_system_prompt(&mut self, system_prompt: &str) -> Result<(), AppError> {
+        self.chain = build_chain(
+            &system_message(
+                system_prompt,
+                &self.knowledge_hints,
+                &self.knowledge_behavior,
+                self.schema,
+                self.language.as_deref(),
+            ),
+            &self.prompt_knowledge(),
+            &self.backend,
+        )?;
+        self.system_prompt = system_prompt.to_string();
+        self.persona = None;
+        debug!("System prompt set to: {}", self.system_prompt);
+        Ok(())
+    }
+
+    // Function to set the language answers are requested in (None leaves it to the model)
+    pub fn set_language(&mut self, language: Option<String>) -> Result<(), AppError> {
+        self.chain = build_chain(
+            &system_message(
+                &self.system_prompt,
+                &self.knowledge_hints,
+                &self.knowledge_behavior,
+                self.schema,
+                language.as_deref(),
+            ),
+            &self.prompt_knowledge(),
+            &self.backend,
+        )?;
+        self.language = language;
+        debug!("Response language set to: {:?}", self.language);
+        Ok(())
+    }
+
+    // Function to switch to a persona: its system message, plus its knowledge source when
+    // it has one (loaded first, so a missing file leaves the engine unchanged)
+    pub fn set_persona(&mut self, persona: &Persona) -> Result<(), AppError> {
+        self.set_persona_in(persona, &knowledge_dir())
+    }
+
+    // Function to switch persona, looking its knowledge up in the given directory
+    fn set_persona_in(&mut self, persona: &Persona, dir: &Path) -> Result<(), AppError> {
+        if let Some(knowledge) = persona.knowledge {
+            self.set_knowledge(&[resolve_knowledge_path_in(knowledge, dir)?])?;
+        }
+        self.set_system_prompt(persona.system_prompt)?;
+        self.persona = Some(persona.name);
+        Ok(())
+    }
+
+    // Function to add a free-text note to the knowledge for the rest of the session
+    // (.addknowledge); notes survive knowledge reloads
+    pub fn add_note(&mut self, note: &str) -> Result<(), AppError> {
+        self.notes.push(note.to_string());
+        self.chain = build_chain(
+            &self.full_system_prompt(),
+            &self.prompt_knowledge(),
+            &self.backend,
+        )
+        .inspect_err(|_| {
+            self.notes.pop();
+        })?;
+        Ok(())
+    }
+
+    // Function to remove the ad-hoc notes, keeping the loaded knowledge (.clearknowledge)
+    pub fn clear_notes(&mut self) -> Result<(), AppError> {
+        self.chain = build_chain(
+            &self.full_system_prompt(),
+            self.sent_knowledge(),
+            &self.backend,
+        )?;
+        self.notes.clear();
+        Ok(())
+    }
+
+    // Function to pick the knowledge sent with the prompt: the retrieved chunks with
+    // --rag, otherwise everything loaded
+    fn sent_knowledge(&self) -> &str {
+        self.retrieved_knowledge
+            .as_deref()
+            .unwrap_or(&self.knowledge)
+    }
+
+    // Function to combine the knowledge sent with the ad-hoc notes
+    fn prompt_knowledge(&self) -> String {
+        with_notes(self.sent_knowledge(), &self.notes)
+    }
+
+    // Function to forget the conversation while keeping knowledge and settings
+    pub fn clear_history(&mut self) {
+        self.history_list.clear();
+    }
+
+    // Function to estimate the tokens a prompt would send with the current knowledge and history
+    pub fn estimate_prompt_tokens(&self, input: &str) -> usize {
+        tokens::estimate_prompt_tokens(
+            &self.system_prompt,
+            &self.prompt_knowledge(),
+            &self.history_list,
+            input,
+        )
+    }
+
+    // Function to drop the oldest exchanges so the next prompt stays within
+    // HISTORY_MAX_TURNS and MAX_PROMPT_TOKENS
+    pub fn trim_history(&mut self, input: &str) -> usize {
+        let fixed_tokens = tokens::estimate_prompt_tokens(
+            &self.system_prompt,
+            &self.prompt_knowledge(),
+            &[],
+            input,
+        );
+        let dropped = history::truncate_history(
+            &mut self.history_list,
+            self.history_max_turns,
+            self.max_prompt_tokens,
+            fixed_tokens,
+        );
+        if dropped > 0 {
+            debug!("Dropped {} oldest turns to fit the context window", dropped);
+        }
+        dropped
+    }
+
+    // Function to describe the active knowledge sources, if any
+    pub fn knowledge_source(&self) -> Option<String> {
+        if self.knowledge_sources.is_empty() {
+            None
+        } else {
+            Some(self.knowledge_sources.join(", "))
+        }
+    }
+
+    // Function to assemble the messages the next prompt would send
+    pub fn build_messages(&self, input: &str) -> Vec<Message> {
+        build_messages(
+            &self.full_system_prompt(),
+            &self.prompt_knowledge(),
+            &self.history_list,
+            input,
+        )
+    }
+
+    // Function to build the system message actually sent, including any schema and
+    // language instruction
+    fn full_system_prompt(&self) -> String {
+        system_message(
+            &self.system_prompt,
+            &self.knowledge_hints,
+            &self.knowledge_behavior,
+            self.schema,
+            self.language.as_deref(),
+        )
+    }
+
+    // Function to answer a prompt against the current history and record the answer;
+    // with LLM_CACHE_DIR set, identical prompts to the same model are answered from disk
+    pub async fn complete(&mut self, input: &str) -> Result<String, AppError> {
+        self.complete_with_progress(input, |_| {}).await
+    }
+
+    // Function to answer a prompt like complete, reporting waits between retries to on_retry
+    pub async fn complete_with_progress(
+        &mut self,
+        input: &str,
+        on_retry: impl FnMut(&RetryWait),
+    ) -> Result<String, AppError> {
+        self.trimmed_preamble = None;
+        self.retrieve_knowledge(input).await?;
+        let messages = self.build_messages(input);
+        if let Some(result) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.model, self.sampling, &messages))
+        {
+            self.push_turn(input, answer_message(&result));
+            self.audit(input, &result);
+            return Ok(result);
+        }
+
+        let mut output = self.invoke(input, on_retry).await?;
+
+        // With --schema, ask once more for a conforming answer; the rejected turn is only
+        // in history for the correction, so only the final answer remains
+        if let Some(schema) = self.schema {
+            if let Err(e) = schema.validate(&output) {
+                debug!("Answer does not match the schema ({}), asking again", e);
+                let correction = format!(
+                    "Your previous answer was not valid ({}). {}",
+                    e,
+                    schema.instruction()
+                );
+                let previous = self.history_list.len();
+                self.push_turn(input, answer_message(&output));
+                let corrected = self.invoke(&correction, |_| {}).await;
+                self.history_list.truncate(previous);
+                output = corrected?;
+            }
+        }
+
+        // With TRIM_PREAMBLE=1, drop a filler opening before the answer is shown or stored;
+        // the removed line is kept for --verbose
+        let trimmed = self.preamble.as_ref().and_then(|trimmer| {
+            trimmer
+                .trim(&output)
+                .map(|(preamble, rest)| (preamble.to_string(), rest.to_string()))
+        });
+        if let Some((preamble, rest)) = trimmed {
+            debug!("Trimmed preamble: {}", preamble);
+            self.trimmed_preamble = Some(preamble);
+            output = rest;
+        }
+
+        // An empty (e.g. filtered) answer is worth asking for again: it leaves no trace in
+        // history, the cache or the audit log, so a .retry starts from the same context
+        if output.trim().is_empty() {
+            return Err(AppError::EmptyResponse);
+        }
+        self.push_turn(input, answer_message(&output));
+        if let Some(cache) = &self.cache {
+            cache.put(&self.model, self.sampling, &messages, &output);
+        }
+        self.audit(input, &output);
+        Ok(output)
+    }
+
+    // Function to record an answered prompt in history; a turn is only recorded once its
+    // answer is in, so the prompt sent never holds the input twice
+    pub fn push_turn(&mut self, input: &str, answer: Message) {
+        self.history_list.push(Message::new_human_message(input));
+        self.history_list.push(answer);
+    }
+
+    // Function to call the LLM once against the current history, recording its usage
+    async fn invoke(
+        &mut self,
+        input: &str,
+        on_retry: impl FnMut(&RetryWait),
+    ) -> Result<String, AppError> {
+        let prompt_tokens = self.estimate_prompt_tokens(input);
+        let started = Instant::now();
+        let result = invoke_llm(
+            input,
+            &self.history_list,
+            &self.chain,
+            &self.retry_policy,
+            self.request_timeout,
+            on_retry,
+        )
+        .await?;
+        self.last_latency = Some(started.elapsed());
+        let usage = match &result.tokens {
+            Some(tokens) => Usage::from_token_usage(tokens),
+            None => Usage::estimate(prompt_tokens, &result.generation),
+        };
+        self.record_usage(usage);
+        Ok(result.generation)
+    }
+
+    // Function to append every answered prompt to the given audit log
+    pub fn set_audit_log(&mut self, audit_log: AuditLog) {
+        self.audit_log = Some(audit_log);
+    }
+
+    // Function to record an answered prompt in the audit log, if one is set
+    pub fn audit(&mut self, input: &str, output: &str) {
+        let knowledge_source = self.knowledge_source();
+        let Some(audit_log) = &mut self.audit_log else {
+            return;
+        };
+        let exchange = ExchangeRecord {
+            input,
+            output,
+            model: &self.model,
+            knowledge_source,
+        };
+        if let Err(e) = audit_log.record(&exchange) {
+            error!("Failed to write audit log: {}", e);
+        }
+    }
+
+    // Function to record the token usage of the last exchange (cached answers cost nothing
+    // and are not recorded)
+    pub fn record_usage(&mut self, usage: Usage) {
+        self.total_usage.add(&usage);
+        self.last_usage = Some(usage);
+    }
+
+    // Function to time one LLM call for a prompt (.time), bypassing the cache and leaving
+    // history untouched
+    pub async fn time_prompt(&self, input: &str) -> Result<Duration, AppError> {
+        let started = Instant::now();
+        invoke_llm(
+            input,
+            &[],
+            &self.chain,
+            &self.retry_policy,
+            self.request_timeout,
+            |_| {},
+        )
+        .await?;
+        Ok(started.elapsed())
+    }
+
+    // Function to answer a prompt on its own, without reading or recording history, so
+    // several prompts can share the engine concurrently (--batch)
+    pub async fn complete_detached(&self, input: &str) -> Result<String, AppError> {
+        let messages = build_messages(
+            &self.full_system_prompt(),
+            &self.prompt_knowledge(),
+            &[],
+            input,
+        );
+        if let Some(result) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.model, self.sampling, &messages))
+        {
+            return Ok(result);
+        }
+
+        let result = invoke_llm(
+            input,
+            &[],
+            &self.chain,
+            &self.retry_policy,
+            self.request_timeout,
+            |_| {},
+        )
+        .await?;
+        if let Some(cache) = &self.cache {
+            cache.put(&self.model, self.sampling, &messages, &result.generation);
+        }
+        Ok(result.generation)
+    }
+
+    // Function to add a prompt to the conversation and answer it
+    pub async fn ask(&mut self, input: &str) -> Result<String, AppError> {
+        self.complete(input).await
+    }
+
+    // Function to start a streamed answer; the caller records the turn with push_turn
+    pub async fn stream(&self, input: &str) -> Result<AnswerStream, AppError> {
+        self.chain
+            .stream(prompt_args! {
+                "input" => input,
+                "history" => &self.history_list
+            })
+            .await
+            .map_err(AppError::from)
+    }
+}
+
+// Function to add the knowledge sources' hints ahead of a system prompt, and their
+// behaviour instructions and the schema and language instructions, if any, after it
+fn system_message(
+    system_prompt: &str,
+    hints: &[String],
+    behavior: &[String],
+    schema: Option<ResponseSchema>,
+    language: Option<&str>,
+) -> String {
+    let mut message = hints.join("\n\n");
+    if !message.is_empty() {
+        message.push_str("\n\n");
+    }
+    message.push_str(system_prompt);
+    for instruction in behavior {
+        message.push_str("\n\n");
+        message.push_str(instruction);
+    }
+    with_language(&with_schema(&message, schema), language)
+}
+
+// Function to append the ad-hoc notes, if any, to the knowledge under their own header
+fn with_notes(knowledge: &str, notes: &[String]) -> String {
+    if notes.is_empty() {
+        return knowledge.to_string();
+    }
+    let mut combined = knowledge.to_string();
+    if !combined.is_empty() {
+        combined.push_str("\n\n");
+    }
+    combined.push_str("===== Ad-hoc notes =====\n");
+    combined.push_str(&notes.join("\n"));
+    combined
+}
+
+// Function to build the system messages: a system prompt with a {knowledge} placeholder
+// gets the knowledge substituted in place, otherwise the knowledge follows in a message of
+// its own (left out when empty, since an empty "Knowledge:" block only confuses the model)
+fn system_messages(system_prompt: &str, knowledge: &str) -> Vec<Message> {
+    if system_prompt.contains(KNOWLEDGE_PLACEHOLDER) {
+        return vec![Message::new_system_message(
+            system_prompt.replace(KNOWLEDGE_PLACEHOLDER, knowledge),
+        )];
+    }
+    let mut messages = vec![Message::new_system_message(system_prompt)];
+    if !knowledge.is_empty() {
+        messages.push(Message::new_system_message(format!(
+            "Knowledge:\n{}",
+            knowledge
+        )));
+    }
+    messages
+}
+
+// Function to assemble the rendered prompt: system message, knowledge (omitted when
+// none is loaded), history and input, in the same order as the chain built by build_chain
+pub fn build_messages(
+    system_prompt: &str,
+    knowledge: &str,
+    history: &[Message],
+    input: &str,
+) -> Vec<Message> {
+    let mut messages = system_messages(system_prompt, knowledge);
+    messages.extend(history.iter().cloned());
+    messages.push(Message::new_human_message(input));
+    messages
+}
+
+// Function to turn an answer into its history message; OpenAI backends return tool calls
+// as a JSON array of calls, which is kept as tool_calls rather than as answer text
+pub fn answer_message(generation: &str) -> Message {
+    match serde_json::from_str::<Value>(generation) {
+        Ok(Value::Array(calls))
+            if !calls.is_empty() && calls.iter().all(|call| call.get("function").is_some()) =>
+        {
+            Message::new_ai_message("").with_tool_calls(Value::Array(calls))
+        }
+        _ => Message::new_ai_message(generation),
+    }
+}
+
+// Function to label a message with its role, as sent to the model
+pub fn role_label(message_type: &MessageType) -> &'static str {
+    match message_type {
+        MessageType::SystemMessage => "system",
+        MessageType::HumanMessage => "user",
+        MessageType::AIMessage => "assistant",
+        MessageType::ToolMessage => "tool",
+    }
+}
+
+// Function to build the LLM chain for the given knowledge; the chain is reused across
+// turns and only rebuilt when the knowledge changes (Refactor chain setup)
+fn build_chain(
+    system_prompt: &str,
+    knowledge: &str,
+    llm: &LlmBackend,
+) -> Result<LLMChain, ChainError> {
+    let mut prompt = MessageFormatterStruct::new();
+    for message in system_messages(system_prompt, knowledge) {
+        prompt.add_message(message);
+    }
+    prompt.add_messages_placeholder("history");
+    prompt.add_template(Box::new(HumanMessagePromptTemplate::new(
+        template_fstring!("{input}", "input"),
+    )));
+
+    LLMChainBuilder::new()
+        .prompt(prompt)
+        .llm(llm.to_llm())
+        .build()
+}
+
+// Function to invoke the LLM chain on the history and input; the request, including
+// retries, fails once LLM_TIMEOUT_SECS elapses (Refactor LLM logic)
+async fn invoke_llm(
+    input: &str,
+    history_list: &[Message],
+    chain: &LLMChain,
+    retry_policy: &RetryPolicy,
+    request_timeout: Duration,
+    on_retry: impl FnMut(&RetryWait),
+) -> Result<GenerateResult, AppError> {
+    let request = retry::with_retry(
+        retry_policy,
+        || {
+            chain.call(prompt_args! {
+                "input" => input,
+                "history" => history_list
+            })
+        },
+        on_retry,
+    );
+    let result = tokio::time::timeout(request_timeout, request)
+        .await
+        .map_err(|_| AppError::Timeout(request_timeout.as_secs()))??;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockLlm;
+    use crate::rag::tests::KeywordEmbedder;
+    use async_trait::async_trait;
+    use futures::stream;
+    use langchain_rust::language_models::llm::LLM;
+    use langchain_rust::language_models::LLMError;
+    use std::sync::{Arc, Mutex};
+
+    // Backend that takes a fixed time to answer, for exercising the request deadline
+    #[derive(Clone)]
+    struct SlowLlm {
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl LLM for SlowLlm {
+        async fn generate(&self, _messages: &[Message]) -> Result<GenerateResult, LLMError> {
+            tokio::time::sleep(self.delay).await;
+            Ok(GenerateResult {
+                tokens: None,
+                generation: "too late".to_string(),
+            })
+        }
+
+        async fn stream(
+            &self,
+            _messages: &[Message],
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError>
+        {
+            Ok(Box::pin(stream::empty()))
+        }
+    }
+
+    // Backend giving the scripted answers in order, then repeating the last one
+    #[derive(Clone)]
+    struct ScriptedLlm {
+        answers: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ScriptedLlm {
+        // Function to create the backend from the answers in the order they are given
+        fn new(answers: &[&str]) -> Self {
+            ScriptedLlm {
+                answers: Arc::new(Mutex::new(
+                    answers
+                        .iter()
+                        .rev()
+                        .map(|answer| answer.to_string())
+                        .collect(),
+                )),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LLM for ScriptedLlm {
+        async fn generate(&self, _messages: &[Message]) -> Result<GenerateResult, LLMError> {
+            let mut answers = self.answers.lock().unwrap();
+            let generation = if answers.len() > 1 {
+                answers.pop().unwrap()
+            } else {
+                answers[0].clone()
+            };
+            Ok(GenerateResult {
+                tokens: None,
+                generation,
+            })
+        }
+
+        async fn stream(
+            &self,
+            _messages: &[Message],
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError>
+        {
+            Ok(Box::pin(stream::empty()))
+        }
+    }
+
+    // Function to create an engine answering with --schema pii from a script
+    fn schema_engine(answers: &[&str]) -> ChatEngine {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        config.schema = Some(ResponseSchema::Pii);
+        let llm = ScriptedLlm::new(answers);
+        ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(llm))).unwrap()
+    }
+
+    // Function to create an engine on the mock backend, without a cache
+    fn mock_engine() -> ChatEngine {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(MockLlm::new()))).unwrap()
+    }
+
+    #[test]
+    fn overridden_system_prompt_is_sent() {
+        let mut engine = mock_engine();
+        engine.set_system_prompt("You are a pirate.").unwrap();
+
+        let messages = engine.build_messages("hello");
+
+        assert!(matches!(
+            messages[0].message_type,
+            MessageType::SystemMessage
+        ));
+        assert_eq!(messages[0].content, "You are a pirate.");
+        assert_eq!(messages.last().unwrap().content, "hello");
+    }
+
+    #[test]
+    fn the_language_instruction_follows_the_setting() {
+        let mut engine = mock_engine();
+        engine.set_system_prompt("You are a pirate.").unwrap();
+
+        engine.set_language(Some("ja".to_string())).unwrap();
+        let messages = engine.build_messages("hello");
+        assert_eq!(
+            messages[0].content,
+            "You are a pirate.\n\nRespond in Japanese."
+        );
+
+        engine.set_language(None).unwrap();
+        let messages = engine.build_messages("hello");
+        assert_eq!(messages[0].content, "You are a pirate.");
+    }
+
+    // Function to load knowledge text into an engine without reading a file
+    fn with_knowledge(engine: &mut ChatEngine, text: &str) {
+        let loaded = LoadedKnowledge {
+            text: text.to_string(),
+            ..LoadedKnowledge::default()
+        };
+        engine
+            .use_loaded_knowledge(vec!["test.json".to_string()], loaded)
+            .unwrap();
+    }
+
+    #[test]
+    fn a_knowledge_placeholder_in_the_template_is_expanded() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("system.txt");
+        std::fs::write(
+            &path,
+            "You classify fields.\n<knowledge>\n{knowledge}\n</knowledge>\nBe brief.\n",
+        )
+        .unwrap();
+        let mut engine = mock_engine();
+        engine
+            .set_system_prompt(&system_prompt_from_file(Some(
+                path.to_string_lossy().into_owned(),
+            )))
+            .unwrap();
+        with_knowledge(&mut engine, "Email address is PII");
+
+        let messages = engine.build_messages("hello");
+
+        // The knowledge is framed by the template, not sent in a message of its own
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0].content,
+            "You classify fields.\n<knowledge>\nEmail address is PII\n</knowledge>\nBe brief."
+        );
+    }
+
+    #[test]
+    fn a_missing_template_falls_back_to_the_default() {
+        assert_eq!(system_prompt_from_file(None), SYSTEM_PROMPT);
+        assert_eq!(
+            system_prompt_from_file(Some("/nonexistent/system.txt".to_string())),
+            SYSTEM_PROMPT
+        );
+    }
+
+    #[test]
+    fn behavior_goes_to_the_system_message_not_the_knowledge() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let source = dir.path().join("pii.json");
+        std::fs::write(
+            &source,
+            r#"{"behavior": "Answer with a markdown table.", "pii": ["Email address"]}"#,
+        )
+        .unwrap();
+        let mut engine = mock_engine();
+        engine.set_system_prompt("You are a pirate.").unwrap();
+
+        engine
+            .set_knowledge(&[source.to_string_lossy().into_owned()])
+            .unwrap();
+        let messages = engine.build_messages("hello");
+
+        assert_eq!(
+            messages[0].content,
+            "You are a pirate.\n\nAnswer with a markdown table."
+        );
+        assert!(messages[1].content.contains("Email address"));
+        assert!(!messages[1].content.contains("markdown table"));
+        assert!(!messages[1].content.contains("behavior"));
+    }
+
+    #[test]
+    fn a_source_with_a_system_hint_leads_the_system_message() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let with_hint = dir.path().join("pii.json");
+        std::fs::write(
+            &with_hint,
+            r#"{"system_hint": "Classify fields as PII or not.", "pii": ["Email address"]}"#,
+        )
+        .unwrap();
+        let without_hint = dir.path().join("mq.json");
+        std::fs::write(&without_hint, r#"{"topics": ["orders"]}"#).unwrap();
+        let mut engine = mock_engine();
+        engine.set_system_prompt("You are a pirate.").unwrap();
+
+        engine
+            .set_knowledge(&[with_hint.to_string_lossy().into_owned()])
+            .unwrap();
+        let messages = engine.build_messages("hello");
+        assert_eq!(
+            messages[0].content,
+            "Classify fields as PII or not.\n\nYou are a pirate."
+        );
+        assert!(!messages[1].content.contains("system_hint"));
+
+        engine
+            .set_knowledge(&[without_hint.to_string_lossy().into_owned()])
+            .unwrap();
+        let messages = engine.build_messages("hello");
+        assert_eq!(messages[0].content, "You are a pirate.");
+    }
+
+    #[test]
+    fn the_pii_classifier_sets_its_prompt_and_knowledge() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("pii.json"),
+            r#"{"pii_descriptions": ["Email address"]}"#,
+        )
+        .unwrap();
+        let persona = crate::persona::find_persona("pii-classifier").unwrap();
+        let mut engine = mock_engine();
+
+        engine.set_persona_in(persona, dir.path()).unwrap();
+
+        assert_eq!(engine.persona, Some("pii-classifier"));
+        assert_eq!(engine.system_prompt, persona.system_prompt);
+        assert_eq!(
+            engine.knowledge_sources,
+            [dir.path().join("pii.json").to_string_lossy()]
+        );
+        let messages = engine.build_messages("customer_email");
+        assert_eq!(messages[0].content, persona.system_prompt);
+        assert!(messages[1].content.contains("Email address"));
+    }
+
+    #[test]
+    fn notes_are_sent_until_cleared() {
+        let mut engine = mock_engine();
+        with_knowledge(&mut engine, "Email address is PII");
+
+        engine.add_note("Order IDs are not PII").unwrap();
+        let messages = engine.build_messages("hello");
+        assert_eq!(
+            messages[1].content,
+            "Knowledge:\nEmail address is PII\n\n===== Ad-hoc notes =====\nOrder IDs are not PII"
+        );
+
+        engine.clear_notes().unwrap();
+        let messages = engine.build_messages("hello");
+        assert_eq!(messages[1].content, "Knowledge:\nEmail address is PII");
+        assert!(engine.notes.is_empty());
+    }
+
+    #[test]
+    fn messages_have_system_and_knowledge_then_history_and_input() {
+        let mut engine = mock_engine();
+        with_knowledge(&mut engine, "[\"email\"]");
+        engine.history_list = vec![
+            Message::new_human_message("first question"),
+            Message::new_ai_message("first answer"),
+        ];
+
+        let messages = engine.build_messages("second question");
+
+        let roles: Vec<&str> = messages
+            .iter()
+            .map(|message| role_label(&message.message_type))
+            .collect();
+        assert_eq!(roles, ["system", "system", "user", "assistant", "user"]);
+        assert_eq!(messages[1].content, "Knowledge:\n[\"email\"]");
+        assert_eq!(messages[2].content, "first question");
+        assert_eq!(messages[3].content, "first answer");
+        assert_eq!(messages[4].content, "second question");
+    }
+
+    #[tokio::test]
+    async fn session_total_accumulates_across_turns() {
+        let mut engine = mock_engine();
+        engine.ask("first").await.unwrap();
+        let first = engine.last_usage.unwrap();
+        engine.ask("second question").await.unwrap();
+        let second = engine.last_usage.unwrap();
+
+        assert!(first.estimated && second.estimated);
+        assert!(second.prompt_tokens > first.prompt_tokens);
+        assert_eq!(
+            engine.total_usage.prompt_tokens,
+            first.prompt_tokens + second.prompt_tokens
+        );
+        assert_eq!(
+            engine.total_usage.total_tokens,
+            first.total_tokens + second.total_tokens
+        );
+    }
+
+    #[tokio::test]
+    async fn a_slow_backend_times_out_without_touching_history() {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        config.request_timeout = Duration::from_millis(50);
+        let slow = SlowLlm {
+            delay: Duration::from_secs(5),
+        };
+        let mut engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(slow))).unwrap();
+        engine.history_list = vec![
+            Message::new_human_message("first question"),
+            Message::new_ai_message("first answer"),
+        ];
+
+        let result = engine.complete("second question").await;
+
+        assert!(matches!(result, Err(AppError::Timeout(_))));
+        assert_eq!(engine.history_list.len(), 2);
+        assert_eq!(engine.history_list[1].content, "first answer");
+    }
+
+    #[tokio::test]
+    async fn the_latency_of_a_delayed_backend_is_measured() {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let slow = SlowLlm {
+            delay: Duration::from_millis(200),
+        };
+        let mut engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(slow))).unwrap();
+
+        engine.ask("hello").await.unwrap();
+
+        let latency = engine.last_latency.unwrap();
+        assert!(latency >= Duration::from_millis(200), "{:?}", latency);
+        assert!(latency < Duration::from_millis(1000), "{:?}", latency);
+    }
+
+    #[tokio::test]
+    async fn a_giant_message_is_truncated_and_the_request_proceeds() {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        config.max_prompt_tokens = 2000;
+        let mut engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(MockLlm::new()))).unwrap();
+        // Multi-byte characters, so a cut by bytes would fall inside one
+        let giant = "日本語のログ".repeat(5000);
+        engine.history_list = vec![
+            Message::new_human_message(&giant),
+            Message::new_ai_message("That is a long log."),
+        ];
+
+        engine.trim_history("What failed?");
+        let answer = engine.ask("What failed?").await.unwrap();
+
+        assert_eq!(answer, "Mock answer: What failed?");
+        let truncated = &engine.history_list[0].content;
+        assert!(truncated.ends_with(history::TRUNCATION_MARKER));
+        assert!(truncated.chars().count() < giant.chars().count());
+        assert!(giant.starts_with(truncated.trim_end_matches(history::TRUNCATION_MARKER)));
+    }
+
+    #[tokio::test]
+    async fn each_ask_appends_one_audit_line() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut engine = mock_engine();
+        engine.set_audit_log(AuditLog::open(&path.to_string_lossy()).unwrap());
+
+        engine.ask("first").await.unwrap();
+        engine.ask("second").await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["input"], "first");
+        assert_eq!(lines[1]["input"], "second");
+        assert_eq!(lines[1]["output"], "Mock answer: second");
+        assert!(lines[1]["timestamp"].is_u64());
+    }
+
+    #[test]
+    fn the_knowledge_message_is_dropped_when_empty() {
+        let history = [
+            Message::new_human_message("first question"),
+            Message::new_ai_message("first answer"),
+        ];
+
+        let messages = build_messages("Be brief.", "", &history, "second question");
+
+        let roles: Vec<&str> = messages
+            .iter()
+            .map(|message| role_label(&message.message_type))
+            .collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert!(messages
+            .iter()
+            .all(|message| !message.content.starts_with("Knowledge:")));
+    }
+
+    const VALID_PII: &str = r#"{"field": "email", "is_pii": true, "category": "contact"}"#;
+
+    #[tokio::test]
+    async fn a_valid_schema_answer_is_kept() {
+        let mut engine = schema_engine(&[VALID_PII]);
+
+        let answer = engine.ask("Classify email").await.unwrap();
+
+        assert_eq!(answer, VALID_PII);
+        assert_eq!(engine.history_list.len(), 2);
+        assert!(ResponseSchema::Pii.validate(&answer).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_malformed_schema_answer_is_corrected_once() {
+        let mut engine = schema_engine(&["The email field is PII.", VALID_PII]);
+
+        let answer = engine.ask("Classify email").await.unwrap();
+
+        assert_eq!(answer, VALID_PII);
+        // The rejected answer is dropped so only the corrected one is kept
+        let contents: Vec<&str> = engine
+            .history_list
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(contents, ["Classify email", VALID_PII]);
+    }
+
+    #[tokio::test]
+    async fn a_tool_call_answer_is_kept_as_tool_calls() {
+        let tool_call = r#"[{"id": "call_1", "type": "function", "function": {"name": "lookup_field", "arguments": "{\"field\": \"email\"}"}}]"#;
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let llm = ScriptedLlm::new(&[tool_call]);
+        let mut engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(llm))).unwrap();
+
+        engine.ask("Is email PII?").await.unwrap();
+
+        let answer = engine.history_list.last().unwrap();
+        assert!(matches!(answer.message_type, MessageType::AIMessage));
+        assert_eq!(answer.content, "");
+        let calls = answer.tool_calls.as_ref().unwrap();
+        assert_eq!(calls[0]["function"]["name"], "lookup_field");
+    }
+
+    #[test]
+    fn a_text_answer_is_kept_as_text() {
+        let answer = answer_message("[1, 2, 3]");
+        assert_eq!(answer.content, "[1, 2, 3]");
+        assert!(answer.tool_calls.is_none());
+    }
+
+    #[tokio::test]
+    async fn retrieved_chunks_are_sent_without_replacing_the_knowledge() {
+        let mut engine = mock_engine();
+        with_knowledge(&mut engine, "email: PII\n\nqueue: orders");
+        let chunks = vec!["email: PII".to_string(), "queue: orders".to_string()];
+        engine.rag = Some(
+            KnowledgeIndex::build(Box::new(KeywordEmbedder), chunks, 1)
+                .await
+                .unwrap(),
+        );
+
+        engine
+            .retrieve_knowledge("Which queue has orders?")
+            .await
+            .unwrap();
+
+        assert_eq!(engine.knowledge, "email: PII\n\nqueue: orders");
+        let messages = engine.build_messages("Which queue has orders?");
+        assert_eq!(messages[1].content, "Knowledge:\nqueue: orders");
+    }
+
+    #[tokio::test]
+    async fn retrieval_follows_a_switch_of_knowledge_sources() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mq = dir.path().join("mq.json");
+        std::fs::write(&mq, r#"[{"queue": "orders"}, {"phone": "none"}]"#).unwrap();
+        let mut engine = mock_engine();
+        with_knowledge(&mut engine, "email: PII");
+        engine.rag = Some(
+            KnowledgeIndex::build(Box::new(KeywordEmbedder), vec!["email: PII".to_string()], 1)
+                .await
+                .unwrap(),
+        );
+
+        engine
+            .set_knowledge(&[mq.to_string_lossy().into_owned()])
+            .unwrap();
+        engine.retrieve_knowledge("Which queue?").await.unwrap();
+
+        let knowledge = &engine.build_messages("Which queue?")[1].content;
+        assert!(knowledge.contains("orders"), "{}", knowledge);
+        assert!(!knowledge.contains("email"));
+        assert!(!knowledge.contains("phone"));
+    }
+
+    #[tokio::test]
+    async fn an_empty_answer_leaves_history_unchanged() {
+        let responses = std::collections::HashMap::from([("filtered".to_string(), String::new())]);
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let mut engine = ChatEngine::with_backend(
+            config,
+            LlmBackend::Custom(Box::new(MockLlm::with_responses(responses))),
+        )
+        .unwrap();
+        engine.ask("hello").await.unwrap();
+        let before = engine.history_list.clone();
+
+        let result = engine.ask("filtered").await;
+
+        assert!(matches!(result, Err(AppError::EmptyResponse)));
+        let contents = |history: &[Message]| -> Vec<String> {
+            history
+                .iter()
+                .map(|message| message.content.clone())
+                .collect()
+        };
+        assert_eq!(contents(&engine.history_list), contents(&before));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::data::KnowledgeError;
+use langchain_rust::chain::ChainError;
+use langchain_rust::embedding::EmbedderError;
+use std::io;
+use thiserror::Error;
+
+// Errors surfaced to the user; each renders as a short, readable message
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("configuration error: {0}")]
+    Config(String),
+    #[error("Set {vars} (required when LLM_PROVIDER={provider}); see README")]
+    MissingEnv { vars: String, provider: String },
+    #[error(transparent)]
+    Knowledge(#[from] KnowledgeError),
+    #[error("Entra ID authentication failed: {0}")]
+    Auth(String),
+    #[error("cannot reach {0}")]
+    Unreachable(String),
+    #[error("LLM request failed: {0}")]
+    Llm(#[from] ChainError),
+    #[error("embedding request failed: {0}")]
+    Embedding(#[from] EmbedderError),
+    #[error("LLM request timed out after {0}s (LLM_TIMEOUT_SECS)")]
+    Timeout(u64),
+    #[error("model returned no content (possibly filtered); type .retry to ask again")]
+    EmptyResponse,
+    #[error("interrupted")]
+    Interrupted,
+    #[error("terminal error: {0}")]
+    Terminal(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl AppError {
+    // Function to pick the process exit code: 2 for misconfiguration, 1 otherwise
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Config(_) | AppError::MissingEnv { .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use langchain_rust::embedding::EmbedderError;
+
+    #[test]
+    fn each_variant_renders_a_readable_message() {
+        let cases = [
+            (
+                AppError::Config("invalid LLM_PROXY".to_string()),
+                "configuration error: invalid LLM_PROXY",
+            ),
+            (
+                AppError::MissingEnv {
+                    vars: "OPENAI_API_KEY".to_string(),
+                    provider: "openai".to_string(),
+                },
+                "Set OPENAI_API_KEY (required when LLM_PROVIDER=openai); see README",
+            ),
+            (
+                AppError::Knowledge(KnowledgeError::NotFound("pii.json".to_string())),
+                "knowledge file not found: pii.json (also looked in KNOWLEDGE_DIR)",
+            ),
+            (
+                AppError::Auth("no credential".to_string()),
+                "Entra ID authentication failed: no credential",
+            ),
+            (
+                AppError::Unreachable("Ollama at http://localhost:11434".to_string()),
+                "cannot reach Ollama at http://localhost:11434",
+            ),
+            (
+                AppError::Llm(ChainError::OtherError("boom".to_string())),
+                "LLM request failed: Error: boom",
+            ),
+            (
+                AppError::Embedding(EmbedderError::FastEmbedError("boom".to_string())),
+                "embedding request failed: FastEmbed error: boom",
+            ),
+            (
+                AppError::Timeout(60),
+                "LLM request timed out after 60s (LLM_TIMEOUT_SECS)",
+            ),
+            (
+                AppError::EmptyResponse,
+                "model returned no content (possibly filtered); type .retry to ask again",
+            ),
+            (AppError::Interrupted, "interrupted"),
+            (
+                AppError::Terminal("not a tty".to_string()),
+                "terminal error: not a tty",
+            ),
+            (
+                AppError::Io(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+                "I/O error: no such file",
+            ),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(error.to_string(), expected);
+        }
+
+        let json = serde_json::from_str::<u32>("x").unwrap_err();
+        assert!(AppError::Json(json).to_string().starts_with("JSON error: "));
+    }
+}
@@ -0,0 +1,143 @@
+use colored::Colorize;
+use std::sync::OnceLock;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+
+// Theme used for code blocks in answers
+const THEME_NAME: &str = "base16-ocean.dark";
+
+// A run of prose, or the body of a fenced code block with its language tag
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Prose(String),
+    Code { language: String, code: String },
+}
+
+// Function to split a response into prose and fenced code blocks, keeping every line and
+// its newline; an unclosed fence runs to the end of the text
+pub fn split_code_blocks(text: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut language: Option<String> = None;
+    for line in LinesWithEndings::from(text) {
+        let fence = line.trim_start().strip_prefix("```");
+        match (&language, fence) {
+            (None, Some(tag)) => {
+                if !current.is_empty() {
+                    segments.push(Segment::Prose(std::mem::take(&mut current)));
+                }
+                language = Some(tag.trim().to_string());
+            }
+            (Some(tag), Some(_)) => {
+                segments.push(Segment::Code {
+                    language: tag.clone(),
+                    code: std::mem::take(&mut current),
+                });
+                language = None;
+            }
+            _ => current.push_str(line),
+        }
+    }
+    match language {
+        Some(language) => segments.push(Segment::Code {
+            language,
+            code: current,
+        }),
+        None if !current.is_empty() => segments.push(Segment::Prose(current)),
+        None => {}
+    }
+    segments
+}
+
+// Function to load the bundled syntax definitions once
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+// Function to load the highlighting theme once
+fn theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults().themes;
+        themes.remove(THEME_NAME).unwrap_or_default()
+    })
+}
+
+// Function to colour code by its fence language (plain text when the language is unknown
+// or a line can't be highlighted), as 24-bit terminal escapes
+pub fn highlight_code(code: &str, language: &str) -> String {
+    let syntaxes = syntax_set();
+    let syntax = syntaxes
+        .find_syntax_by_token(language)
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, theme());
+    let mut highlighted = String::new();
+    for line in LinesWithEndings::from(code) {
+        match highlighter.highlight_line(line, syntaxes) {
+            Ok(ranges) => highlighted.push_str(&as_24_bit_terminal_escaped(&ranges, false)),
+            Err(_) => highlighted.push_str(line),
+        }
+    }
+    highlighted.push_str("\x1b[0m");
+    highlighted
+}
+
+// Function to render a fenced code block with dimmed fences around the highlighted code
+pub fn render_code_block(code: &str, language: &str) -> String {
+    format!(
+        "{}\n{}{}\n",
+        format!("```{}", language).dimmed(),
+        highlight_code(code, language),
+        "```".dimmed()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Function to count the distinct foreground colours in terminal-escaped text
+    fn colours(text: &str) -> std::collections::HashSet<&str> {
+        text.split("\x1b[")
+            .skip(1)
+            .filter_map(|escape| escape.split_once('m').map(|(code, _)| code))
+            .filter(|code| code.starts_with("38;2;"))
+            .collect()
+    }
+
+    #[test]
+    fn a_rust_block_is_split_out_and_highlighted_in_spans() {
+        let answer = "Here it is:\n```rust\nfn main() {\n    let x = 42;\n}\n```\nDone.\n";
+
+        let segments = split_code_blocks(answer);
+
+        assert_eq!(
+            segments,
+            [
+                Segment::Prose("Here it is:\n".to_string()),
+                Segment::Code {
+                    language: "rust".to_string(),
+                    code: "fn main() {\n    let x = 42;\n}\n".to_string(),
+                },
+                Segment::Prose("Done.\n".to_string()),
+            ]
+        );
+        let Segment::Code { language, code } = &segments[1] else {
+            unreachable!()
+        };
+        let highlighted = highlight_code(code, language);
+        // Keywords, names and numbers get colours of their own
+        assert!(colours(&highlighted).len() >= 3, "{:?}", highlighted);
+        assert!(highlighted.contains("fn"));
+        assert!(highlighted.contains("42"));
+    }
+
+    #[test]
+    fn an_unknown_language_is_left_in_one_colour() {
+        let highlighted = highlight_code("fn main() { let x = 42; }\n", "not-a-language");
+        assert_eq!(colours(&highlighted).len(), 1);
+    }
+}
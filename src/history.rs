@@ -0,0 +1,497 @@
+use crate::error::AppError;
+use crate::tokens;
+use langchain_rust::schemas::{Message, MessageType};
+use log::{debug, error, warn};
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+// Function to save the conversation history to a JSON file
+pub fn save_history(path: &str, history: &[Message]) -> Result<(), AppError> {
+    let content = serde_json::to_string_pretty(history)?;
+    fs::write(path, content)?;
+    debug!("Saved {} messages to {}", history.len(), path);
+    Ok(())
+}
+
+// Function to load the conversation history from a JSON file (empty on failure)
+pub fn load_history(path: &str) -> Vec<Message> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            error!("Failed to read history file {}: {}", path, e);
+            return Vec::new();
+        }
+    };
+
+    match serde_json::from_str::<Vec<Message>>(&content) {
+        Ok(history) => {
+            debug!("Loaded {} messages from {}", history.len(), path);
+            history
+        }
+        Err(e) => {
+            error!("Failed to parse history file {}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+// Function to render the conversation as a Markdown transcript
+pub fn render_transcript(history: &[Message]) -> String {
+    let mut transcript = String::new();
+    for message in history {
+        let label = match message.message_type {
+            MessageType::HumanMessage => "**You:**",
+            MessageType::AIMessage => "**Assistant:**",
+            _ => continue,
+        };
+        transcript.push_str(label);
+        transcript.push_str("\n\n");
+        transcript.push_str(message.content.trim_end());
+        transcript.push_str("\n\n");
+    }
+    transcript
+}
+
+// Function to read the .history preview length from HISTORY_PREVIEW_CHARS (default 80)
+pub fn preview_chars_from_env() -> usize {
+    std::env::var("HISTORY_PREVIEW_CHARS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(80)
+}
+
+// Function to list the conversation one message per line, numbered by turn; with a
+// preview length each message is cut to that many characters followed by "..."
+pub fn render_history(history: &[Message], preview_chars: Option<usize>) -> String {
+    let mut listing = String::new();
+    let mut turn = 0;
+    for message in history {
+        let label = match message.message_type {
+            MessageType::HumanMessage => {
+                turn += 1;
+                "You"
+            }
+            MessageType::AIMessage => "Assistant",
+            MessageType::SystemMessage => "System",
+            MessageType::ToolMessage => "Tool",
+        };
+        let content = message.content.trim();
+        let content = match preview_chars {
+            Some(max) if content.chars().count() > max => {
+                let preview: String = content.chars().take(max).collect();
+                format!("{}...", preview.replace('\n', " "))
+            }
+            _ => content.to_string(),
+        };
+        listing.push_str(&format!("[{}] {}: {}\n", turn, label, content));
+    }
+    listing
+}
+
+// Function to write the transcript to a Markdown file, appending after a separator
+// when the file already exists
+pub fn export_transcript(path: &str, history: &[Message]) -> io::Result<()> {
+    let exists = Path::new(path).exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if exists {
+        file.write_all(b"---\n\n")?;
+    }
+    file.write_all(render_transcript(history).as_bytes())?;
+    debug!("Exported {} messages to {}", history.len(), path);
+    Ok(())
+}
+
+// Function to count the exchanges (human messages) in the conversation
+pub fn count_turns(history: &[Message]) -> usize {
+    history
+        .iter()
+        .filter(|message| matches!(message.message_type, MessageType::HumanMessage))
+        .count()
+}
+
+// Function to find the most recent answer (.copy); tool-call answers have no text
+pub fn last_answer(history: &[Message]) -> Option<&str> {
+    history
+        .iter()
+        .rev()
+        .find(|message| matches!(message.message_type, MessageType::AIMessage))
+        .map(|message| message.content.as_str())
+        .filter(|content| !content.is_empty())
+}
+
+// The last answered exchanges as shown, kept apart from the model context so they can be
+// re-read (.scrollback) even after .clear or trimming
+#[derive(Debug, Clone)]
+pub struct Scrollback {
+    exchanges: VecDeque<(String, String)>,
+    capacity: usize,
+}
+
+impl Scrollback {
+    // Function to create an empty buffer holding at most `capacity` exchanges
+    pub fn new(capacity: usize) -> Self {
+        Scrollback {
+            exchanges: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // Function to create the buffer sized by SCROLLBACK_SIZE (default 50)
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("SCROLLBACK_SIZE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(50);
+        Scrollback::new(capacity)
+    }
+
+    // Function to add an exchange, evicting the oldest once the buffer is full
+    pub fn push(&mut self, input: &str, answer: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.exchanges.len() == self.capacity {
+            self.exchanges.pop_front();
+        }
+        self.exchanges
+            .push_back((input.to_string(), answer.to_string()));
+    }
+
+    // Function to check whether any exchange has been buffered yet
+    pub fn is_empty(&self) -> bool {
+        self.exchanges.is_empty()
+    }
+
+    // Function to render the buffered exchanges oldest first, like the transcript
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for (input, answer) in &self.exchanges {
+            text.push_str(&format!("You: {}\n\n", input.trim_end()));
+            text.push_str(&format!("Assistant: {}\n\n", answer.trim_end()));
+        }
+        text
+    }
+}
+
+// Function to remove the exchange starting at the human message at `start` together with
+// the replies that follow it, so no response is left orphaned. System messages are always
+// kept. Returns the removed messages.
+fn remove_turn(history: &mut Vec<Message>, start: usize) -> Vec<Message> {
+    let end = history[start + 1..]
+        .iter()
+        .position(|message| matches!(message.message_type, MessageType::HumanMessage))
+        .map(|offset| start + 1 + offset)
+        .unwrap_or(history.len());
+
+    let mut removed = Vec::new();
+    let mut index = 0;
+    history.retain(|message| {
+        let keep = index < start
+            || index >= end
+            || matches!(message.message_type, MessageType::SystemMessage);
+        if !keep {
+            removed.push(message.clone());
+        }
+        index += 1;
+        keep
+    });
+    removed
+}
+
+// Function to remove the oldest exchange (see remove_turn)
+pub fn remove_oldest_turn(history: &mut Vec<Message>) -> bool {
+    match history
+        .iter()
+        .position(|message| matches!(message.message_type, MessageType::HumanMessage))
+    {
+        Some(start) => !remove_turn(history, start).is_empty(),
+        None => false,
+    }
+}
+
+// Function to remove the most recent exchange (.undo), returning the removed messages
+pub fn remove_last_turn(history: &mut Vec<Message>) -> Vec<Message> {
+    match history
+        .iter()
+        .rposition(|message| matches!(message.message_type, MessageType::HumanMessage))
+    {
+        Some(start) => remove_turn(history, start),
+        None => Vec::new(),
+    }
+}
+
+// Marker appended to a message cut down to fit the token budget
+pub const TRUNCATION_MARKER: &str = "… [truncated]";
+
+// Function to cut a text to about `max_tokens` tokens, ending with TRUNCATION_MARKER; the
+// cut always falls between characters. None when the text already fits.
+pub fn truncate_to_tokens(text: &str, max_tokens: usize) -> Option<String> {
+    if tokens::estimate_tokens(text) <= max_tokens {
+        return None;
+    }
+    let max_chars = (max_tokens * 4).saturating_sub(TRUNCATION_MARKER.chars().count());
+    let mut truncated: String = text.chars().take(max_chars).collect();
+    truncated.push_str(TRUNCATION_MARKER);
+    Some(truncated)
+}
+
+// Function to drop the oldest exchanges until the history fits both the turn limit and
+// the token budget left after the fixed part of the prompt. Returns the turns dropped.
+pub fn truncate_history(
+    history: &mut Vec<Message>,
+    max_turns: Option<usize>,
+    token_budget: usize,
+    fixed_tokens: usize,
+) -> usize {
+    let history_tokens = |history: &[Message]| -> usize {
+        history
+            .iter()
+            .map(|message| tokens::estimate_tokens(&message.content))
+            .sum()
+    };
+
+    // Whole turns go first, oldest first, down to the most recent one
+    let mut dropped = 0;
+    loop {
+        let over_turns = max_turns.is_some_and(|max| count_turns(history) > max);
+        let over_budget = fixed_tokens + history_tokens(history) > token_budget;
+        let can_drop = over_turns || (over_budget && count_turns(history) > 1);
+        if !can_drop || !remove_oldest_turn(history) {
+            break;
+        }
+        dropped += 1;
+    }
+
+    // Dropping whole turns can't help when one message alone is over the budget, so such
+    // a message is cut down to what the fixed part and the other messages leave
+    let mut total = history_tokens(history);
+    for message in history.iter_mut() {
+        if fixed_tokens + total <= token_budget {
+            break;
+        }
+        let own = tokens::estimate_tokens(&message.content);
+        let available = token_budget.saturating_sub(fixed_tokens + total - own);
+        if let Some(truncated) = truncate_to_tokens(&message.content, available) {
+            warn!(
+                "Truncated a {:?} of about {} tokens to fit the remaining budget of {}",
+                message.message_type, own, available
+            );
+            message.content = truncated;
+            total = total - own + tokens::estimate_tokens(&message.content);
+        }
+    }
+
+    // Still over (the fixed part alone is): nothing of the history fits
+    while fixed_tokens + history_tokens(history) > token_budget && remove_oldest_turn(history) {
+        dropped += 1;
+    }
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Function to list the role and content of each message, for comparing histories
+    fn roles_and_content(history: &[Message]) -> Vec<(String, String)> {
+        history
+            .iter()
+            .map(|message| {
+                (
+                    format!("{:?}", message.message_type),
+                    message.content.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn saved_history_loads_back_with_roles_and_content() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("history.json");
+        let path = path.to_string_lossy();
+        let history = vec![
+            Message::new_system_message("Be brief."),
+            Message::new_human_message("What is PII?"),
+            Message::new_ai_message("Personally identifiable information."),
+        ];
+
+        save_history(&path, &history).unwrap();
+
+        assert_eq!(
+            roles_and_content(&load_history(&path)),
+            roles_and_content(&history)
+        );
+    }
+
+    #[test]
+    fn transcript_renders_turns_in_order_with_code_fences_intact() {
+        let history = vec![
+            Message::new_system_message("Be brief."),
+            Message::new_human_message("Show a loop"),
+            Message::new_ai_message("Here:\n\n```rust\nfor i in 0..3 {}\n```\n"),
+        ];
+        assert_eq!(
+            render_transcript(&history),
+            "**You:**\n\nShow a loop\n\n**Assistant:**\n\nHere:\n\n```rust\nfor i in 0..3 {}\n```\n\n"
+        );
+    }
+
+    #[test]
+    fn exporting_twice_appends_after_a_separator() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("transcript.md");
+        let path = path.to_string_lossy();
+        let history = vec![
+            Message::new_human_message("Hi"),
+            Message::new_ai_message("Hello"),
+        ];
+
+        export_transcript(&path, &history).unwrap();
+        export_transcript(&path, &history).unwrap();
+
+        let once = render_transcript(&history);
+        assert_eq!(
+            fs::read_to_string(path.as_ref()).unwrap(),
+            format!("{}---\n\n{}", once, once)
+        );
+    }
+
+    #[test]
+    fn oldest_turns_are_dropped_first_and_system_messages_kept() {
+        let mut history = vec![Message::new_system_message("Be brief.")];
+        for turn in 1..=4 {
+            history.push(Message::new_human_message(format!(
+                "question {} {}",
+                turn,
+                "x".repeat(40)
+            )));
+            history.push(Message::new_ai_message(format!(
+                "answer {} {}",
+                turn,
+                "y".repeat(40)
+            )));
+        }
+
+        // Each exchange is about 25 tokens, so only the last two fit with the system prompt
+        let dropped = truncate_history(&mut history, None, 60, 0);
+
+        assert_eq!(dropped, 2);
+        assert_eq!(history.len(), 5);
+        assert!(matches!(
+            history[0].message_type,
+            MessageType::SystemMessage
+        ));
+        assert!(history[1].content.starts_with("question 3"));
+        assert!(history[2].content.starts_with("answer 3"));
+        assert!(history[3].content.starts_with("question 4"));
+        assert!(history[4].content.starts_with("answer 4"));
+    }
+
+    #[test]
+    fn the_scrollback_evicts_the_oldest_beyond_capacity() {
+        let mut scrollback = Scrollback::new(2);
+        assert!(scrollback.is_empty());
+        for turn in 1..=3 {
+            scrollback.push(&format!("question {}", turn), &format!("answer {}", turn));
+        }
+
+        assert_eq!(
+            scrollback.render(),
+            "You: question 2\n\nAssistant: answer 2\n\nYou: question 3\n\nAssistant: answer 3\n\n"
+        );
+    }
+
+    #[test]
+    fn a_zero_sized_scrollback_keeps_nothing() {
+        let mut scrollback = Scrollback::new(0);
+        scrollback.push("question", "answer");
+        assert!(scrollback.is_empty());
+    }
+
+    #[test]
+    fn the_turn_limit_keeps_the_most_recent_turns() {
+        let mut history = Vec::new();
+        for turn in 1..=3 {
+            history.push(Message::new_human_message(format!("question {}", turn)));
+            history.push(Message::new_ai_message(format!("answer {}", turn)));
+        }
+
+        assert_eq!(truncate_history(&mut history, Some(1), usize::MAX, 0), 2);
+        assert_eq!(count_turns(&history), 1);
+        assert_eq!(history[0].content, "question 3");
+    }
+
+    #[test]
+    fn undo_on_a_two_turn_history_leaves_one_turn() {
+        let mut history = vec![
+            Message::new_system_message("Be brief."),
+            Message::new_human_message("question 1"),
+            Message::new_ai_message("answer 1"),
+            Message::new_human_message("question 2"),
+            Message::new_ai_message("answer 2"),
+        ];
+
+        let removed = remove_last_turn(&mut history);
+
+        assert_eq!(
+            roles_and_content(&removed),
+            roles_and_content(&[
+                Message::new_human_message("question 2"),
+                Message::new_ai_message("answer 2"),
+            ])
+        );
+        assert_eq!(count_turns(&history), 1);
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[2].content, "answer 1");
+    }
+
+    #[test]
+    fn undo_on_an_empty_history_removes_nothing() {
+        let mut history = Vec::new();
+        assert!(remove_last_turn(&mut history).is_empty());
+    }
+
+    #[test]
+    fn the_history_listing_has_one_entry_per_message() {
+        let history = vec![
+            Message::new_system_message("Be brief."),
+            Message::new_human_message("What is PII?"),
+            Message::new_ai_message(format!("Personal data.\n{}", "x".repeat(100))),
+            Message::new_human_message("Thanks"),
+            Message::new_ai_message("You're welcome."),
+        ];
+
+        let preview = render_history(&history, Some(20));
+        let full = render_history(&history, None);
+
+        assert_eq!(preview.lines().count(), history.len());
+        assert_eq!(
+            preview.lines().collect::<Vec<_>>(),
+            [
+                "[0] System: Be brief.",
+                "[1] You: What is PII?",
+                "[1] Assistant: Personal data. xxxxx...",
+                "[2] You: Thanks",
+                "[2] Assistant: You're welcome.",
+            ]
+        );
+        assert!(full.contains(&"x".repeat(100)));
+    }
+
+    #[test]
+    fn there_is_nothing_to_copy_before_the_first_answer() {
+        assert_eq!(last_answer(&[]), None);
+        assert_eq!(last_answer(&[Message::new_human_message("Hi")]), None);
+
+        let history = vec![
+            Message::new_human_message("Hi"),
+            Message::new_ai_message("Hello"),
+            Message::new_human_message("Again"),
+        ];
+        assert_eq!(last_answer(&history), Some("Hello"));
+    }
+}
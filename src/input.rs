@@ -0,0 +1,384 @@
+use crate::command::COMMANDS;
+use aichat_cli::interrupt::Interrupts;
+use aichat_cli::output::Output;
+use colored::Colorize;
+use log::{error, info};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Config, Context, Editor, Helper};
+use std::path::{Path, PathBuf};
+
+// Opens and closes a multi-line prompt when it starts a line
+const BLOCK_DELIMITER: &str = "\"\"\"";
+
+pub type InputEditor = Editor<CommandHelper, DefaultHistory>;
+
+// Where typed lines come from, and where the accepted ones are remembered: the line
+// editor, or scripted lines in tests
+pub trait LineSource {
+    fn read_line(&mut self, prompt: &str) -> rustyline::Result<String>;
+
+    fn add_history(&mut self, line: &str);
+}
+
+impl LineSource for InputEditor {
+    fn read_line(&mut self, prompt: &str) -> rustyline::Result<String> {
+        self.readline(prompt)
+    }
+
+    fn add_history(&mut self, line: &str) {
+        if let Err(e) = self.add_history_entry(line) {
+            error!("Error recording input history: {}", e);
+        }
+    }
+}
+
+// Line editor helper that completes commands at the start of the line
+pub struct CommandHelper;
+
+impl Completer for CommandHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(' ') {
+            return Ok((pos, Vec::new()));
+        }
+
+        let candidates = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(prefix))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
+
+// Function to read how many typed lines to remember from INPUT_HISTORY_SIZE (default 1000)
+fn input_history_size() -> usize {
+    std::env::var("INPUT_HISTORY_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1000)
+}
+
+// Function to locate the file typed lines are kept in across runs (~/.aichat_history)
+pub fn input_history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".aichat_history"))
+}
+
+// Function to create the line editor with command completion and the typed lines of
+// earlier runs; a missing history file just starts empty
+pub fn create_editor() -> rustyline::Result<InputEditor> {
+    let mut editor = new_editor(input_history_size())?;
+    if let Some(path) = input_history_path() {
+        load_input_history(&mut editor, &path);
+    }
+    Ok(editor)
+}
+
+// Function to create a line editor with command completion remembering max_lines lines
+fn new_editor(max_lines: usize) -> rustyline::Result<InputEditor> {
+    let config = Config::builder().max_history_size(max_lines)?.build();
+    let mut editor = InputEditor::with_config(config)?;
+    editor.set_helper(Some(CommandHelper));
+    Ok(editor)
+}
+
+// Function to load typed lines saved by an earlier run, if the file exists
+fn load_input_history(editor: &mut InputEditor, path: &Path) {
+    if path.exists() {
+        if let Err(e) = editor.load_history(path) {
+            error!("Error loading input history: {}", e);
+        }
+    }
+}
+
+// Function to save the typed lines, commands included, for the next run
+pub fn save_input_history(editor: &mut InputEditor) {
+    if let Some(path) = input_history_path() {
+        save_input_history_to(editor, &path);
+    }
+}
+
+// Function to save the typed lines to a given file
+fn save_input_history_to(editor: &mut InputEditor, path: &Path) {
+    if let Err(e) = editor.save_history(path) {
+        error!("Error saving input history: {}", e);
+    }
+}
+
+// Function to read the input prompt from PROMPT_TEXT
+pub fn prompt_text() -> String {
+    prompt_text_from(std::env::var("PROMPT_TEXT").ok())
+}
+
+// Function to choose the input prompt given the PROMPT_TEXT value, if set
+fn prompt_text_from(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "Please enter some text and press Enter: ".to_string())
+}
+
+// Function to handle user input (Refactor input handling logic); returns None when the
+// session should end: stdin closed (EOF), a double Ctrl-C or an unreadable terminal.
+// Blank lines just ask again.
+pub fn get_user_input(
+    editor: &mut impl LineSource,
+    interrupts: &Interrupts,
+    output: &mut dyn Output,
+) -> Option<String> {
+    let prompt = prompt_text().bright_green().to_string();
+
+    loop {
+        if !interrupts.is_running() {
+            return None;
+        }
+
+        let line = match editor.read_line(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                // The editor owns the terminal while reading, so Ctrl-C arrives here
+                if interrupts.press() {
+                    return None;
+                }
+                output.write_line(
+                    &"Press Ctrl-C again within 2 seconds to exit."
+                        .yellow()
+                        .to_string(),
+                );
+                continue;
+            }
+            Err(ReadlineError::Eof) => {
+                info!("Input closed, ending the session");
+                return None;
+            }
+            Err(e) => {
+                error!("Error reading input: {}", e);
+                return None;
+            }
+        };
+
+        let input = match line.trim_start().strip_prefix(BLOCK_DELIMITER) {
+            // An empty or cancelled block asks again rather than ending the session
+            Some(first) => match read_block(editor, first, output)? {
+                Some(block) if !block.trim().is_empty() => block,
+                _ => continue,
+            },
+            None => line.trim().to_string(),
+        };
+        if input.is_empty() {
+            continue;
+        }
+
+        editor.add_history(&input);
+        return Some(input);
+    }
+}
+
+// Function to collect the lines of a multi-line block up to a closing delimiter on its
+// own line, joined with their newlines intact. `first` is any text after the opening
+// delimiter. Returns Some(None) when the block is cancelled with Ctrl-C and None on EOF.
+fn read_block(
+    editor: &mut impl LineSource,
+    first: &str,
+    output: &mut dyn Output,
+) -> Option<Option<String>> {
+    let mut lines = Vec::new();
+    if !first.trim().is_empty() {
+        lines.push(first.to_string());
+    }
+
+    loop {
+        match editor.read_line(&"... ".bright_green().to_string()) {
+            Ok(line) if line.trim() == BLOCK_DELIMITER => return Some(Some(lines.join("\n"))),
+            Ok(line) => lines.push(line),
+            Err(ReadlineError::Interrupted) => {
+                output.write_line(&"Multi-line input cancelled".yellow().to_string());
+                return Some(None);
+            }
+            Err(ReadlineError::Eof) => {
+                info!("Input closed inside a multi-line block, ending the session");
+                return None;
+            }
+            Err(e) => {
+                error!("Error reading input: {}", e);
+                return None;
+            }
+        }
+    }
+}
+
+// Function to ask a yes/no question on the line editor; anything other than y/yes,
+// including Ctrl-C or closed input, counts as no
+pub fn confirm(question: &str, editor: &mut impl LineSource) -> bool {
+    let prompt = format!("{} [y/N] ", question.yellow());
+    match editor.read_line(&prompt) {
+        Ok(answer) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::history::History;
+    use std::collections::VecDeque;
+
+    // Lines typed in advance; once they run out the input is closed (EOF)
+    struct ScriptedLines {
+        lines: VecDeque<&'static str>,
+        history: Vec<String>,
+    }
+
+    impl LineSource for ScriptedLines {
+        fn read_line(&mut self, _prompt: &str) -> rustyline::Result<String> {
+            self.lines
+                .pop_front()
+                .map(str::to_string)
+                .ok_or(ReadlineError::Eof)
+        }
+
+        fn add_history(&mut self, line: &str) {
+            self.history.push(line.to_string());
+        }
+    }
+
+    // Output keeping the lines written, for asserting on notices
+    #[derive(Default)]
+    struct CaptureOutput {
+        lines: Vec<String>,
+    }
+
+    impl Output for CaptureOutput {
+        fn write_chunk(&mut self, text: &str) {
+            self.lines.push(text.to_string());
+        }
+
+        fn write_line(&mut self, text: &str) {
+            self.lines.push(text.to_string());
+        }
+    }
+
+    fn scripted(lines: &[&'static str]) -> ScriptedLines {
+        ScriptedLines {
+            lines: lines.iter().copied().collect(),
+            history: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn saved_input_history_loads_back_in_order() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(".aichat_history");
+        let typed = ["first prompt", ".clear", "second prompt"];
+        let mut editor = new_editor(1000).unwrap();
+        for line in typed {
+            editor.add_history_entry(line).unwrap();
+        }
+        save_input_history_to(&mut editor, &path);
+
+        let mut reopened = new_editor(1000).unwrap();
+        load_input_history(&mut reopened, &path);
+
+        let loaded: Vec<&String> = reopened.history().iter().collect();
+        assert_eq!(loaded, typed);
+    }
+
+    #[test]
+    fn a_missing_input_history_starts_empty() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut editor = new_editor(1000).unwrap();
+        load_input_history(&mut editor, &dir.path().join("missing"));
+        assert!(editor.history().is_empty());
+    }
+
+    #[test]
+    fn the_prompt_is_taken_from_prompt_text() {
+        assert_eq!(prompt_text_from(Some("> ".to_string())), "> ");
+    }
+
+    #[test]
+    fn the_default_prompt_is_used_without_prompt_text() {
+        assert_eq!(
+            prompt_text_from(None),
+            "Please enter some text and press Enter: "
+        );
+    }
+
+    #[test]
+    fn a_three_line_block_keeps_its_newlines() {
+        let mut lines = scripted(&["fn main() {", "    println!(\"hi\");", "}", "\"\"\""]);
+
+        let block = read_block(&mut lines, "", &mut CaptureOutput::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(block, "fn main() {\n    println!(\"hi\");\n}");
+    }
+
+    #[test]
+    fn text_after_the_opening_delimiter_starts_the_block() {
+        let mut lines = scripted(&["second", "third", "  \"\"\"  "]);
+
+        let block = read_block(&mut lines, "first", &mut CaptureOutput::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(block, "first\nsecond\nthird");
+    }
+
+    #[test]
+    fn closed_input_ends_the_session_unlike_typing_exit() {
+        let interrupts = Interrupts::default();
+        let mut lines = scripted(&["hello", "   ", "exit"]);
+        let mut output = CaptureOutput::default();
+
+        assert_eq!(
+            get_user_input(&mut lines, &interrupts, &mut output).as_deref(),
+            Some("hello")
+        );
+        // The blank line is skipped; `exit` is handed back for the caller to act on
+        assert_eq!(
+            get_user_input(&mut lines, &interrupts, &mut output).as_deref(),
+            Some("exit")
+        );
+        // No lines left: the reader reports EOF and the loop ends without asking again
+        assert_eq!(get_user_input(&mut lines, &interrupts, &mut output), None);
+        assert_eq!(get_user_input(&mut lines, &interrupts, &mut output), None);
+        assert_eq!(lines.history, ["hello", "exit"]);
+        assert!(output.lines.is_empty());
+    }
+
+    #[test]
+    fn only_yes_confirms_and_closed_input_declines() {
+        let mut lines = scripted(&["y", " YES ", "n", ""]);
+
+        assert!(confirm("Send anyway?", &mut lines));
+        assert!(confirm("Send anyway?", &mut lines));
+        assert!(!confirm("Send anyway?", &mut lines));
+        assert!(!confirm("Send anyway?", &mut lines));
+        assert!(!confirm("Send anyway?", &mut lines));
+        // The answers are not remembered as prompts
+        assert!(lines.history.is_empty());
+    }
+}
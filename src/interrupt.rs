@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// A second Ctrl-C within this window exits instead of cancelling
+const EXIT_WINDOW: Duration = Duration::from_secs(2);
+
+// Ctrl-C state shared with the signal handler: the first press clears `active` to abort
+// the current operation, a second press within EXIT_WINDOW also clears `running`
+#[derive(Clone)]
+pub struct Interrupts {
+    pub running: Arc<AtomicBool>,
+    pub active: Arc<AtomicBool>,
+    last_press: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Default for Interrupts {
+    fn default() -> Self {
+        Interrupts {
+            running: Arc::new(AtomicBool::new(true)),
+            active: Arc::new(AtomicBool::new(true)),
+            last_press: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Interrupts {
+    // Function to record a Ctrl-C press; returns true when the program should exit
+    pub fn press(&self) -> bool {
+        let now = Instant::now();
+        let mut last_press = self.last_press.lock().unwrap();
+        let exit = last_press.is_some_and(|last| now.duration_since(last) < EXIT_WINDOW);
+        *last_press = Some(now);
+
+        self.active.store(false, Ordering::SeqCst);
+        if exit {
+            self.running.store(false, Ordering::SeqCst);
+        }
+        exit
+    }
+
+    // Function to check whether the program should keep running
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    // Function to re-arm the operation flag before starting a new request
+    pub fn begin_operation(&self) {
+        self.active.store(true, Ordering::SeqCst);
+    }
+}
+
+// Function to wait until the current operation is cancelled by Ctrl-C
+pub async fn cancelled(active: &AtomicBool) {
+    while active.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
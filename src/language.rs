@@ -0,0 +1,60 @@
+use log::error;
+
+// Response languages accepted by RESPONSE_LANGUAGE and .lang (BCP-47 code, name used in
+// the instruction)
+const LANGUAGES: &[(&str, &str)] = &[
+    ("en", "English"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+    ("zh-CN", "Simplified Chinese"),
+    ("zh-TW", "Traditional Chinese"),
+    ("th", "Thai"),
+    ("vi", "Vietnamese"),
+    ("de", "German"),
+    ("fr", "French"),
+    ("es", "Spanish"),
+    ("pt-BR", "Brazilian Portuguese"),
+];
+
+// Function to look up a language code case-insensitively, returning its canonical form
+pub fn parse_language(code: &str) -> Result<&'static str, String> {
+    let code = code.trim();
+    LANGUAGES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(code))
+        .map(|(known, _)| *known)
+        .ok_or_else(|| {
+            let known: Vec<&str> = LANGUAGES.iter().map(|(known, _)| *known).collect();
+            format!(
+                "unsupported language '{}', expected one of: {}",
+                code,
+                known.join(", ")
+            )
+        })
+}
+
+// Function to read RESPONSE_LANGUAGE, ignoring unsupported codes
+pub fn language_from_env() -> Option<String> {
+    let code = std::env::var("RESPONSE_LANGUAGE").ok()?;
+    match parse_language(&code) {
+        Ok(code) => Some(code.to_string()),
+        Err(e) => {
+            error!("Ignoring RESPONSE_LANGUAGE: {}", e);
+            None
+        }
+    }
+}
+
+// Function to append the response language instruction, if any, to a system message
+pub fn with_language(system_prompt: &str, language: Option<&str>) -> String {
+    let name = language.and_then(|code| {
+        LANGUAGES
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map(|(_, name)| *name)
+    });
+    match name {
+        Some(name) => format!("{}\n\nRespond in {}.", system_prompt, name),
+        None => system_prompt.to_string(),
+    }
+}
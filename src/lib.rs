@@ -0,0 +1,38 @@
+// Core of AI Chat CLI: knowledge loading, the chat engine and terminal helpers.
+// The binary in main.rs is a thin REPL over these modules.
+pub mod aad;
+pub mod audit;
+pub mod batch;
+pub mod cache;
+pub mod config;
+pub mod data;
+pub mod diff;
+pub mod engine;
+pub mod error;
+pub mod highlight;
+pub mod history;
+pub mod interrupt;
+pub mod language;
+pub mod llm;
+pub mod markdown;
+pub mod mock;
+pub mod openai;
+pub mod output;
+pub mod persona;
+pub mod preamble;
+pub mod preprocess;
+pub mod rag;
+pub mod recording;
+pub mod redact;
+pub mod retry;
+pub mod schema;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod session;
+pub mod templates;
+pub mod terminal;
+pub mod tokens;
+pub mod watch;
+
+pub use engine::{ChatEngine, EngineConfig};
+pub use error::AppError;
@@ -0,0 +1,578 @@
+use crate::aad::{AadAzureConfig, AzureCredentialProvider};
+use crate::error::AppError;
+use crate::mock::MockLlm;
+use crate::openai::{OpenAiChat, OpenAiEmbeddings};
+use crate::redact::{log_safe, LogKind};
+use langchain_rust::embedding::Embedder;
+use langchain_rust::language_models::llm::LLM;
+use langchain_rust::language_models::options::CallOptions;
+use langchain_rust::llm::{AzureConfig, OpenAIConfig};
+use log::{debug, error};
+use reqwest::{NoProxy, Proxy};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How long the Ollama server gets to answer the startup probe
+const OLLAMA_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// LLM backends selectable with LLM_PROVIDER
+pub enum LlmBackend {
+    Azure(OpenAiChat<AzureConfig>),
+    // Azure with Entra ID bearer tokens (AUTH_MODE=aad); the settings are kept so a rebuild
+    // reuses the token instead of fetching another one
+    AzureAad(OpenAiChat<AadAzureConfig>, AadAzureConfig),
+    OpenAI(OpenAiChat<OpenAIConfig>),
+    // Any other langchain LLM, for embedding the engine (e.g. a stub in tests)
+    Custom(Box<dyn LLM>),
+}
+
+impl LlmBackend {
+    // Function to box the backend so it can be handed to the chain builder
+    pub fn to_llm(&self) -> Box<dyn LLM> {
+        match self {
+            LlmBackend::Azure(llm) => Box::new(llm.clone()),
+            LlmBackend::AzureAad(llm, _) => Box::new(llm.clone()),
+            LlmBackend::OpenAI(llm) => Box::new(llm.clone()),
+            LlmBackend::Custom(llm) => llm.clone_box(),
+        }
+    }
+}
+
+// Box<dyn LLM> isn't Clone, so custom backends are copied through LLMClone::clone_box
+impl Clone for LlmBackend {
+    fn clone(&self) -> Self {
+        match self {
+            LlmBackend::Azure(llm) => LlmBackend::Azure(llm.clone()),
+            LlmBackend::AzureAad(llm, config) => LlmBackend::AzureAad(llm.clone(), config.clone()),
+            LlmBackend::OpenAI(llm) => LlmBackend::OpenAI(llm.clone()),
+            LlmBackend::Custom(llm) => LlmBackend::Custom(llm.clone_box()),
+        }
+    }
+}
+
+// Sampling settings applied to every request
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SamplingOptions {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl SamplingOptions {
+    // Function to read LLM_TEMPERATURE and LLM_MAX_TOKENS, ignoring invalid values
+    pub fn from_env() -> Self {
+        let temperature = std::env::var("LLM_TEMPERATURE").ok().and_then(|value| {
+            match parse_temperature(&value) {
+                Ok(temperature) => Some(temperature),
+                Err(e) => {
+                    error!("Ignoring LLM_TEMPERATURE: {}", e);
+                    None
+                }
+            }
+        });
+        let max_tokens =
+            std::env::var("LLM_MAX_TOKENS")
+                .ok()
+                .and_then(|value| match parse_max_tokens(&value) {
+                    Ok(max_tokens) => Some(max_tokens),
+                    Err(e) => {
+                        error!("Ignoring LLM_MAX_TOKENS: {}", e);
+                        None
+                    }
+                });
+        SamplingOptions {
+            temperature,
+            max_tokens,
+        }
+    }
+
+    // Function to convert the settings into langchain call options
+    fn to_call_options(self) -> CallOptions {
+        let mut options = CallOptions::default();
+        if let Some(temperature) = self.temperature {
+            options = options.with_temperature(temperature);
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            options = options.with_max_tokens(max_tokens);
+        }
+        options
+    }
+}
+
+// Function to parse a temperature, accepting only 0.0 to 2.0
+pub fn parse_temperature(value: &str) -> Result<f32, String> {
+    match value.trim().parse::<f32>() {
+        Ok(temperature) if (0.0..=2.0).contains(&temperature) => Ok(temperature),
+        _ => Err(format!(
+            "temperature must be between 0.0 and 2.0, got '{}'",
+            value
+        )),
+    }
+}
+
+// Function to parse a positive max_tokens value
+pub fn parse_max_tokens(value: &str) -> Result<u32, String> {
+    match value.trim().parse::<u32>() {
+        Ok(max_tokens) if max_tokens > 0 => Ok(max_tokens),
+        _ => Err(format!(
+            "max_tokens must be a positive integer, got '{}'",
+            value
+        )),
+    }
+}
+
+// Function to read the LLM provider from LLM_PROVIDER (azure | openai | ollama | mock,
+// default azure)
+pub(crate) fn provider() -> String {
+    std::env::var("LLM_PROVIDER")
+        .unwrap_or_else(|_| "azure".to_string())
+        .to_lowercase()
+}
+
+// Function to check whether LLM_PROVIDER selects the offline mock backend
+pub fn is_mock_provider() -> bool {
+    provider() == "mock"
+}
+
+// Function to resolve the model (Azure deployment id) configured for the provider
+pub fn default_model() -> String {
+    let (var, default) = match provider().as_str() {
+        "openai" => ("OPENAI_MODEL", "gpt-4"),
+        "ollama" => ("OLLAMA_MODEL", "llama3"),
+        "mock" => ("MOCK_MODEL", "mock"),
+        _ => ("OPEN_AI_DEPLOYMENT_ID", "gpt-4"),
+    };
+    std::env::var(var).unwrap_or_else(|_| default.to_string())
+}
+
+// Function to check the environment variables a provider requires; when any is missing
+// the error names all of them so they can be set in one go
+fn check_required_env(vars: &[&str], provider: &str) -> Result<(), AppError> {
+    check_required(vars, provider, |var| std::env::var(var).ok())
+}
+
+// Function to check required settings looked up through `value`, reporting them all
+// together when any is unset or empty
+fn check_required(
+    vars: &[&str],
+    provider: &str,
+    value: impl Fn(&str) -> Option<String>,
+) -> Result<(), AppError> {
+    if vars
+        .iter()
+        .any(|var| value(var).is_none_or(|value| value.is_empty()))
+    {
+        return Err(AppError::MissingEnv {
+            vars: vars.join(" and "),
+            provider: provider.to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Function to read a required environment variable for the given provider
+fn required_env(var: &str, provider: &str) -> Result<String, AppError> {
+    check_required_env(&[var], provider)?;
+    Ok(std::env::var(var).unwrap_or_default())
+}
+
+// Function to report an LLM_PROVIDER value that isn't supported
+fn unknown_provider(provider: &str) -> AppError {
+    AppError::Config(format!(
+        "unknown LLM_PROVIDER '{}', expected 'azure', 'openai', 'ollama' or 'mock'",
+        provider
+    ))
+}
+
+// Function to check the proxy settings at startup so a bad proxy fails early rather than on
+// the first request
+pub fn check_proxy() -> Result<(), AppError> {
+    llm_http_client().map(|_| ())
+}
+
+// Function to build the HTTP client for LLM requests (see llm_client_builder)
+pub fn llm_http_client() -> Result<reqwest::Client, AppError> {
+    llm_client_builder(|var| std::env::var(var).ok())?
+        .build()
+        .map_err(|e| AppError::Config(format!("failed to build the HTTP client: {}", e)))
+}
+
+// Function to configure the HTTP client builder for LLM requests, given a lookup of the
+// environment. LLM_PROXY, when set, proxies them (except to NO_PROXY hosts) in place of
+// HTTPS_PROXY/HTTP_PROXY, which reqwest otherwise applies to every client as usual; each
+// proxy URL is checked here.
+fn llm_client_builder(
+    value: impl Fn(&str) -> Option<String>,
+) -> Result<reqwest::ClientBuilder, AppError> {
+    for var in ["HTTPS_PROXY", "HTTP_PROXY"] {
+        if let Some(proxy) = value(var) {
+            Proxy::all(&proxy).map_err(|e| AppError::Config(format!("invalid {}: {}", var, e)))?;
+        }
+    }
+
+    let builder = reqwest::Client::builder();
+    let Some(proxy) = value("LLM_PROXY") else {
+        return Ok(builder);
+    };
+    debug!("llm_proxy: {}", log_safe(&proxy, LogKind::Url));
+    let no_proxy = value("NO_PROXY").and_then(|hosts| NoProxy::from_string(&hosts));
+    let proxy = Proxy::all(&proxy)
+        .map_err(|e| AppError::Config(format!("invalid LLM_PROXY: {}", e)))?
+        .no_proxy(no_proxy);
+    Ok(builder.proxy(proxy))
+}
+
+// Function to create the LLM backend selected by LLM_PROVIDER for the given model
+pub async fn create_llm(model: &str, sampling: SamplingOptions) -> Result<LlmBackend, AppError> {
+    let provider = provider();
+    debug!("llm_provider: {}", provider);
+
+    match provider.as_str() {
+        "azure" if aad_auth()? => create_openai_aad(aad_azure_config(model).await?, sampling),
+        "azure" => Ok(LlmBackend::Azure(create_openai(model, sampling)?)),
+        "openai" => Ok(LlmBackend::OpenAI(create_openai_public(model, sampling)?)),
+        "ollama" => Ok(LlmBackend::OpenAI(create_ollama(model, sampling).await?)),
+        "mock" => Ok(LlmBackend::Custom(Box::new(MockLlm::from_env()?))),
+        other => Err(unknown_provider(other)),
+    }
+}
+
+// Function to create the backend for another model or sampling settings; an Entra ID
+// backend keeps its token and refresher rather than fetching a new token
+pub async fn recreate_llm(
+    previous: &LlmBackend,
+    model: &str,
+    sampling: SamplingOptions,
+) -> Result<LlmBackend, AppError> {
+    match previous {
+        LlmBackend::AzureAad(_, config) => {
+            create_openai_aad(config.with_deployment(model), sampling)
+        }
+        _ => create_llm(model, sampling).await,
+    }
+}
+
+// Function to create the Azure OpenAI configuration (Refactor LLM setup)
+pub fn create_openai(
+    deployment_id: &str,
+    sampling: SamplingOptions,
+) -> Result<OpenAiChat<AzureConfig>, AppError> {
+    debug!("temperature: {:?}", sampling.temperature);
+    debug!("max_tokens: {:?}", sampling.max_tokens);
+
+    let azure_config = azure_config(deployment_id)?;
+    Ok(OpenAiChat::new(azure_config, llm_http_client()?).with_options(sampling.to_call_options()))
+}
+
+// Function to read AUTH_MODE: `key` (default) authenticates Azure with OPEN_AI_SERVICE_KEY,
+// `aad` with an Entra ID token from DefaultAzureCredential
+fn aad_auth() -> Result<bool, AppError> {
+    match std::env::var("AUTH_MODE")
+        .unwrap_or_default()
+        .to_lowercase()
+        .as_str()
+    {
+        "" | "key" => Ok(false),
+        "aad" => Ok(true),
+        other => Err(AppError::Config(format!(
+            "unknown AUTH_MODE '{}', expected key or aad",
+            other
+        ))),
+    }
+}
+
+// Function to create the Azure OpenAI backend authenticated with Entra ID
+pub fn create_openai_aad(
+    config: AadAzureConfig,
+    sampling: SamplingOptions,
+) -> Result<LlmBackend, AppError> {
+    let llm = OpenAiChat::new(config.clone(), llm_http_client()?)
+        .with_options(sampling.to_call_options());
+    Ok(LlmBackend::AzureAad(llm, config))
+}
+
+// Function to wrap the Azure settings of a deployment with a refreshed bearer token; the
+// first token is fetched here so a credential problem is reported at startup
+async fn aad_azure_config(deployment_id: &str) -> Result<AadAzureConfig, AppError> {
+    let azure = azure_endpoint(deployment_id)?;
+    let provider = Arc::new(AzureCredentialProvider::new()?);
+    AadAzureConfig::new(azure, provider).await
+}
+
+// Function to build the Azure connection settings for a deployment
+fn azure_config(deployment_id: &str) -> Result<AzureConfig, AppError> {
+    check_required_env(&["OPEN_AI_SERVICE_URL", "OPEN_AI_SERVICE_KEY"], "azure")?;
+    let open_ai_key = required_env("OPEN_AI_SERVICE_KEY", "azure")?;
+    debug!("open_ai_key: {}", log_safe(&open_ai_key, LogKind::ApiKey));
+    Ok(azure_endpoint(deployment_id)?.with_api_key(open_ai_key))
+}
+
+// Function to build the Azure endpoint settings for a deployment, without credentials
+fn azure_endpoint(deployment_id: &str) -> Result<AzureConfig, AppError> {
+    let open_ai_url = required_env("OPEN_AI_SERVICE_URL", "azure")?;
+
+    let api_version =
+        std::env::var("OPEN_AI_API_VERSION").unwrap_or_else(|_| "2023-03-15-preview".to_string());
+
+    Ok(azure_settings(&open_ai_url, &api_version, deployment_id))
+}
+
+// Function to combine the Azure endpoint, API version and deployment into its settings
+fn azure_settings(open_ai_url: &str, api_version: &str, deployment_id: &str) -> AzureConfig {
+    debug!("open_ai_url: {}", log_safe(open_ai_url, LogKind::Url));
+    debug!("deployment_id: {}", deployment_id);
+    debug!("api_version: {}", api_version);
+
+    AzureConfig::default()
+        .with_api_base(open_ai_url)
+        .with_api_version(api_version)
+        .with_deployment_id(deployment_id)
+}
+
+// Function to create the api.openai.com configuration
+pub fn create_openai_public(
+    model: &str,
+    sampling: SamplingOptions,
+) -> Result<OpenAiChat<OpenAIConfig>, AppError> {
+    let api_key = required_env("OPENAI_API_KEY", "openai")?;
+
+    debug!("openai_model: {}", model);
+    debug!("temperature: {:?}", sampling.temperature);
+    debug!("max_tokens: {:?}", sampling.max_tokens);
+
+    let config = OpenAIConfig::default().with_api_key(api_key);
+    Ok(OpenAiChat::new(config, llm_http_client()?)
+        .with_model(model)
+        .with_options(sampling.to_call_options()))
+}
+
+// Function to read the Ollama server address from OLLAMA_HOST
+fn ollama_host() -> String {
+    std::env::var("OLLAMA_HOST").unwrap_or_else(|_| "http://localhost:11434".to_string())
+}
+
+// Function to create a local Ollama configuration through its OpenAI-compatible API;
+// Ollama needs no key, so the server is probed here to report an unreachable one at startup
+pub async fn create_ollama(
+    model: &str,
+    sampling: SamplingOptions,
+) -> Result<OpenAiChat<OpenAIConfig>, AppError> {
+    let host = ollama_host();
+    probe_ollama(&host).await?;
+    let api_base = format!("{}/v1", host.trim_end_matches('/'));
+
+    debug!("ollama_api_base: {}", log_safe(&api_base, LogKind::Url));
+    debug!("ollama_model: {}", model);
+    debug!("temperature: {:?}", sampling.temperature);
+    debug!("max_tokens: {:?}", sampling.max_tokens);
+
+    let config = OpenAIConfig::default()
+        .with_api_base(api_base)
+        .with_api_key("ollama");
+    Ok(OpenAiChat::new(config, llm_http_client()?)
+        .with_model(model)
+        .with_options(sampling.to_call_options()))
+}
+
+// Function to check that an Ollama server answers on host by listing its models
+async fn probe_ollama(host: &str) -> Result<(), AppError> {
+    let url = format!("{}/api/tags", host.trim_end_matches('/'));
+    let unreachable = |e: reqwest::Error| {
+        AppError::Unreachable(format!(
+            "Ollama at {} ({}); is `ollama serve` running?",
+            log_safe(host, LogKind::Url),
+            e
+        ))
+    };
+    llm_http_client()?
+        .get(&url)
+        .timeout(OLLAMA_PROBE_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(unreachable)?;
+    debug!("Ollama is reachable at {}", log_safe(host, LogKind::Url));
+    Ok(())
+}
+
+// Function to create the embedder used for --rag, on the provider selected by LLM_PROVIDER;
+// an Entra ID chat backend lends it its token
+pub async fn create_embedder(backend: &LlmBackend) -> Result<Box<dyn Embedder>, AppError> {
+    let provider = provider();
+    let embedder: Box<dyn Embedder> = match provider.as_str() {
+        "azure" => {
+            let deployment_id = std::env::var("OPEN_AI_EMBEDDING_DEPLOYMENT_ID")
+                .unwrap_or_else(|_| "text-embedding-ada-002".to_string());
+            let http_client = llm_http_client()?;
+            if let LlmBackend::AzureAad(_, config) = backend {
+                let config = config.with_deployment(&deployment_id);
+                Box::new(OpenAiEmbeddings::new(config, http_client, deployment_id))
+            } else if aad_auth()? {
+                let config = aad_azure_config(&deployment_id).await?;
+                Box::new(OpenAiEmbeddings::new(config, http_client, deployment_id))
+            } else {
+                let config = azure_config(&deployment_id)?;
+                Box::new(OpenAiEmbeddings::new(config, http_client, deployment_id))
+            }
+        }
+        "openai" => {
+            let api_key = required_env("OPENAI_API_KEY", "openai")?;
+            let model = std::env::var("OPENAI_EMBEDDING_MODEL")
+                .unwrap_or_else(|_| "text-embedding-3-small".to_string());
+            let config = OpenAIConfig::default().with_api_key(api_key);
+            Box::new(OpenAiEmbeddings::new(config, llm_http_client()?, model))
+        }
+        "ollama" => {
+            let host = ollama_host();
+            let model = std::env::var("OLLAMA_EMBEDDING_MODEL")
+                .unwrap_or_else(|_| "nomic-embed-text".to_string());
+            let config = OpenAIConfig::default()
+                .with_api_base(format!("{}/v1", host.trim_end_matches('/')))
+                .with_api_key("ollama");
+            Box::new(OpenAiEmbeddings::new(config, llm_http_client()?, model))
+        }
+        "mock" => {
+            return Err(AppError::Config(
+                "LLM_PROVIDER=mock has no embedding model, so --rag is unavailable".to_string(),
+            ))
+        }
+        other => return Err(unknown_provider(other)),
+    };
+    debug!("embedder for provider: {}", provider);
+    Ok(embedder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use langchain_rust::llm::openai::Config;
+
+    #[test]
+    fn azure_settings_carry_the_deployment_and_api_version() {
+        let config = azure_settings(
+            "https://example.openai.azure.com",
+            "2024-02-01",
+            "gpt-4o-deployment",
+        );
+        assert_eq!(config.api_base(), "https://example.openai.azure.com");
+        assert_eq!(
+            config.url("/chat/completions"),
+            "https://example.openai.azure.com/openai/deployments/gpt-4o-deployment/chat/completions"
+        );
+        assert_eq!(config.query(), vec![("api-version", "2024-02-01")]);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_ollama_server_is_reported() {
+        // Nothing listens on port 1, so the connection is refused straight away
+        let result = probe_ollama("http://127.0.0.1:1").await;
+
+        match result {
+            Err(AppError::Unreachable(message)) => {
+                assert!(message.starts_with("Ollama at http://127.0.0.1:1"))
+            }
+            other => panic!("expected Unreachable, got {:?}", other),
+        }
+    }
+
+    // Function to look values up in a fixed list instead of the environment
+    fn lookup(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |var| {
+            vars.iter()
+                .find(|(name, _)| *name == var)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn llm_requests_go_through_llm_proxy() {
+        // A plain-HTTP request through a proxy is sent to the proxy in full, so the mock
+        // server standing in for the proxy sees it
+        let mut proxy = mockito::Server::new_async().await;
+        let proxied = proxy
+            .mock("GET", "/v1/models")
+            .with_body("{}")
+            .create_async()
+            .await;
+        let proxy_url = proxy.url();
+        let client = llm_client_builder(|var| (var == "LLM_PROXY").then(|| proxy_url.clone()))
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let response = client
+            .get("http://llm.invalid/v1/models")
+            .send()
+            .await
+            .unwrap();
+
+        assert!(response.status().is_success());
+        proxied.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn no_proxy_hosts_bypass_llm_proxy() {
+        let mut server = mockito::Server::new_async().await;
+        let direct = server
+            .mock("GET", "/v1/models")
+            .with_body("{}")
+            .create_async()
+            .await;
+        let client = llm_client_builder(lookup(&[
+            // Nothing listens on port 1, so a proxied request would fail
+            ("LLM_PROXY", "http://127.0.0.1:1"),
+            ("NO_PROXY", "127.0.0.1"),
+        ]))
+        .unwrap()
+        .build()
+        .unwrap();
+
+        let url = format!("{}/v1/models", server.url());
+        assert!(client.get(&url).send().await.is_ok());
+        direct.assert_async().await;
+    }
+
+    #[test]
+    fn an_invalid_proxy_is_a_config_error() {
+        let invalid = |var: &'static str| {
+            let vars = [(var, "http://[::1")];
+            llm_client_builder(move |name| {
+                vars.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| value.to_string())
+            })
+            .err()
+        };
+        assert!(matches!(
+            invalid("LLM_PROXY"),
+            Some(AppError::Config(message)) if message.starts_with("invalid LLM_PROXY")
+        ));
+        assert!(matches!(
+            invalid("HTTPS_PROXY"),
+            Some(AppError::Config(message)) if message.starts_with("invalid HTTPS_PROXY")
+        ));
+        assert!(llm_client_builder(lookup(&[])).is_ok());
+    }
+
+    #[test]
+    fn missing_azure_settings_are_a_missing_env_error() {
+        let vars = ["OPEN_AI_SERVICE_URL", "OPEN_AI_SERVICE_KEY"];
+        let only_url = |var: &str| {
+            (var == "OPEN_AI_SERVICE_URL").then(|| "https://example.openai.azure.com".to_string())
+        };
+
+        let error = check_required(&vars, "azure", only_url).unwrap_err();
+
+        assert!(matches!(
+            &error,
+            AppError::MissingEnv { vars, provider }
+                if vars == "OPEN_AI_SERVICE_URL and OPEN_AI_SERVICE_KEY" && provider == "azure"
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Set OPEN_AI_SERVICE_URL and OPEN_AI_SERVICE_KEY (required when LLM_PROVIDER=azure); see README"
+        );
+        assert_eq!(error.exit_code(), 2);
+        assert!(check_required(&vars, "azure", |_| Some(String::new())).is_err());
+        assert!(check_required(&vars, "azure", |_| Some("set".to_string())).is_ok());
+    }
+}
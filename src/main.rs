@@ -1,193 +1,902 @@
+mod cli;
+mod command;
+mod input;
+
+use aichat_cli::audit::AuditLog;
+use aichat_cli::config::AppConfig;
+use aichat_cli::engine::default_system_prompt;
+use aichat_cli::interrupt::Interrupts;
+use aichat_cli::llm::LlmBackend;
+use aichat_cli::mock::MockLlm;
+use aichat_cli::output::{OutputFormat, StdoutOutput};
+use aichat_cli::persona::{self, PERSONAS};
+use aichat_cli::recording::{Recorder, Recording, Replay, ReplayOutcome};
+use aichat_cli::session::{LatencySummary, Session};
+use aichat_cli::templates::TemplateLibrary;
+use aichat_cli::terminal::{self, print_messages};
+use aichat_cli::watch::KnowledgeWatcher;
+use aichat_cli::{batch, data, diff, history, language, llm, rag, redact, AppError, ChatEngine, EngineConfig};
+use clap::Parser;
+use langchain_rust::schemas::MessageType;
+use cli::Args;
+use command::{parse_command, Command};
 use colored::Colorize;
-use indicatif::{ProgressBar, ProgressStyle};
-use langchain_rust::chain::{Chain, LLMChainBuilder};
-use langchain_rust::llm::{AzureConfig, OpenAI};
-use langchain_rust::prompt::HumanMessagePromptTemplate;
-use langchain_rust::schemas::Message;
-use langchain_rust::{
-    fmt_message, fmt_placeholder, fmt_template, message_formatter, prompt_args, template_fstring,
-};
 use log::{debug, error};
-use std::io::Write;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
-use std::{fs, io, thread};
-use serde_json::Value;
-
-// Function to load knowledge from a file (Refactor knowledge loading logic)
-fn load_knowledge(file_path: &str) -> String {
-    let file_content = fs::read_to_string(file_path).expect("Failed to read JSON file");
-    let parsed_json: Value = serde_json::from_str(&file_content).expect("Failed to parse JSON");
-
-    let mut knowledge = String::new();
+use std::io::{self, Write};
+use std::path::Path;
 
+// Function to check whether streaming output is enabled (--stream or STREAM_OUTPUT=1)
+fn is_streaming_enabled(args: &Args) -> bool {
+    args.stream || std::env::var("STREAM_OUTPUT").map(|v| v == "1").unwrap_or(false)
+}
 
+// Function to re-read the active knowledge sources from disk, fetching URL sources again;
+// set_knowledge keeps the previous knowledge if the new files don't parse
+async fn reload_knowledge(session: &mut Session, use_rag: bool) -> Result<(), AppError> {
+    let sources = session.engine.knowledge_sources.clone();
+    session.engine.fetch_knowledge_urls(&sources, true).await?;
+    session.engine.set_knowledge(&sources)?;
+    terminal::warn_if_knowledge_truncated(&session.engine, session.output.as_mut());
+    if use_rag {
+        session.engine.enable_rag(rag::top_k_from_env()).await?;
+    }
+    Ok(())
+}
 
-    knowledge
+// Function to check every file behind the sources (--check-knowledge), printing a table
+// and returning the exit code: 1 if any file failed to load
+async fn check_knowledge(sources: &[String]) -> i32 {
+    // A URL that can't be fetched shows up in the table as not fetched
+    let mut fetched = data::FetchedUrls::new();
+    if let Err(e) = data::fetch_knowledge_urls(&mut fetched, sources, false).await {
+        eprintln!("{}", e.to_string().red());
+    }
+    let checks = data::check_sources(sources, &fetched);
+    let all_ok = terminal::print_source_checks(&checks, &mut StdoutOutput);
+    if all_ok {
+        0
+    } else {
+        1
+    }
 }
 
-// Function to create the Azure OpenAI configuration (Refactor LLM setup)
-fn create_openai() -> OpenAI<AzureConfig> {
-    let open_ai_url = std::env::var("OPEN_AI_SERVICE_URL").expect("OPEN_AI_SERVICE_URL is not set");
-    let open_ai_key = std::env::var("OPEN_AI_SERVICE_KEY").expect("OPEN_AI_SERVICE_KEY is not set");
+fn main() {
+    if let Err(e) = start() {
+        eprintln!("{}", e.to_string().red());
+        std::process::exit(e.exit_code());
+    }
+}
 
-    debug!("open_ai_url: {}", open_ai_url);
+// Function to settle the environment (.env, config file) and then start the runtime; the
+// environment is only changed here, before the runtime has started any worker threads
+fn start() -> Result<(), AppError> {
+    pretty_env_logger::init();
+    dotenv::dotenv().ok();
 
-    let azure_config = AzureConfig::default()
-        .with_api_base(open_ai_url)
-        .with_api_key(open_ai_key)
-        .with_api_version("2023-03-15-preview")
-        .with_deployment_id("gpt-4");
+    let args = Args::parse();
+    AppConfig::load(Path::new(&args.config))?.apply_to_env();
+    llm::check_proxy()?;
 
-    OpenAI::new(azure_config)
+    tokio::runtime::Runtime::new()?.block_on(run(args))
 }
 
-// Function to handle user input (Refactor input handling logic)
-fn get_user_input(running: Arc<AtomicBool>) -> Option<String> {
-    if !running.load(Ordering::SeqCst) {
-        return None;
+async fn run(args: Args) -> Result<(), AppError> {
+    if args.check_knowledge {
+        std::process::exit(check_knowledge(&args.knowledge).await);
     }
 
-    print!(
-        "{}",
-        "Please enter some text and press Enter: ".bright_green()
-    );
-    io::stdout().flush().unwrap();
+    let interrupts = Interrupts::default();
+    let mut config = EngineConfig::from_env();
+    config.schema = args.schema;
+    #[cfg(feature = "server")]
+    if let Some(addr) = &args.serve {
+        return aichat_cli::server::serve(addr, config).await;
+    }
+    // --replay serves the recorded answers when running against the mock backend
+    let replay_recording = args.replay.as_deref().map(Recording::load).transpose()?;
+    let engine = match &replay_recording {
+        Some(recording) if llm::is_mock_provider() => ChatEngine::with_backend(
+            config,
+            LlmBackend::Custom(Box::new(MockLlm::with_responses(recording.mock_responses()))),
+        )?,
+        _ => ChatEngine::new(config).await?,
+    };
+    let mut session = Session::new(engine, interrupts.active.clone());
+    session.streaming = is_streaming_enabled(&args);
+    session.render_markdown = args.markdown;
+    session.output_format = args.format;
+    session.dry_run = args.dry_run;
+    session.verbose = args.verbose;
+    // Machine-readable output gets no ANSI colours; NO_COLOR disables them too
+    terminal::init_color(args.format == OutputFormat::Json);
+    terminal::init_spinner(args.no_spinner);
 
-    let mut input = String::new();
-    if io::stdin().read_line(&mut input).is_err() {
-        error!("Error reading input.");
-        return None;
+    // Append every answered prompt to AUDIT_LOG when set
+    if let Some(audit_log) = AuditLog::from_env() {
+        match audit_log {
+            Ok(audit_log) => session.engine.set_audit_log(audit_log),
+            Err(e) => {
+                session.output.write_warning(&format!("Failed to open AUDIT_LOG: {}", e).red().to_string());
+                std::process::exit(1);
+            }
+        }
     }
 
-    let input = input.trim();
-    if input.is_empty() || input == "exit" {
-        return None;
+    // Start with the chosen persona; its knowledge applies unless --knowledge is given
+    let mut knowledge = args.knowledge.clone();
+    if let Some(name) = args.persona.clone().or_else(|| std::env::var("PERSONA").ok()) {
+        let persona = persona::find_persona(&name).map_err(AppError::Config)?;
+        session.engine.set_system_prompt(persona.system_prompt)?;
+        session.engine.persona = Some(persona.name);
+        if knowledge.is_empty() {
+            knowledge.extend(persona.knowledge.map(str::to_string));
+        }
     }
 
-    Some(input.to_string())
-}
+    // Load knowledge from one or more files
+    if !knowledge.is_empty() {
+        let loaded = match session.engine.fetch_knowledge_urls(&knowledge, false).await {
+            Ok(()) => session.engine.set_knowledge(&knowledge),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = loaded {
+            let message = format!("Failed to load knowledge from {}: {}", knowledge.join(", "), e);
+            session.output.write_warning(&message.red().to_string());
+            std::process::exit(1);
+        }
+        terminal::warn_if_knowledge_truncated(&session.engine, session.output.as_mut());
+        if args.rag {
+            session.engine.enable_rag(rag::top_k_from_env()).await?;
+        }
+    }
 
-// Function to create a spinner (Refactor spinner creation)
-fn create_spinner(message: &str) -> ProgressBar {
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_message(format!("{} {}", "💡".blue(), message));
-    spinner.set_style(
-        ProgressStyle::with_template("{spinner:.green} {msg}")
-            .unwrap()
-            .tick_strings(&["|", "/", "-", "\\", "|", "/", "-", "\\"]),
-    );
-    spinner.enable_steady_tick(Duration::from_millis(120));
-    spinner
-}
+    // One-shot mode: answer a single prompt (from --prompt, --prompt-file or a stdin
+    // pipe) without the REPL, spinner or typewriter
+    let one_shot = match args.one_shot_prompt() {
+        Ok(prompt) => prompt,
+        Err(e) => {
+            session.output.write_warning(&format!("Failed to read prompt: {}", e).red().to_string());
+            std::process::exit(1);
+        }
+    };
+    if let Some(prompt) = &one_shot {
+        if session.dry_run {
+            print_messages(&session.engine.build_messages(prompt), session.output.as_mut());
+            return Ok(());
+        }
+        match session.engine.complete(prompt).await {
+            Ok(result) => {
+                session.warn_if_off_schema(&result);
+                if args.format == OutputFormat::Json {
+                    session.print_json(prompt, &result)?;
+                } else {
+                    session.output.write_line(&result);
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                session.output.write_warning(&e.to_string().red().to_string());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let handler_interrupts = interrupts.clone();
+
+    // Set up the Ctrl-C handler: the first press aborts the current answer, a second
+    // press within two seconds exits
+    ctrlc::set_handler(move || {
+        let exit = handler_interrupts.press();
+        // Reset the colour and end the partial line so the shell isn't left yellow
+        if terminal::color_enabled() {
+            print!("\x1b[0m");
+        }
+        println!();
+        if exit {
+            debug!("Ctrl-C pressed twice, exiting...");
+        } else {
+            debug!("Ctrl-C detected, aborting current operation...");
+            println!("{}", "Interrupted. Press Ctrl-C again within 2 seconds to exit.".yellow());
+        }
+        io::stdout().flush().unwrap();
+    })
+    .map_err(|e| AppError::Terminal(format!("failed to set the Ctrl-C handler: {}", e)))?;
 
-// Function to handle the LLM chain execution and processing (Refactor LLM logic)
-async fn process_with_llm(
-    input: &str,
-    knowledge: &str,
-    history_list: &mut Vec<Message>,
-    open_ai: &OpenAI<AzureConfig>,
-    running: Arc<AtomicBool>,
-    fn_callback: Box<dyn Fn() + 'static>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let prompt = message_formatter![
-        fmt_message!(Message::new_system_message(
-            "You are a world-class technical documentation writer. Use the following knowledge to answer the user's query."
-        )),
-        fmt_message!(Message::new_system_message(format!("Knowledge:\n{}", knowledge))),
-        fmt_placeholder!("history"),
-        fmt_template!(HumanMessagePromptTemplate::new(template_fstring!("{input}", "input")))
-    ];
-
-    let chain = LLMChainBuilder::new()
-        .prompt(prompt)
-        .llm(open_ai.clone())
-        .build()?;
-
-    let res = chain
-        .invoke(prompt_args! {
-            "input" => input,
-            "knowledge" => knowledge,
-            "history" => history_list
-        })
+    // Batch mode: answer every line of a file, printing one JSON line per prompt
+    if let Some(path) = &args.batch {
+        let content = std::fs::read_to_string(path)?;
+        interrupts.begin_operation();
+        let records = batch::run_batch(
+            &session.engine,
+            &content,
+            batch::concurrency_from_env(),
+            &interrupts.active,
+        )
         .await;
+        for record in &records {
+            session.output.write_line(&serde_json::to_string(record)?);
+        }
+        return Ok(());
+    }
 
-    fn_callback();
+    if session.engine.knowledge.is_empty() {
+        session.output.write_line(
+            &"No knowledge loaded; answers use the model alone. Start with --knowledge <file> to add some."
+                .yellow()
+                .to_string(),
+        );
+    }
 
-    if let Ok(result) = res {
-        history_list.push(Message::new_ai_message(&result));
-        typewriter(&result, 100, running);
-        Ok(result)
-    } else {
-        Err(Box::new(res.err().unwrap()))
+    // Load every file in KNOWLEDGE_DIR up front so `.knowledge use` switches instantly
+    if std::env::var("PRELOAD_KNOWLEDGE").is_ok_and(|value| value == "1") {
+        session.preloaded_knowledge = data::preload_knowledge_dir().await;
     }
-}
 
-// Function to display typing effect (Already refactored)
-fn typewriter(text: &str, delay_ms: u64, running: Arc<AtomicBool>) {
-    for c in text.chars() {
-        if !running.load(Ordering::SeqCst) {
-            break;
+    // Named prompt templates for .template; a broken file leaves the library empty
+    let templates = TemplateLibrary::from_env().unwrap_or_else(|e| {
+        session.output.write_line(&e.to_string().red().to_string());
+        TemplateLibrary::default()
+    });
+
+    let mut editor = input::create_editor().map_err(|e| AppError::Terminal(e.to_string()))?;
+
+    // Watch the knowledge sources so edits are picked up before the next prompt
+    let watcher = if args.watch_knowledge {
+        match KnowledgeWatcher::new(&session.engine.knowledge_sources) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                session.output.write_line(&format!("Failed to watch knowledge: {}", e).red().to_string());
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Restore the previous conversation if HISTORY_FILE points at an existing file
+    let history_file = std::env::var("HISTORY_FILE").ok();
+    if let Some(path) = &history_file {
+        if Path::new(path).exists() {
+            session.engine.history_list = history::load_history(path);
         }
-        print!("{}", c.to_string().yellow());
-        io::stdout().flush().unwrap();
-        thread::sleep(Duration::from_millis(delay_ms));
     }
-    println!();
-}
+    // --record saves each line and answer as the session goes; --replay types the lines
+    // of a recording instead of reading the terminal
+    let mut recorder = args.record.as_deref().map(Recorder::create).transpose()?;
+    let mut replay = replay_recording.map(Replay::new);
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    pretty_env_logger::init();
-    dotenv::dotenv().ok();
+    // Main loop for user input and processing
+    let mut restore_sampling = None;
+    while interrupts.is_running() {
+        // Undo a one-off `.retry temp` override from the previous turn
+        if let Some(sampling) = restore_sampling.take() {
+            if let Err(e) = session.engine.set_sampling(sampling).await {
+                session.output.write_warning(&e.to_string().red().to_string());
+            }
+        }
 
-    // Load knowledge from a file
-    let knowledge = "";//load_knowledge("dataset/app_info.json");
-    let open_ai = create_openai();
+        let line = match replay.as_mut() {
+            Some(replay) => {
+                let Some(line) = replay.next_input() else {
+                    break;
+                };
+                session.output.write_line(&format!("{}{}", input::prompt_text().bright_green(), line));
+                line
+            }
+            None => {
+                let Some(line) = input::get_user_input(&mut editor, &interrupts, session.output.as_mut()) else {
+                    break;
+                };
+                line
+            }
+        };
+        if let Some(recorder) = recorder.as_mut() {
+            if let Err(e) = recorder.begin(&line) {
+                error!("Failed to record the session: {}", e);
+            }
+        }
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+        // Answer to compare the next one against (.diff), and whether to keep the new turn
+        let mut pending_diff = None;
+        let input = match parse_command(&line) {
+            Command::Exit => break,
+            Command::Clear => {
+                session.engine.clear_history();
+                continue;
+            }
+            Command::Save(path) => {
+                if path.is_empty() {
+                    session.output.write_line(&"Usage: .save <file>".red().to_string());
+                    continue;
+                }
+                match history::save_history(path, &session.engine.history_list) {
+                    Ok(()) => session.output.write_line(&format!("History saved to {}", path).cyan().to_string()),
+                    Err(e) => session.output.write_line(&format!("Failed to save history: {}", e).red().to_string()),
+                }
+                continue;
+            }
+            Command::SaveConfig(path) => {
+                // No default path, so the --config file isn't overwritten by accident
+                if path.is_empty() {
+                    session.output.write_line(&"Usage: .save-config <file>".red().to_string());
+                    continue;
+                }
+                match session.current_config().save(Path::new(path)) {
+                    Ok(()) => session.output.write_line(&format!("Settings saved to {}", path).cyan().to_string()),
+                    Err(e) => session.output.write_line(&format!("Failed to save settings: {}", e).red().to_string()),
+                }
+                continue;
+            }
+            Command::Load(path) => {
+                if path.is_empty() {
+                    session.output.write_line(&"Usage: .load <file>".red().to_string());
+                    continue;
+                }
+                session.engine.history_list = history::load_history(path);
+                let message = format!("Loaded {} messages from {}", session.engine.history_list.len(), path);
+                session.output.write_line(&message.cyan().to_string());
+                continue;
+            }
+            Command::Export(path) => {
+                if path.is_empty() {
+                    session.output.write_line(&"Usage: .export <file>".red().to_string());
+                    continue;
+                }
+                match history::export_transcript(path, &session.engine.history_list) {
+                    Ok(()) => session.output.write_line(&format!("Transcript exported to {}", path).cyan().to_string()),
+                    Err(e) => session.output.write_line(&format!("Failed to export transcript: {}", e).red().to_string()),
+                }
+                continue;
+            }
+            Command::System(prompt) => {
+                if prompt.is_empty() {
+                    session.output.write_line(&format!("System prompt: {}", session.engine.system_prompt).cyan().to_string());
+                } else if prompt == "reset" {
+                    match session.engine.set_system_prompt(&default_system_prompt()) {
+                        Ok(()) => session.output.write_line(&"System prompt reset to the default".cyan().to_string()),
+                        Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                    }
+                } else {
+                    match session.engine.set_system_prompt(prompt) {
+                        Ok(()) => session.output.write_line(&"System prompt updated".cyan().to_string()),
+                        Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                    }
+                }
+                continue;
+            }
+            Command::Lang(code) => {
+                let language = match code {
+                    "" => {
+                        let current = session.engine.language.as_deref().unwrap_or("auto");
+                        session.output.write_line(&format!("Response language: {}", current).cyan().to_string());
+                        continue;
+                    }
+                    "auto" => None,
+                    _ => match language::parse_language(code) {
+                        Ok(code) => Some(code.to_string()),
+                        Err(e) => {
+                            session.output.write_line(&e.red().to_string());
+                            continue;
+                        }
+                    },
+                };
+                match session.engine.set_language(language) {
+                    Ok(()) => session.output.write_line(&"Response language updated".cyan().to_string()),
+                    Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                }
+                continue;
+            }
+            Command::Templates => {
+                let mut listed = false;
+                for (name, template) in templates.iter() {
+                    session.output.write_line(&format!("{}: {}", name, template).cyan().to_string());
+                    listed = true;
+                }
+                if !listed {
+                    session.output.write_line(&"No templates defined (see TEMPLATES_FILE)".yellow().to_string());
+                }
+                continue;
+            }
+            Command::Template(arg) => {
+                if arg.is_empty() {
+                    session.output.write_line(&"Usage: .template <name> <args...>".red().to_string());
+                    continue;
+                }
+                let (name, args) = arg.split_once(char::is_whitespace).unwrap_or((arg, ""));
+                match templates.expand(name, args) {
+                    Ok(prompt) => prompt,
+                    Err(e) => {
+                        session.output.write_line(&e.red().to_string());
+                        continue;
+                    }
+                }
+            }
+            Command::Persona(name) => {
+                if name.is_empty() {
+                    for persona in PERSONAS {
+                        let knowledge = persona.knowledge.map(|k| format!(" [{}]", k)).unwrap_or_default();
+                        session.output.write_line(&format!("{}: {}{}", persona.name, persona.description, knowledge).cyan().to_string());
+                    }
+                    continue;
+                }
+                let persona = match persona::find_persona(name) {
+                    Ok(persona) => persona,
+                    Err(e) => {
+                        session.output.write_line(&e.red().to_string());
+                        continue;
+                    }
+                };
+                let result = match session.engine.set_persona(persona) {
+                    Ok(()) if args.rag && persona.knowledge.is_some() => {
+                        session.engine.enable_rag(rag::top_k_from_env()).await
+                    }
+                    result => result,
+                };
+                match result {
+                    Ok(()) => session.output.write_line(&format!("Persona set to {}", persona.name).cyan().to_string()),
+                    Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                }
+                continue;
+            }
+            Command::Models => {
+                session.output.write_line(&format!("Active model: {}", session.engine.model).cyan().to_string());
+                continue;
+            }
+            Command::Model(model) => {
+                if model.is_empty() {
+                    session.output.write_line(&"Usage: .model <deployment_id>".red().to_string());
+                    continue;
+                }
+                match session.engine.switch_model(model).await {
+                    Ok(()) => session.output.write_line(&format!("Switched model to {}", session.engine.model).cyan().to_string()),
+                    Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                }
+                continue;
+            }
+            Command::Temp(value) => {
+                match llm::parse_temperature(value) {
+                    Ok(temperature) => {
+                        let mut sampling = session.engine.sampling;
+                        sampling.temperature = Some(temperature);
+                        match session.engine.set_sampling(sampling).await {
+                            Ok(()) => session.output.write_line(&format!("Temperature set to {}", temperature).cyan().to_string()),
+                            Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                        }
+                    }
+                    Err(e) => session.output.write_line(&format!("Usage: .temp <0.0-2.0> ({})", e).red().to_string()),
+                }
+                continue;
+            }
+            Command::MaxTokens(value) => {
+                match llm::parse_max_tokens(value) {
+                    Ok(max_tokens) => {
+                        let mut sampling = session.engine.sampling;
+                        sampling.max_tokens = Some(max_tokens);
+                        match session.engine.set_sampling(sampling).await {
+                            Ok(()) => session.output.write_line(&format!("Max tokens set to {}", max_tokens).cyan().to_string()),
+                            Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                        }
+                    }
+                    Err(e) => session.output.write_line(&format!("Usage: .maxtokens <n> ({})", e).red().to_string()),
+                }
+                continue;
+            }
+            Command::Speed(value) => {
+                match value.parse::<u64>() {
+                    Ok(delay_ms) => {
+                        session.typewriter.delay_ms = delay_ms;
+                        session.output.write_line(&format!("Typewriter delay set to {}ms", delay_ms).cyan().to_string());
+                    }
+                    Err(_) => session.output.write_line(&"Usage: .speed <ms>".red().to_string()),
+                }
+                continue;
+            }
+            Command::Tokens => {
+                match &session.engine.last_usage {
+                    Some(usage) => session.output.write_line(&format!("Last turn: {}", usage).cyan().to_string()),
+                    None => session.output.write_line(&"No answers yet".cyan().to_string()),
+                }
+                session.output.write_line(&format!("Session total: {}", session.engine.total_usage).cyan().to_string());
+                continue;
+            }
+            Command::Knowledge(arg) => {
+                let sources = match arg.split_once(char::is_whitespace) {
+                    // No argument: tick the sources to combine, the active ones pre-checked
+                    None if arg.is_empty() => {
+                        let available = data::available_knowledge_sources();
+                        if available.is_empty() && session.engine.knowledge_sources.is_empty() {
+                            session.output.write_line(&format!("No knowledge files in {}", data::knowledge_dir().display()).yellow().to_string());
+                            continue;
+                        }
+                        match terminal::pick_knowledge_sources(&available, &session.engine.knowledge_sources) {
+                            Ok(Some(sources)) => sources,
+                            Ok(None) => continue,
+                            Err(e) => {
+                                session.output.write_line(&e.to_string().red().to_string());
+                                continue;
+                            }
+                        }
+                    }
+                    None if arg == "show" => {
+                        if session.engine.knowledge.is_empty() {
+                            session.output.write_line(&"No knowledge loaded".yellow().to_string());
+                        } else {
+                            session.output.page(&session.engine.knowledge);
+                        }
+                        continue;
+                    }
+                    Some(("save", path)) => {
+                        match data::save_knowledge(path.trim(), &session.engine.knowledge) {
+                            Ok(bytes) => session.output.write_line(&format!("Wrote {} bytes to {}", bytes, path.trim()).cyan().to_string()),
+                            Err(e) => session.output.write_line(&format!("Failed to save knowledge: {}", e).red().to_string()),
+                        }
+                        continue;
+                    }
+                    Some(("use", source)) => vec![source.trim().to_string()],
+                    _ => {
+                        session.output.write_line(&"Usage: .knowledge | .knowledge show | .knowledge save <file> | .knowledge use <source>".red().to_string());
+                        continue;
+                    }
+                };
+                let result = match session.switch_knowledge(&sources).await {
+                    Ok(()) if args.rag && !sources.is_empty() => session.engine.enable_rag(rag::top_k_from_env()).await,
+                    result => result,
+                };
+                match result {
+                    Ok(()) if sources.is_empty() => session.output.write_line(&"Knowledge cleared".cyan().to_string()),
+                    Ok(()) => {
+                        terminal::warn_if_knowledge_truncated(&session.engine, session.output.as_mut());
+                        session.output.write_line(&format!("Knowledge switched to {}", session.engine.knowledge_sources.join(", ")).cyan().to_string());
+                    }
+                    Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                }
+                continue;
+            }
+            Command::AddKnowledge(note) => {
+                match session.engine.add_note(note) {
+                    Ok(()) => session.output.write_line(&"Added to the knowledge for this session".cyan().to_string()),
+                    Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                }
+                continue;
+            }
+            Command::ClearKnowledge => {
+                if session.engine.notes.is_empty() {
+                    session.output.write_line(&"No ad-hoc notes to clear".yellow().to_string());
+                    continue;
+                }
+                match session.engine.clear_notes() {
+                    Ok(()) => session.output.write_line(&"Ad-hoc notes cleared".cyan().to_string()),
+                    Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                }
+                continue;
+            }
+            Command::Check => {
+                if session.engine.knowledge_sources.is_empty() {
+                    session.output.write_line(&"No knowledge loaded".yellow().to_string());
+                } else {
+                    terminal::print_source_checks(&data::check_sources(&session.engine.knowledge_sources, &session.engine.fetched_urls), session.output.as_mut());
+                }
+                continue;
+            }
+            Command::Sources => {
+                terminal::print_knowledge_sources(&session.engine, session.output.as_mut());
+                continue;
+            }
+            Command::Browse => {
+                if session.engine.knowledge_sources.is_empty() {
+                    session.output.write_line(&"No knowledge loaded".yellow().to_string());
+                    continue;
+                }
+                let browsed = data::load_knowledge_entries(&session.engine.knowledge_sources, &session.engine.fetched_urls)
+                    .map_err(AppError::from)
+                    .and_then(|entries| terminal::browse_knowledge(&entries, session.output.as_mut()));
+                if let Err(e) = browsed {
+                    session.output.write_line(&e.to_string().red().to_string());
+                }
+                continue;
+            }
+            Command::Time(arg) => {
+                let parsed = arg
+                    .split_once(char::is_whitespace)
+                    .and_then(|(runs, prompt)| Some((runs.parse::<usize>().ok().filter(|runs| *runs > 0)?, prompt.trim())));
+                let Some((runs, prompt)) = parsed else {
+                    session.output.write_line(&"Usage: .time <n> <prompt>".red().to_string());
+                    continue;
+                };
+                interrupts.begin_operation();
+                match session.benchmark(runs, prompt).await {
+                    Ok(samples) => {
+                        if samples.len() < runs {
+                            session.output.write_line(&format!("Stopped after {} of {} runs", samples.len(), runs).yellow().to_string());
+                        }
+                        match LatencySummary::from_samples(&samples) {
+                            Some(summary) => session.output.write_line(&format!("{} runs: {}", samples.len(), summary).cyan().to_string()),
+                            None => session.output.write_line(&"No runs completed".yellow().to_string()),
+                        }
+                    }
+                    Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                }
+                continue;
+            }
+            Command::Stats => {
+                session.output.write_line(&session.stats().to_string().cyan().to_string());
+                continue;
+            }
+            Command::DryRun => {
+                session.dry_run = !session.dry_run;
+                let state = if session.dry_run { "on" } else { "off" };
+                session.output.write_line(&format!("Dry run {}", state).cyan().to_string());
+                continue;
+            }
+            Command::Scrollback => {
+                if session.scrollback.is_empty() {
+                    session.output.write_line(&"Nothing to scroll back to yet".yellow().to_string());
+                } else {
+                    session.output.page(&session.scrollback.render());
+                }
+                continue;
+            }
+            Command::History(arg) => {
+                let preview_chars = match arg {
+                    "" => Some(history::preview_chars_from_env()),
+                    "full" => None,
+                    _ => {
+                        session.output.write_line(&"Usage: .history [full]".red().to_string());
+                        continue;
+                    }
+                };
+                if session.engine.history_list.is_empty() {
+                    session.output.write_line(&"History is empty".yellow().to_string());
+                } else {
+                    session.output.write_chunk(&history::render_history(&session.engine.history_list, preview_chars));
+                }
+                continue;
+            }
+            Command::Copy => {
+                match history::last_answer(&session.engine.history_list) {
+                    Some(answer) => match terminal::copy_to_clipboard(answer) {
+                        Ok(()) => session.output.write_line(&"Copied the last answer to the clipboard".cyan().to_string()),
+                        Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                    },
+                    None => session.output.write_line(&"No answer to copy yet".yellow().to_string()),
+                }
+                continue;
+            }
+            Command::Reload => {
+                if session.engine.knowledge_sources.is_empty() {
+                    session.output.write_line(&"No knowledge loaded".yellow().to_string());
+                    continue;
+                }
+                match reload_knowledge(&mut session, args.rag).await {
+                    Ok(()) => {
+                        let message = format!("Knowledge reloaded ({} chars)", session.engine.knowledge.chars().count());
+                        session.output.write_line(&message.cyan().to_string());
+                    }
+                    Err(e) => session.output.write_line(&e.to_string().red().to_string()),
+                }
+                continue;
+            }
+            Command::Undo => {
+                let removed = history::remove_last_turn(&mut session.engine.history_list);
+                if removed.is_empty() {
+                    session.output.write_line(&"Nothing to undo".yellow().to_string());
+                }
+                for message in &removed {
+                    let label = if matches!(message.message_type, MessageType::HumanMessage) {
+                        "Removed prompt"
+                    } else {
+                        "Removed answer"
+                    };
+                    session.output.write_line(&format!("{}: {}", label, message.content.trim_end()).cyan().to_string());
+                }
+                continue;
+            }
+            Command::Retry(arg) => {
+                let Some(last_input) = session.last_input.clone() else {
+                    session.output.write_line(&"Nothing to retry yet".red().to_string());
+                    continue;
+                };
+                // `.retry temp <v>` uses a different temperature for this answer only
+                if !arg.is_empty() {
+                    let temperature = match arg.strip_prefix("temp") {
+                        Some(value) => llm::parse_temperature(value),
+                        None => Err(format!("unknown option '{}'", arg)),
+                    };
+                    let temperature = match temperature {
+                        Ok(temperature) => temperature,
+                        Err(e) => {
+                            session.output.write_line(&format!("Usage: .retry [temp <0.0-2.0>] ({})", e).red().to_string());
+                            continue;
+                        }
+                    };
+                    let mut sampling = session.engine.sampling;
+                    sampling.temperature = Some(temperature);
+                    restore_sampling = Some(session.engine.sampling);
+                    if let Err(e) = session.engine.set_sampling(sampling).await {
+                        session.output.write_line(&e.to_string().red().to_string());
+                        continue;
+                    }
+                }
+                last_input
+            }
+            Command::Diff(arg) => {
+                let store = match arg {
+                    "" => true,
+                    "--no-store" => false,
+                    _ => {
+                        session.output.write_line(&"Usage: .diff [--no-store]".red().to_string());
+                        continue;
+                    }
+                };
+                let (Some(last_input), Some(last_answer)) =
+                    (session.last_input.clone(), session.last_answer.clone())
+                else {
+                    session.output.write_line(&"No answer to compare yet".yellow().to_string());
+                    continue;
+                };
+                pending_diff = Some((last_answer, store));
+                last_input
+            }
+            Command::Unknown(line, suggestion) => {
+                let name = line.split_whitespace().next().unwrap_or(line);
+                let message = match suggestion {
+                    Some(command) if command.eq_ignore_ascii_case(name) => {
+                        format!("Invalid arguments for {}", command)
+                    }
+                    Some(command) => format!("Unknown command {}. Did you mean {}?", name, command),
+                    None => format!("Unknown command {}", name),
+                };
+                session.output.write_line(&message.red().to_string());
+                continue;
+            }
+            Command::Prompt(prompt) => prompt.to_string(),
+        };
+        let input = input.as_str();
 
-    // Set up the Ctrl-C handler
-    ctrlc::set_handler(move || {
-        debug!("\nCtrl-C detected, exiting...");
-        r.store(false, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
+        if watcher.as_ref().is_some_and(|watcher| watcher.take_change()) {
+            match reload_knowledge(&mut session, args.rag).await {
+                Ok(()) => session.output.write_line(&"Knowledge reloaded".cyan().to_string()),
+                Err(e) => session.output.write_line(&format!("Knowledge changed but could not be reloaded: {}", e).red().to_string()),
+            }
+        }
 
-    let mut history_list = Vec::new();
-    // Main loop for user input and processing
-    while running.load(Ordering::SeqCst) {
-        if let Some(input) = get_user_input(running.clone()) {
-            if input == "clear" {
-                history_list.clear();
+        // Show what would be sent without calling the LLM or touching history
+        if session.dry_run {
+            if let Err(e) = session.engine.retrieve_knowledge(input).await {
+                session.output.write_line(&e.to_string().red().to_string());
                 continue;
             }
+            print_messages(&session.engine.build_messages(input), session.output.as_mut());
+            continue;
+        }
 
-            history_list.push(Message::new_human_message(&input));
+        // Ask before sending input that looks like it carries credentials or card numbers
+        if redact::warn_enabled() {
+            let secrets = redact::detect_secrets(input);
+            if !secrets.is_empty()
+                && !input::confirm(
+                    &format!(
+                        "This looks like it contains sensitive data ({}) — send anyway?",
+                        secrets.join(", ")
+                    ),
+                    &mut editor,
+                )
+            {
+                session.output.write_line(&"Prompt not sent.".yellow().to_string());
+                continue;
+            }
+        }
 
-            let spinner = create_spinner("Asking...");
-            let res = process_with_llm(
-                &input,
-                &knowledge,
-                &mut history_list,
-                &open_ai,
-                running.clone(),
-                Box::new(move || {
-                    spinner.finish_and_clear();
-                }),
+        session.engine.trim_history(input);
+        let estimate = session.engine.estimate_prompt_tokens(input);
+        if estimate > session.engine.max_prompt_tokens
+            && !input::confirm(
+                &format!(
+                    "This prompt is about {} tokens, above MAX_PROMPT_TOKENS ({}). Send anyway?",
+                    estimate, session.engine.max_prompt_tokens
+                ),
+                &mut editor,
             )
-            .await;
-            //spinner.finish_and_clear();
+        {
+            session.output.write_line(&"Prompt not sent.".yellow().to_string());
+            continue;
+        }
 
-            if let Err(e) = res {
-                error!("Error invoking LLMChain: {:?}", e);
+        interrupts.begin_operation();
+        match session.ask(input).await {
+            Ok(answer) => {
+                if let Some(recorder) = recorder.as_mut() {
+                    let prompt = session.last_input.as_deref().unwrap_or(input);
+                    if let Err(e) = recorder.finish(prompt, &answer, session.engine.last_latency) {
+                        error!("Failed to record the session: {}", e);
+                    }
+                }
+                if let Some(replay) = replay.as_mut() {
+                    match replay.compare(&answer) {
+                        ReplayOutcome::Matches => {
+                            session.output.write_line(&"(matches the recording)".dimmed().to_string())
+                        }
+                        ReplayOutcome::Differs(recorded) => {
+                            session.output.write_line(&"Answer differs from the recording:".yellow().to_string());
+                            session.output.write_chunk(&diff::render_diff(&recorded, &answer));
+                        }
+                        ReplayOutcome::NotRecorded => {}
+                    }
+                }
+                if let Some((previous, store)) = pending_diff {
+                    session.output.write_chunk(&diff::render_diff(&previous, &answer));
+                    if !store {
+                        history::remove_last_turn(&mut session.engine.history_list);
+                        session.last_answer = Some(previous);
+                    }
+                }
             }
-        } else {
-            break;
+            // The Ctrl-C handler has already told the user
+            Err(AppError::Interrupted) => debug!("Answer interrupted"),
+            Err(e) => session.output.write_warning(&e.to_string().red().to_string()),
         }
     }
 
+    // Flush the conversation so the next session can pick it up
+    if let Some(path) = &history_file {
+        if let Err(e) = history::save_history(path, &session.engine.history_list) {
+            error!("Failed to save history: {}", e);
+        }
+    }
+    input::save_input_history(&mut editor);
+    if let Some(replay) = &replay {
+        session.output.write_line(&replay.to_string().cyan().to_string());
+    }
+    session.output.write_line(&session.stats().to_string().cyan().to_string());
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn a_broken_file_makes_the_check_fail() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let good = dir.path().join("good.json");
+        std::fs::write(&good, r#"{"topics": ["orders"]}"#).unwrap();
+        let broken = dir.path().join("broken.json");
+        std::fs::write(&broken, r#"{"topics": ["#).unwrap();
+        let good = good.to_string_lossy().into_owned();
+        let broken = broken.to_string_lossy().into_owned();
+
+        assert_eq!(check_knowledge(std::slice::from_ref(&good)).await, 0);
+        assert_eq!(check_knowledge(&[good.clone(), broken.clone()]).await, 1);
+
+        let checks = data::check_sources(&[good, broken], &data::FetchedUrls::new());
+        assert!(matches!(checks[0].result, Ok(1)));
+        assert!(checks[1].result.is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_picks_up_an_edited_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("pii.json");
+        std::fs::write(&path, r#"["Email address"]"#).unwrap();
+        let engine = ChatEngine::with_backend(
+            EngineConfig::from_env(),
+            LlmBackend::Custom(Box::new(MockLlm::new())),
+        )
+        .unwrap();
+        let mut session = Session::new(engine, Arc::new(AtomicBool::new(true)));
+        session
+            .engine
+            .set_knowledge(&[path.to_string_lossy().into_owned()])
+            .unwrap();
+        assert!(session.engine.knowledge.contains("Email address"));
+
+        std::fs::write(&path, r#"["Phone number"]"#).unwrap();
+        reload_knowledge(&mut session, false).await.unwrap();
+
+        assert!(session.engine.knowledge.contains("Phone number"));
+        assert!(!session.engine.knowledge.contains("Email address"));
+    }
+}
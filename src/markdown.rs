@@ -0,0 +1,47 @@
+use crate::terminal;
+use termimad::MadSkin;
+
+// Function to check whether a response contains Markdown worth rendering
+pub fn has_markdown(text: &str) -> bool {
+    let has_block_syntax = text.lines().any(|line| {
+        let line = line.trim_start();
+        line.starts_with('#')
+            || line.starts_with("```")
+            || line.starts_with("- ")
+            || line.starts_with("* ")
+            || line.starts_with("> ")
+    });
+    has_block_syntax || text.contains("**") || text.contains('`')
+}
+
+// Function to keep each code fence's language visible above the rendered block
+fn label_code_fences(text: &str) -> String {
+    let mut labelled = String::new();
+    let mut in_fence = false;
+    for line in text.lines() {
+        if let Some(language) = line.trim_start().strip_prefix("```") {
+            if !in_fence && !language.trim().is_empty() {
+                labelled.push_str(&format!("*{}*\n", language.trim()));
+            }
+            in_fence = !in_fence;
+        }
+        labelled.push_str(line);
+        labelled.push('\n');
+    }
+    labelled
+}
+
+// Function to render a completed response as Markdown for the terminal
+pub fn render_markdown(text: &str) -> String {
+    let skin = if terminal::color_enabled() {
+        MadSkin::default()
+    } else {
+        MadSkin::no_style()
+    };
+    skin.text(&label_code_fences(text), None).to_string()
+}
+
+// Function to print a completed response as Markdown in the terminal
+pub fn print_markdown(text: &str) {
+    print!("{}", render_markdown(text));
+}
@@ -0,0 +1,117 @@
+use crate::error::AppError;
+use async_trait::async_trait;
+use futures::stream::{self, Stream};
+use langchain_rust::language_models::llm::LLM;
+use langchain_rust::language_models::{GenerateResult, LLMError};
+use langchain_rust::schemas::{Message, MessageType, StreamData};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::pin::Pin;
+
+// Offline backend for LLM_PROVIDER=mock: answers from a fixture of canned responses
+// keyed by prompt, or echoes the prompt back, so the CLI runs without credentials
+#[derive(Debug, Clone, Default)]
+pub struct MockLlm {
+    responses: HashMap<String, String>,
+}
+
+impl MockLlm {
+    // Function to create a mock that only echoes
+    pub fn new() -> Self {
+        MockLlm::default()
+    }
+
+    // Function to create a mock with canned responses (prompt -> answer)
+    pub fn with_responses(responses: HashMap<String, String>) -> Self {
+        MockLlm { responses }
+    }
+
+    // Function to read canned responses from the JSON object in MOCK_RESPONSES, if set
+    pub fn from_env() -> Result<Self, AppError> {
+        let Ok(path) = std::env::var("MOCK_RESPONSES") else {
+            return Ok(MockLlm::new());
+        };
+        let content = fs::read_to_string(&path)
+            .map_err(|e| AppError::Config(format!("failed to read MOCK_RESPONSES: {}", e)))?;
+        let responses: HashMap<String, String> = serde_json::from_str(&content).map_err(|e| {
+            AppError::Config(format!(
+                "MOCK_RESPONSES must be a JSON object of prompt to answer: {}",
+                e
+            ))
+        })?;
+        Ok(MockLlm::with_responses(responses))
+    }
+
+    // Function to pick the answer for the latest human message
+    fn answer(&self, messages: &[Message]) -> String {
+        let input = messages
+            .iter()
+            .rev()
+            .find(|message| matches!(message.message_type, MessageType::HumanMessage))
+            .map(|message| message.content.trim())
+            .unwrap_or_default();
+        self.responses
+            .get(input)
+            .cloned()
+            .unwrap_or_else(|| format!("Mock answer: {}", input))
+    }
+}
+
+#[async_trait]
+impl LLM for MockLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        Ok(GenerateResult {
+            tokens: None,
+            generation: self.answer(messages),
+        })
+    }
+
+    async fn stream(
+        &self,
+        messages: &[Message],
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError> {
+        // One chunk per word, like a real backend streaming tokens
+        let chunks: Vec<Result<StreamData, LLMError>> = self
+            .answer(messages)
+            .split_inclusive(' ')
+            .map(|word| Ok(StreamData::new(Value::String(word.to_string()), None, word)))
+            .collect();
+        Ok(Box::pin(stream::iter(chunks)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{ChatEngine, EngineConfig};
+    use crate::llm::LlmBackend;
+
+    #[tokio::test]
+    async fn two_asks_are_answered_through_the_mock() {
+        let responses = HashMap::from([("hello".to_string(), "Hi there".to_string())]);
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let mut engine = ChatEngine::with_backend(
+            config,
+            LlmBackend::Custom(Box::new(MockLlm::with_responses(responses))),
+        )
+        .unwrap();
+
+        assert_eq!(engine.ask("hello").await.unwrap(), "Hi there");
+        assert_eq!(
+            engine.ask("what now?").await.unwrap(),
+            "Mock answer: what now?"
+        );
+
+        let contents: Vec<&str> = engine
+            .history_list
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            ["hello", "Hi there", "what now?", "Mock answer: what now?"]
+        );
+    }
+}
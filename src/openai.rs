@@ -0,0 +1,275 @@
+use async_openai::config::Config;
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessageArgs,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionStreamOptions, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+    CreateEmbeddingRequestArgs, EmbeddingInput,
+};
+use async_openai::Client;
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use langchain_rust::embedding::{Embedder, EmbedderError};
+use langchain_rust::language_models::llm::LLM;
+use langchain_rust::language_models::options::CallOptions;
+use langchain_rust::language_models::{GenerateResult, LLMError, TokenUsage};
+use langchain_rust::schemas::{Message, MessageType, StreamData};
+use serde_json::Value;
+use std::pin::Pin;
+
+// OpenAI-compatible chat model (Azure, api.openai.com, Ollama) sending its requests through
+// the HTTP client it is given, so LLM_PROXY applies to LLM traffic only; langchain's OpenAI
+// builds a default client for every call
+#[derive(Clone)]
+pub struct OpenAiChat<C: Config> {
+    client: Client<C>,
+    options: CallOptions,
+    model: String,
+}
+
+impl<C: Config> OpenAiChat<C> {
+    // Function to create the model on the given connection settings and HTTP client
+    pub fn new(config: C, http_client: reqwest::Client) -> Self {
+        OpenAiChat {
+            client: Client::with_config(config).with_http_client(http_client),
+            options: CallOptions::default(),
+            model: "gpt-4o-mini".to_string(),
+        }
+    }
+
+    // Function to set the model name sent with each request (Azure takes the deployment
+    // from the settings instead)
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    // Function to set the sampling options sent with each request
+    pub fn with_options(mut self, options: CallOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    // Function to build the chat completion request for the messages
+    fn request(
+        &self,
+        messages: &[Message],
+        stream: bool,
+    ) -> Result<CreateChatCompletionRequest, LLMError> {
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request.model(self.model.clone());
+        request.messages(
+            messages
+                .iter()
+                .map(request_message)
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+        if let Some(temperature) = self.options.temperature {
+            request.temperature(temperature);
+        }
+        if let Some(max_tokens) = self.options.max_tokens {
+            request.max_tokens(max_tokens);
+        }
+        if let Some(stop_words) = &self.options.stop_words {
+            request.stop(stop_words);
+        }
+        if stream {
+            if let Some(include_usage) = self.options.stream_usage {
+                request.stream_options(ChatCompletionStreamOptions { include_usage });
+            }
+        }
+        Ok(request.build()?)
+    }
+}
+
+// Function to convert a langchain message into its chat completion message; an answer
+// given as tool calls is sent back as those calls
+fn request_message(message: &Message) -> Result<ChatCompletionRequestMessage, LLMError> {
+    let content = message.content.clone();
+    Ok(match message.message_type {
+        MessageType::SystemMessage => ChatCompletionRequestSystemMessageArgs::default()
+            .content(content)
+            .build()?
+            .into(),
+        MessageType::HumanMessage => ChatCompletionRequestUserMessageArgs::default()
+            .content(content)
+            .build()?
+            .into(),
+        MessageType::AIMessage => {
+            let mut assistant = ChatCompletionRequestAssistantMessageArgs::default();
+            assistant.content(content);
+            if let Some(calls) = &message.tool_calls {
+                let calls: Vec<ChatCompletionMessageToolCall> =
+                    serde_json::from_value(calls.clone())?;
+                assistant.tool_calls(calls);
+            }
+            assistant.build()?.into()
+        }
+        MessageType::ToolMessage => ChatCompletionRequestToolMessageArgs::default()
+            .content(content)
+            .tool_call_id(message.id.clone().unwrap_or_default())
+            .build()?
+            .into(),
+    })
+}
+
+#[async_trait]
+impl<C: Config + Send + Sync + 'static> LLM for OpenAiChat<C> {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        let response = self
+            .client
+            .chat()
+            .create(self.request(messages, false)?)
+            .await?;
+        let tokens = response.usage.map(|usage| TokenUsage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+        });
+        // Tool calls come back as their JSON array, like langchain's OpenAI
+        let generation = match response.choices.into_iter().next() {
+            Some(choice) => match choice.message.tool_calls {
+                Some(calls) => serde_json::to_string(&calls)?,
+                None => choice.message.content.unwrap_or_default(),
+            },
+            None => String::new(),
+        };
+        Ok(GenerateResult { tokens, generation })
+    }
+
+    async fn stream(
+        &self,
+        messages: &[Message],
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError> {
+        let chunks = self
+            .client
+            .chat()
+            .create_stream(self.request(messages, true)?)
+            .await?;
+        Ok(Box::pin(chunks.map(|chunk| {
+            let chunk = chunk?;
+            let tokens = chunk.usage.as_ref().map(|usage| TokenUsage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                total_tokens: usage.total_tokens,
+            });
+            let content = chunk
+                .choices
+                .first()
+                .and_then(|choice| choice.delta.content.clone())
+                .unwrap_or_default();
+            let value = serde_json::to_value(&chunk).unwrap_or(Value::Null);
+            Ok(StreamData::new(value, tokens, &content))
+        })))
+    }
+
+    fn add_options(&mut self, options: CallOptions) {
+        self.options.merge_options(options)
+    }
+}
+
+// OpenAI-compatible embedding model (--rag) on the same HTTP client as the chat model
+pub struct OpenAiEmbeddings<C: Config> {
+    client: Client<C>,
+    model: String,
+}
+
+impl<C: Config> OpenAiEmbeddings<C> {
+    // Function to create the embedding model on the given settings, HTTP client and model
+    pub fn new(config: C, http_client: reqwest::Client, model: impl Into<String>) -> Self {
+        OpenAiEmbeddings {
+            client: Client::with_config(config).with_http_client(http_client),
+            model: model.into(),
+        }
+    }
+
+    // Function to embed the given input, one vector per text
+    async fn embed(&self, input: EmbeddingInput) -> Result<Vec<Vec<f64>>, EmbedderError> {
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(&self.model)
+            .input(input)
+            .build()?;
+        let response = self.client.embeddings().create(request).await?;
+        Ok(response
+            .data
+            .into_iter()
+            .map(|item| item.embedding.into_iter().map(f64::from).collect())
+            .collect())
+    }
+}
+
+#[async_trait]
+impl<C: Config + Send + Sync> Embedder for OpenAiEmbeddings<C> {
+    async fn embed_documents(&self, documents: &[String]) -> Result<Vec<Vec<f64>>, EmbedderError> {
+        self.embed(EmbeddingInput::StringArray(documents.to_vec()))
+            .await
+    }
+
+    async fn embed_query(&self, text: &str) -> Result<Vec<f64>, EmbedderError> {
+        let mut embeddings = self.embed(EmbeddingInput::String(text.to_string())).await?;
+        if embeddings.is_empty() {
+            return Err(EmbedderError::OpenAIError(OpenAIError::InvalidArgument(
+                "no embedding in the response".to_string(),
+            )));
+        }
+        Ok(embeddings.swap_remove(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::config::OpenAIConfig;
+
+    #[tokio::test]
+    async fn a_chat_request_carries_the_model_messages_and_options() {
+        let mut server = mockito::Server::new_async().await;
+        let completion = server
+            .mock("POST", "/v1/chat/completions")
+            .match_body(mockito::Matcher::PartialJson(serde_json::json!({
+                "model": "llama3",
+                "temperature": 0.2,
+                "messages": [
+                    {"role": "system", "content": "Be brief."},
+                    {"role": "user", "content": "hello"}
+                ]
+            })))
+            .with_header("content-type", "application/json")
+            .with_body(
+                serde_json::json!({
+                    "id": "chatcmpl-1",
+                    "object": "chat.completion",
+                    "created": 0,
+                    "model": "llama3",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": "Hi"},
+                        "finish_reason": "stop"
+                    }],
+                    "usage": {"prompt_tokens": 7, "completion_tokens": 1, "total_tokens": 8}
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let config = OpenAIConfig::default()
+            .with_api_base(format!("{}/v1", server.url()))
+            .with_api_key("test");
+        let chat = OpenAiChat::new(config, reqwest::Client::new())
+            .with_model("llama3")
+            .with_options(CallOptions::default().with_temperature(0.2));
+
+        let result = chat
+            .generate(&[
+                Message::new_system_message("Be brief."),
+                Message::new_human_message("hello"),
+            ])
+            .await
+            .unwrap();
+
+        completion.assert_async().await;
+        assert_eq!(result.generation, "Hi");
+        assert_eq!(result.tokens.unwrap().total_tokens, 8);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::terminal;
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use serde::Serialize;
+use std::io::{self, Write};
+
+// How answers are written to stdout
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+// Where a session writes answers, labels and progress, so a frontend other than the
+// terminal can take them
+pub trait Output: Send {
+    // Function to write text as-is, without a line break
+    fn write_chunk(&mut self, text: &str);
+
+    // Function to write text followed by a line break
+    fn write_line(&mut self, text: &str);
+
+    // Function to write a warning; kept apart so a frontend can show it differently
+    fn write_warning(&mut self, text: &str) {
+        self.write_line(text);
+    }
+
+    // Function to show a long text the user reads at their own pace
+    fn page(&mut self, text: &str) {
+        self.write_line(text);
+    }
+
+    // Function to show progress while waiting for an answer; the caller finishes it
+    fn show_spinner(&mut self, message: &str) -> ProgressBar {
+        let _ = message;
+        ProgressBar::hidden()
+    }
+}
+
+// Default output: the terminal's stdout with an animated spinner
+#[derive(Debug, Default)]
+pub struct StdoutOutput;
+
+impl Output for StdoutOutput {
+    fn write_chunk(&mut self, text: &str) {
+        print!("{}", text);
+        io::stdout().flush().unwrap();
+    }
+
+    fn write_line(&mut self, text: &str) {
+        println!("{}", text);
+    }
+
+    // Warnings go to stderr so stdout carries only answers (one-shot mode, pipes)
+    fn write_warning(&mut self, text: &str) {
+        eprintln!("{}", text);
+    }
+
+    fn page(&mut self, text: &str) {
+        terminal::page_text(text);
+    }
+
+    // With the spinner disabled the message is printed once as a plain line instead
+    fn show_spinner(&mut self, message: &str) -> ProgressBar {
+        if !terminal::spinner_enabled() {
+            self.write_line(message);
+        }
+        terminal::create_spinner(message)
+    }
+}
+
+// One answered prompt as emitted by --format json (one object per line)
+#[derive(Serialize, Debug)]
+pub struct ExchangeRecord<'a> {
+    pub input: &'a str,
+    pub output: &'a str,
+    pub model: &'a str,
+    pub knowledge_source: Option<String>,
+}
+
+impl ExchangeRecord<'_> {
+    // Function to write the record as a single JSON line
+    pub fn print(&self, output: &mut dyn Output) -> Result<(), serde_json::Error> {
+        output.write_line(&serde_json::to_string(self)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn exchange_record_serializes_to_the_same_fields() {
+        let record = ExchangeRecord {
+            input: "What is \"PII\"?",
+            output: "Personal data.\nSee the docs.",
+            model: "gpt-4",
+            knowledge_source: Some("pii.json".to_string()),
+        };
+
+        let line = serde_json::to_string(&record).unwrap();
+        assert!(!line.contains('\n'));
+
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(parsed["input"], record.input);
+        assert_eq!(parsed["output"], record.output);
+        assert_eq!(parsed["model"], record.model);
+        assert_eq!(parsed["knowledge_source"], "pii.json");
+    }
+}
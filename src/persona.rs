@@ -0,0 +1,53 @@
+use crate::engine::SYSTEM_PROMPT;
+
+// A named system-message preset, optionally with the knowledge source it works from
+#[derive(Debug, Clone, Copy)]
+pub struct Persona {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub system_prompt: &'static str,
+    // Knowledge file loaded with the persona, looked up in KNOWLEDGE_DIR
+    pub knowledge: Option<&'static str>,
+}
+
+// Personas selectable with --persona and .persona; the first is the default
+pub const PERSONAS: &[Persona] = &[
+    Persona {
+        name: "docwriter",
+        description: "technical documentation writer (default)",
+        system_prompt: SYSTEM_PROMPT,
+        knowledge: None,
+    },
+    Persona {
+        name: "pii-classifier",
+        description: "classifies data fields by personal-data sensitivity",
+        system_prompt: "You are a data protection specialist. Classify the fields, columns or \
+            values the user gives you by personal-data sensitivity using the following \
+            knowledge, and explain each classification briefly.",
+        knowledge: Some("pii.json"),
+    },
+    Persona {
+        name: "mq-architect",
+        description: "designs and reviews message queue setups",
+        system_prompt: "You are an experienced message queue architect. Use the following \
+            knowledge to design, review and troubleshoot queue managers, queues and channels \
+            for the user.",
+        knowledge: Some("mq.json"),
+    },
+];
+
+// Function to look up a persona by name (case-insensitive)
+pub fn find_persona(name: &str) -> Result<&'static Persona, String> {
+    let name = name.trim();
+    PERSONAS
+        .iter()
+        .find(|persona| persona.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let known: Vec<&str> = PERSONAS.iter().map(|persona| persona.name).collect();
+            format!(
+                "unknown persona '{}', expected one of: {}",
+                name,
+                known.join(", ")
+            )
+        })
+}
@@ -0,0 +1,143 @@
+// Openings that are stripped with TRIM_PREAMBLE=1 when TRIM_PREAMBLE_PHRASES isn't set
+pub const DEFAULT_PREAMBLE_PHRASES: &[&str] = &[
+    "sure",
+    "certainly",
+    "of course",
+    "absolutely",
+    "here is",
+    "here's",
+    "here are",
+    "sure, here is",
+    "sure, here's",
+    "sure! here is",
+    "sure! here's",
+    "certainly! here is",
+    "certainly, here is",
+    "of course! here is",
+    "of course, here is",
+];
+
+// Strips filler openings ("Sure, here is the summary:") from answers. Matching is
+// conservative: only a first line that starts with a known phrase and either stops right
+// after it or ends with a colon is removed, and only when more text follows.
+#[derive(Debug, Clone)]
+pub struct PreambleTrimmer {
+    phrases: Vec<String>,
+}
+
+impl PreambleTrimmer {
+    // Function to create a trimmer for the given phrases (matched case-insensitively)
+    pub fn new(phrases: &[&str]) -> Self {
+        PreambleTrimmer {
+            phrases: phrases.iter().map(|phrase| phrase.to_lowercase()).collect(),
+        }
+    }
+
+    // Function to enable trimming with TRIM_PREAMBLE=1, using the `|`-separated phrases in
+    // TRIM_PREAMBLE_PHRASES or the defaults
+    pub fn from_env() -> Option<Self> {
+        if std::env::var("TRIM_PREAMBLE").ok()? != "1" {
+            return None;
+        }
+        Some(match std::env::var("TRIM_PREAMBLE_PHRASES") {
+            Ok(phrases) => {
+                let phrases: Vec<&str> = phrases
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|phrase| !phrase.is_empty())
+                    .collect();
+                PreambleTrimmer::new(&phrases)
+            }
+            Err(_) => PreambleTrimmer::new(DEFAULT_PREAMBLE_PHRASES),
+        })
+    }
+
+    // Function to split an answer into its preamble line and the rest, when it has one
+    pub fn trim<'a>(&self, answer: &'a str) -> Option<(&'a str, &'a str)> {
+        let answer = answer.trim_start();
+        let (first_line, rest) = answer.split_once('\n')?;
+        let rest = rest.trim_start();
+        if rest.is_empty() || !self.is_preamble(first_line.trim()) {
+            return None;
+        }
+        Some((first_line.trim(), rest))
+    }
+
+    // Function to check whether a line is only filler: a known phrase followed by nothing
+    // but punctuation, or a phrase introducing what follows with a trailing colon
+    fn is_preamble(&self, line: &str) -> bool {
+        let lower = line.to_lowercase();
+        self.phrases.iter().any(|phrase| {
+            let Some(after) = lower.strip_prefix(phrase.as_str()) else {
+                return false;
+            };
+            let standalone = after.chars().all(|c| matches!(c, '!' | '.' | ',' | ' '));
+            let introduces = after.starts_with([' ', ',', '!', ':']) && lower.ends_with(':');
+            standalone || introduces
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trimmer() -> PreambleTrimmer {
+        PreambleTrimmer::new(DEFAULT_PREAMBLE_PHRASES)
+    }
+
+    #[test]
+    fn filler_openings_are_trimmed() {
+        let cases = [
+            ("Sure!\nemail is PII.", "Sure!"),
+            ("Certainly.\n\nemail is PII.", "Certainly."),
+            (
+                "Sure, here is the summary:\nemail is PII.",
+                "Sure, here is the summary:",
+            ),
+            (
+                "  Here's what I found:\nemail is PII.",
+                "Here's what I found:",
+            ),
+            (
+                "OF COURSE! HERE IS THE LIST:\nemail is PII.",
+                "OF COURSE! HERE IS THE LIST:",
+            ),
+        ];
+        for (answer, preamble) in cases {
+            assert_eq!(
+                trimmer().trim(answer),
+                Some((preamble, "email is PII.")),
+                "{:?}",
+                answer
+            );
+        }
+    }
+
+    #[test]
+    fn answers_without_a_preamble_are_left_alone() {
+        let answers = [
+            "email is PII.\nphone is PII too.",
+            // Real content that happens to start with a phrase
+            "Surely the email column is PII.\nThe phone column too.",
+            "Here is the email column, which is PII.\nThe phone column too.",
+            // Nothing would be left
+            "Sure!",
+            "Sure!\n  ",
+        ];
+        for answer in answers {
+            assert_eq!(trimmer().trim(answer), None, "{:?}", answer);
+        }
+    }
+
+    #[test]
+    fn only_the_configured_phrases_are_trimmed() {
+        let trimmer = PreambleTrimmer::new(&["great question"]);
+
+        assert_eq!(
+            trimmer.trim("Great question!\nIt is PII."),
+            Some(("Great question!", "It is PII."))
+        );
+        assert_eq!(trimmer.trim("Sure!\nIt is PII."), None);
+    }
+}
@@ -0,0 +1,97 @@
+use log::error;
+
+// A step that rewrites the user's input before it is sent (e.g. normalising or scrubbing it)
+pub trait Preprocessor: Send + Sync {
+    fn process(&self, input: &str) -> String;
+}
+
+// Preprocessor that trims the input and collapses runs of whitespace to one space
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CollapseWhitespace;
+
+impl Preprocessor for CollapseWhitespace {
+    fn process(&self, input: &str) -> String {
+        input.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+}
+
+// Preprocessors applied in order, each to the output of the one before
+#[derive(Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn Preprocessor>>,
+}
+
+impl Pipeline {
+    // Function to create an empty pipeline, which leaves the input unchanged
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    // Function to append a preprocessor to the end of the pipeline
+    pub fn with(mut self, step: impl Preprocessor + 'static) -> Self {
+        self.steps.push(Box::new(step));
+        self
+    }
+
+    // Function to build the pipeline named in PREPROCESSORS (comma-separated, in order),
+    // ignoring unknown names; only `collapse_whitespace` is built in
+    pub fn from_env() -> Self {
+        let mut pipeline = Pipeline::new();
+        let Ok(names) = std::env::var("PREPROCESSORS") else {
+            return pipeline;
+        };
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name {
+                "collapse_whitespace" => pipeline = pipeline.with(CollapseWhitespace),
+                other => error!("Ignoring unknown preprocessor '{}' in PREPROCESSORS", other),
+            }
+        }
+        pipeline
+    }
+
+    // Function to run the input through every preprocessor in order
+    pub fn process(&self, input: &str) -> String {
+        self.steps
+            .iter()
+            .fold(input.to_string(), |text, step| step.process(&text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Appends a marker, so the order of application shows in the result
+    struct Append(&'static str);
+
+    impl Preprocessor for Append {
+        fn process(&self, input: &str) -> String {
+            format!("{}{}", input, self.0)
+        }
+    }
+
+    #[test]
+    fn preprocessors_run_in_the_order_they_were_added() {
+        let pipeline = Pipeline::new().with(Append(" [a]")).with(Append(" [b]"));
+        assert_eq!(pipeline.process("query"), "query [a] [b]");
+
+        let pipeline = Pipeline::new()
+            .with(Append("  tail  "))
+            .with(CollapseWhitespace);
+        assert_eq!(pipeline.process("  some   query "), "some query tail");
+
+        let pipeline = Pipeline::new()
+            .with(CollapseWhitespace)
+            .with(Append("  tail  "));
+        assert_eq!(pipeline.process("  some   query "), "some query  tail  ");
+    }
+
+    #[test]
+    fn an_empty_pipeline_leaves_the_input_unchanged() {
+        assert_eq!(Pipeline::new().process("  as  is "), "  as  is ");
+    }
+}
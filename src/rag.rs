@@ -0,0 +1,149 @@
+use crate::error::AppError;
+use langchain_rust::embedding::Embedder;
+use log::debug;
+
+// Function to read how many knowledge chunks are retrieved per prompt from RAG_TOP_K (default 4)
+pub fn top_k_from_env() -> usize {
+    std::env::var("RAG_TOP_K")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&top_k| top_k > 0)
+        .unwrap_or(4)
+}
+
+// Function to compute the cosine similarity of two embeddings (0 for a zero vector)
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+// Function to pick the indices of the top_k embeddings most similar to the query, best first
+pub fn top_k_indices(query: &[f64], embeddings: &[Vec<f64>], top_k: usize) -> Vec<usize> {
+    let mut scored: Vec<(usize, f64)> = embeddings
+        .iter()
+        .enumerate()
+        .map(|(index, embedding)| (index, cosine_similarity(query, embedding)))
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scored
+        .into_iter()
+        .take(top_k)
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// In-memory embeddings of the knowledge chunks for the session (--rag)
+pub struct KnowledgeIndex {
+    embedder: Box<dyn Embedder>,
+    chunks: Vec<String>,
+    embeddings: Vec<Vec<f64>>,
+    top_k: usize,
+}
+
+impl KnowledgeIndex {
+    // Function to embed every chunk once, up front
+    pub async fn build(
+        embedder: Box<dyn Embedder>,
+        chunks: Vec<String>,
+        top_k: usize,
+    ) -> Result<Self, AppError> {
+        let embeddings = embedder.embed_documents(&chunks).await?;
+        debug!("Embedded {} knowledge chunks", chunks.len());
+        Ok(KnowledgeIndex {
+            embedder,
+            chunks,
+            embeddings,
+            top_k,
+        })
+    }
+
+    // Function to embed a new set of chunks in place of the current ones, keeping the
+    // embedder and top_k (after the knowledge sources change)
+    pub async fn rebuild(&mut self, chunks: Vec<String>) -> Result<(), AppError> {
+        self.embeddings = self.embedder.embed_documents(&chunks).await?;
+        debug!("Re-embedded {} knowledge chunks", chunks.len());
+        self.chunks = chunks;
+        Ok(())
+    }
+
+    // Function to select the chunks most relevant to a prompt, joined as knowledge text
+    pub async fn retrieve(&self, input: &str) -> Result<String, AppError> {
+        let query = self.embedder.embed_query(input).await?;
+        let selected: Vec<&str> = top_k_indices(&query, &self.embeddings, self.top_k)
+            .into_iter()
+            .map(|index| self.chunks[index].as_str())
+            .collect();
+        debug!("Retrieved {} knowledge chunks", selected.len());
+        Ok(selected.join("\n\n"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use langchain_rust::embedding::EmbedderError;
+
+    // Embedder placing a text on one axis per keyword it mentions
+    pub(crate) struct KeywordEmbedder;
+
+    const KEYWORDS: [&str; 3] = ["email", "phone", "queue"];
+
+    fn keyword_vector(text: &str) -> Vec<f64> {
+        let text = text.to_lowercase();
+        KEYWORDS
+            .iter()
+            .map(|keyword| text.matches(keyword).count() as f64)
+            .collect()
+    }
+
+    #[async_trait]
+    impl Embedder for KeywordEmbedder {
+        async fn embed_documents(
+            &self,
+            documents: &[String],
+        ) -> Result<Vec<Vec<f64>>, EmbedderError> {
+            Ok(documents.iter().map(|text| keyword_vector(text)).collect())
+        }
+
+        async fn embed_query(&self, text: &str) -> Result<Vec<f64>, EmbedderError> {
+            Ok(keyword_vector(text))
+        }
+    }
+
+    #[test]
+    fn cosine_similarity_ranks_by_direction() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-9);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+
+        let embeddings = vec![vec![0.0, 1.0], vec![1.0, 0.1], vec![1.0, 1.0]];
+        assert_eq!(top_k_indices(&[1.0, 0.0], &embeddings, 2), [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn the_most_similar_chunks_are_retrieved_best_first() {
+        let chunks = vec![
+            "email: a contact address, PII".to_string(),
+            "queue: orders.created carries order events".to_string(),
+            "phone: a contact number, PII".to_string(),
+            "email and phone together identify a person".to_string(),
+        ];
+        let index = KnowledgeIndex::build(Box::new(KeywordEmbedder), chunks, 2)
+            .await
+            .unwrap();
+
+        let retrieved = index.retrieve("Is an email address PII?").await.unwrap();
+
+        assert_eq!(
+            retrieved,
+            "email: a contact address, PII\n\nemail and phone together identify a person"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use crate::error::AppError;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+
+// One line typed into the REPL (--record); prompts also keep what was sent, the answer
+// and how long the LLM took, commands only the line itself
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedTurn {
+    pub input: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+}
+
+// A whole recorded session, saved as a JSON file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub turns: Vec<RecordedTurn>,
+}
+
+impl Recording {
+    // Function to read a recording written by --record
+    pub fn load(path: &str) -> Result<Self, AppError> {
+        let content = fs::read_to_string(path)?;
+        let recording: Recording = serde_json::from_str(&content)?;
+        debug!(
+            "Loaded {} recorded turns from {}",
+            recording.turns.len(),
+            path
+        );
+        Ok(recording)
+    }
+
+    // Function to write the recording as pretty-printed JSON
+    pub fn save(&self, path: &str) -> Result<(), AppError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Function to map each recorded prompt to its answer, for replaying against the mock
+    pub fn mock_responses(&self) -> HashMap<String, String> {
+        self.turns
+            .iter()
+            .filter_map(|turn| Some((turn.prompt.clone()?, turn.response.clone()?)))
+            .collect()
+    }
+}
+
+// Writes the session to the --record file as it goes; rewritten after every turn so a
+// crash keeps everything up to the last answer
+pub struct Recorder {
+    path: String,
+    recording: Recording,
+}
+
+impl Recorder {
+    // Function to start a new recording, replacing any file at path
+    pub fn create(path: &str) -> Result<Self, AppError> {
+        let recorder = Recorder {
+            path: path.to_string(),
+            recording: Recording::default(),
+        };
+        recorder.recording.save(path)?;
+        debug!("Recording the session to {}", path);
+        Ok(recorder)
+    }
+
+    // Function to record a line as typed, before it is handled
+    pub fn begin(&mut self, input: &str) -> Result<(), AppError> {
+        self.recording.turns.push(RecordedTurn {
+            input: input.to_string(),
+            prompt: None,
+            response: None,
+            latency_ms: None,
+        });
+        self.recording.save(&self.path)
+    }
+
+    // Function to add the prompt sent for the latest line and its answer
+    pub fn finish(
+        &mut self,
+        prompt: &str,
+        response: &str,
+        latency: Option<Duration>,
+    ) -> Result<(), AppError> {
+        if let Some(turn) = self.recording.turns.last_mut() {
+            turn.prompt = Some(prompt.to_string());
+            turn.response = Some(response.to_string());
+            turn.latency_ms = latency.map(|latency| latency.as_millis() as u64);
+        }
+        self.recording.save(&self.path)
+    }
+}
+
+// How a replayed answer compares with the recorded one
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayOutcome {
+    Matches,
+    // Holds the recorded answer
+    Differs(String),
+    // The line had no recorded answer (it failed or was a command when recorded)
+    NotRecorded,
+}
+
+// Feeds the lines of a recording back into the REPL (--replay) and tallies how the new
+// answers compare with the recorded ones
+pub struct Replay {
+    turns: std::vec::IntoIter<RecordedTurn>,
+    current: Option<RecordedTurn>,
+    pub matched: usize,
+    pub differed: usize,
+}
+
+impl Replay {
+    pub fn new(recording: Recording) -> Self {
+        Replay {
+            turns: recording.turns.into_iter(),
+            current: None,
+            matched: 0,
+            differed: 0,
+        }
+    }
+
+    // Function to take the next recorded line, None once the recording is used up
+    pub fn next_input(&mut self) -> Option<String> {
+        self.current = self.turns.next();
+        self.current.as_ref().map(|turn| turn.input.clone())
+    }
+
+    // Function to compare the answer to the current line with the recorded one
+    pub fn compare(&mut self, answer: &str) -> ReplayOutcome {
+        let Some(recorded) = self
+            .current
+            .as_ref()
+            .and_then(|turn| turn.response.as_ref())
+        else {
+            return ReplayOutcome::NotRecorded;
+        };
+        if recorded.trim() == answer.trim() {
+            self.matched += 1;
+            ReplayOutcome::Matches
+        } else {
+            self.differed += 1;
+            ReplayOutcome::Differs(recorded.clone())
+        }
+    }
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Replay finished: {} answer(s) matched the recording, {} differed",
+            self.matched, self.differed
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{ChatEngine, EngineConfig};
+    use crate::llm::LlmBackend;
+    use crate::mock::MockLlm;
+
+    #[tokio::test]
+    async fn a_recorded_session_replays_with_matching_answers() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("session.json");
+        let path = path.to_str().unwrap();
+        let mut recorder = Recorder::create(path).unwrap();
+        recorder.begin("hello").unwrap();
+        recorder
+            .finish("hello", "Hi there", Some(Duration::from_millis(120)))
+            .unwrap();
+        recorder.begin(".clear").unwrap();
+        recorder.begin("what now?").unwrap();
+        recorder
+            .finish("what now?", "Ask me about PII.", None)
+            .unwrap();
+
+        let recording = Recording::load(path).unwrap();
+        assert_eq!(recording, recorder.recording);
+        assert_eq!(recording.turns.len(), 3);
+        assert_eq!(recording.turns[0].latency_ms, Some(120));
+        assert_eq!(recording.turns[1].response, None);
+
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let mut engine = ChatEngine::with_backend(
+            config,
+            LlmBackend::Custom(Box::new(MockLlm::with_responses(
+                recording.mock_responses(),
+            ))),
+        )
+        .unwrap();
+        let mut replay = Replay::new(recording);
+        let mut outcomes = Vec::new();
+        while let Some(input) = replay.next_input() {
+            if input.starts_with('.') {
+                continue;
+            }
+            let answer = engine.ask(&input).await.unwrap();
+            outcomes.push(replay.compare(&answer));
+        }
+
+        assert_eq!(outcomes, [ReplayOutcome::Matches, ReplayOutcome::Matches]);
+        assert_eq!((replay.matched, replay.differed), (2, 0));
+    }
+
+    #[test]
+    fn a_changed_answer_is_reported_with_the_recorded_one() {
+        let recording = Recording {
+            turns: vec![RecordedTurn {
+                input: "hello".to_string(),
+                prompt: Some("hello".to_string()),
+                response: Some("Hi there".to_string()),
+                latency_ms: None,
+            }],
+        };
+        let mut replay = Replay::new(recording);
+        replay.next_input();
+
+        assert_eq!(
+            replay.compare("Hello!"),
+            ReplayOutcome::Differs("Hi there".to_string())
+        );
+        assert_eq!(replay.differed, 1);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::redact::{log_safe, LogKind};
+use log::debug;
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Retry policy for transient LLM failures (rate limits, timeouts, unavailable service)
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    // Function to read the policy from LLM_MAX_RETRIES (default 3)
+    pub fn from_env() -> Self {
+        let max_retries = std::env::var("LLM_MAX_RETRIES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(3);
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+
+    // Function to compute the exponential backoff with jitter for a retry attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay * 2u32.saturating_pow(attempt.saturating_sub(1));
+        let jitter_range = (delay.as_millis() as u64 / 2).max(1);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or(0);
+        delay + Duration::from_millis(nanos % jitter_range)
+    }
+}
+
+// Function to check whether an error is worth retrying; auth and bad requests are not
+pub fn is_transient_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    [
+        "429",
+        "503",
+        "rate limit",
+        "too many requests",
+        "service unavailable",
+        "timed out",
+        "timeout",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+// Wait before a retry, reported to the on_retry callback about once a second
+#[derive(Debug, Clone, Copy)]
+pub struct RetryWait {
+    pub attempt: u32,
+    pub max_retries: u32,
+    pub remaining: Duration,
+    pub rate_limited: bool,
+}
+
+// Function to run an operation, retrying transient failures with exponential backoff;
+// on_retry is kept informed while waiting for each retry
+pub async fn with_retry<T, E, F, Fut, R>(
+    policy: &RetryPolicy,
+    mut operation: F,
+    mut on_retry: R,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: fmt::Display,
+    R: FnMut(&RetryWait),
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_retries && is_transient_error(&e.to_string()) => {
+                attempt += 1;
+                let delay = policy.backoff(attempt);
+                debug!(
+                    "LLM call failed ({}), retrying in {:?} (retry {}/{})",
+                    log_safe(&e.to_string(), LogKind::Text),
+                    delay,
+                    attempt,
+                    policy.max_retries
+                );
+                let message = e.to_string().to_lowercase();
+                let rate_limited = message.contains("429") || message.contains("rate limit");
+                // Sleep in steps of at most a second so the countdown stays live
+                let mut remaining = delay;
+                while !remaining.is_zero() {
+                    on_retry(&RetryWait {
+                        attempt,
+                        max_retries: policy.max_retries,
+                        remaining,
+                        rate_limited,
+                    });
+                    let step = remaining.min(Duration::from_secs(1));
+                    tokio::time::sleep(step).await;
+                    remaining -= step;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Function to create a policy with a tiny backoff so tests don't wait
+    fn quick_policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_until_success() {
+        let calls = Cell::new(0);
+        let result: Result<&str, String> = with_retry(
+            &quick_policy(3),
+            || {
+                calls.set(calls.get() + 1);
+                let attempt = calls.get();
+                async move {
+                    if attempt <= 2 {
+                        Err("HTTP 429 Too Many Requests".to_string())
+                    } else {
+                        Ok("answer")
+                    }
+                }
+            },
+            |_| {},
+        )
+        .await;
+        assert_eq!(result, Ok("answer"));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn auth_errors_are_not_retried() {
+        let calls = Cell::new(0);
+        let result: Result<(), String> = with_retry(
+            &quick_policy(3),
+            || {
+                calls.set(calls.get() + 1);
+                async { Err("HTTP 401 Unauthorized".to_string()) }
+            },
+            |_| {},
+        )
+        .await;
+        assert_eq!(result, Err("HTTP 401 Unauthorized".to_string()));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn the_last_error_is_returned_once_retries_run_out() {
+        let calls = Cell::new(0);
+        let result: Result<(), String> = with_retry(
+            &quick_policy(2),
+            || {
+                calls.set(calls.get() + 1);
+                async { Err("503 Service Unavailable".to_string()) }
+            },
+            |_| {},
+        )
+        .await;
+        assert_eq!(result, Err("503 Service Unavailable".to_string()));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_countdown_is_reported_while_waiting() {
+        let policy = RetryPolicy {
+            max_retries: 1,
+            base_delay: Duration::from_millis(2500),
+        };
+        let calls = Cell::new(0);
+        let mut waits = Vec::new();
+
+        let result: Result<(), String> = with_retry(
+            &policy,
+            || {
+                calls.set(calls.get() + 1);
+                let attempt = calls.get();
+                async move {
+                    if attempt == 1 {
+                        Err("429 rate limit".to_string())
+                    } else {
+                        Ok(())
+                    }
+                }
+            },
+            |wait| waits.push(*wait),
+        )
+        .await;
+
+        assert_eq!(result, Ok(()));
+        // 2.5s plus jitter is counted down a second at a time
+        assert!(waits.len() >= 3);
+        assert!(waits
+            .windows(2)
+            .all(|pair| pair[1].remaining + Duration::from_secs(1) == pair[0].remaining));
+        assert!(waits
+            .iter()
+            .all(|wait| wait.attempt == 1 && wait.max_retries == 1 && wait.rate_limited));
+    }
+}
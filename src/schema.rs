@@ -0,0 +1,145 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+// Fixed JSON shapes the answer can be constrained to (--schema)
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseSchema {
+    Pii,
+}
+
+// Answer shape for --schema pii
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PiiClassification {
+    pub field: String,
+    pub is_pii: bool,
+    pub category: String,
+}
+
+impl ResponseSchema {
+    // Function to describe the required shape to the model; appended to the system message
+    pub fn instruction(self) -> &'static str {
+        match self {
+            ResponseSchema::Pii => {
+                "Answer with only a JSON object of the form \
+                 {\"field\": string, \"is_pii\": boolean, \"category\": string} \
+                 and no other text."
+            }
+        }
+    }
+
+    // Function to check that an answer parses into the schema
+    pub fn validate(self, text: &str) -> Result<(), serde_json::Error> {
+        match self {
+            ResponseSchema::Pii => {
+                serde_json::from_str::<PiiClassification>(strip_code_fence(text))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Function to append the schema instruction, if any, to a system message
+pub fn with_schema(system_prompt: &str, schema: Option<ResponseSchema>) -> String {
+    match schema {
+        Some(schema) => format!("{}\n\n{}", system_prompt, schema.instruction()),
+        None => system_prompt.to_string(),
+    }
+}
+
+// Function to drop a surrounding ``` fence, which models often add around JSON
+fn strip_code_fence(text: &str) -> &str {
+    let text = text.trim();
+    match text.strip_prefix("```") {
+        Some(rest) => {
+            let rest = rest.strip_prefix("json").unwrap_or(rest);
+            rest.strip_suffix("```").unwrap_or(rest).trim()
+        }
+        None => text,
+    }
+}
+
+// Buffer for a JSON answer arriving in stream chunks (--schema with --stream); it tracks
+// bracket depth outside strings so the answer is parsed only once it is balanced
+#[derive(Debug, Default)]
+pub struct JsonAccumulator {
+    buffer: String,
+    depth: usize,
+    started: bool,
+    complete: bool,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonAccumulator {
+    // Function to add a chunk, updating whether the first JSON value has been closed
+    pub fn push(&mut self, chunk: &str) {
+        self.buffer.push_str(chunk);
+        for c in chunk.chars() {
+            if self.complete {
+                break;
+            }
+            if self.in_string {
+                match c {
+                    _ if self.escaped => self.escaped = false,
+                    '\\' => self.escaped = true,
+                    '"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                '"' if self.started => self.in_string = true,
+                '{' | '[' => {
+                    self.started = true;
+                    self.depth += 1;
+                }
+                '}' | ']' if self.started => {
+                    self.depth = self.depth.saturating_sub(1);
+                    self.complete = self.depth == 0;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Function to check whether the brackets of the JSON value are balanced yet
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    // Function to parse the accumulated answer; None until it is complete
+    pub fn parse(&self) -> Option<Result<serde_json::Value, serde_json::Error>> {
+        self.complete
+            .then(|| serde_json::from_str(strip_code_fence(&self.buffer)))
+    }
+
+    // Function to get everything received so far
+    pub fn text(&self) -> &str {
+        &self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_split_over_three_chunks_parses_once_complete() {
+        let mut json = JsonAccumulator::default();
+
+        json.push(r#"{"field": "email", "#);
+        assert!(!json.is_complete());
+        assert!(json.parse().is_none());
+
+        // The brace inside the string doesn't close anything
+        json.push(r#""reason": "looks like {user}@host", "#);
+        assert!(!json.is_complete());
+
+        json.push(r#""pii": true}"#);
+        assert!(json.is_complete());
+        let value = json.parse().unwrap().unwrap();
+        assert_eq!(value["field"], "email");
+        assert_eq!(value["reason"], "looks like {user}@host");
+        assert_eq!(value["pii"], true);
+    }
+}
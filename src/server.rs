@@ -0,0 +1,139 @@
+use crate::data::resolve_knowledge_path;
+use crate::engine::{ChatEngine, EngineConfig};
+use crate::error::AppError;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use futures::{stream, Stream, StreamExt};
+use langchain_rust::schemas::Message;
+use log::{debug, error};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+// Header naming the conversation a request belongs to; requests without it share one
+const SESSION_HEADER: &str = "x-session-id";
+
+// Body of POST /chat
+#[derive(Debug, Deserialize)]
+pub struct ChatRequest {
+    pub input: String,
+    pub knowledge_source: Option<String>,
+}
+
+// Engines by session id, each with its own history, created on first use
+#[derive(Clone)]
+struct ServerState {
+    config: EngineConfig,
+    sessions: Arc<Mutex<HashMap<String, Arc<Mutex<ChatEngine>>>>>,
+}
+
+impl ServerState {
+    // Function to get the engine of a session, creating it on the first request
+    async fn engine(&self, session_id: &str) -> Result<Arc<Mutex<ChatEngine>>, AppError> {
+        let mut sessions = self.sessions.lock().await;
+        if let Some(engine) = sessions.get(session_id) {
+            return Ok(engine.clone());
+        }
+        let engine = Arc::new(Mutex::new(ChatEngine::new(self.config.clone()).await?));
+        sessions.insert(session_id.to_string(), engine.clone());
+        debug!("Started session {}", session_id);
+        Ok(engine)
+    }
+}
+
+// Function to serve POST /chat on addr (--serve), streaming each answer as Server-Sent
+// Events: one `data` event per chunk, then `done`, or `error` if the answer fails midway
+pub async fn serve(addr: &str, config: EngineConfig) -> Result<(), AppError> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("Serving POST /chat on http://{}", listener.local_addr()?);
+    axum::serve(listener, router(config)).await?;
+    Ok(())
+}
+
+// Function to build the routes of the server, with engines created from config
+pub fn router(config: EngineConfig) -> Router {
+    let state = ServerState {
+        config,
+        sessions: Arc::new(Mutex::new(HashMap::new())),
+    };
+    Router::new().route("/chat", post(chat)).with_state(state)
+}
+
+// Function to answer one chat request as an SSE stream
+async fn chat(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(request): Json<ChatRequest>,
+) -> Response {
+    let session_id = headers
+        .get(SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("default")
+        .to_string();
+
+    match start_answer(&state, &session_id, request).await {
+        Ok(events) => Sse::new(events)
+            .keep_alive(KeepAlive::default())
+            .into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+// Function to start streaming an answer; the session's engine stays locked until the
+// answer is complete and recorded, so one session answers one request at a time
+async fn start_answer(
+    state: &ServerState,
+    session_id: &str,
+    request: ChatRequest,
+) -> Result<impl Stream<Item = Result<Event, Infallible>>, AppError> {
+    let mut engine = state.engine(session_id).await?.lock_owned().await;
+    if let Some(source) = &request.knowledge_source {
+        let source = resolve_knowledge_path(source)?;
+        if engine.knowledge_sources != [source.clone()] {
+            engine
+                .fetch_knowledge_urls(std::slice::from_ref(&source), false)
+                .await?;
+            engine.set_knowledge(&[source])?;
+        }
+    }
+
+    engine.trim_history(&request.input);
+    let mut chunks = engine.stream(&request.input).await?;
+
+    let (sender, receiver) = mpsc::channel(32);
+    tokio::spawn(async move {
+        let mut answer = String::new();
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(data) => {
+                    answer.push_str(&data.content);
+                    let event = Event::default().data(data.content);
+                    if sender.send(event).await.is_err() {
+                        // The client went away; keep what was answered so far
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("Streaming failed: {}", e);
+                    let _ = sender
+                        .send(Event::default().event("error").data(e.to_string()))
+                        .await;
+                    break;
+                }
+            }
+        }
+        engine.audit(&request.input, &answer);
+        engine.push_turn(&request.input, Message::new_ai_message(answer));
+        let _ = sender.send(Event::default().event("done").data("")).await;
+    });
+
+    Ok(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|event| (Ok(event), receiver))
+    }))
+}
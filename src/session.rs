@@ -0,0 +1,570 @@
+use crate::config::{AppConfig, API_KEY_REFERENCE};
+use crate::data::{resolve_knowledge_path, LoadedKnowledge};
+use crate::engine::ChatEngine;
+use crate::error::AppError;
+use crate::history::Scrollback;
+use crate::interrupt;
+use crate::llm;
+use crate::output::{ExchangeRecord, Output, OutputFormat, StdoutOutput};
+use crate::preprocess::Pipeline;
+use crate::terminal::{
+    print_knowledge_summary, print_user_label, process_with_llm, process_with_llm_streaming,
+    TypewriterOptions,
+};
+use crate::tokens::Usage;
+use colored::Colorize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Interactive chat session: the engine plus how answers are presented in the terminal
+pub struct Session {
+    pub engine: ChatEngine,
+    pub typewriter: TypewriterOptions,
+    pub streaming: bool,
+    pub render_markdown: bool,
+    pub output_format: OutputFormat,
+    pub dry_run: bool,
+    pub verbose: bool,
+    pub last_input: Option<String>,
+    pub last_answer: Option<String>,
+    pub active: Arc<AtomicBool>,
+    pub output: Box<dyn Output>,
+    pub started_at: Instant,
+    pub turn_usage: Vec<Usage>,
+    pub turn_latency: Vec<Duration>,
+    pub show_latency: bool,
+    pub scrollback: Scrollback,
+    pub preprocessors: Pipeline,
+    pub preloaded_knowledge: HashMap<String, LoadedKnowledge>,
+}
+
+// Summary of a session so far (.stats)
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    pub turns: usize,
+    pub usage: Usage,
+    pub model: String,
+    pub knowledge_source: Option<String>,
+    pub duration: Duration,
+    pub latencies: Vec<Duration>,
+}
+
+impl fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.duration.as_secs();
+        writeln!(f, "Turns: {}", self.turns)?;
+        writeln!(f, "Tokens: {}", self.usage)?;
+        writeln!(f, "Model: {}", self.model)?;
+        writeln!(
+            f,
+            "Knowledge: {}",
+            self.knowledge_source.as_deref().unwrap_or("none")
+        )?;
+        write!(f, "Duration: {}m {}s", seconds / 60, seconds % 60)?;
+        if let Some(latency) = LatencySummary::from_samples(&self.latencies) {
+            write!(f, "\nLatency: {}", latency)?;
+        }
+        Ok(())
+    }
+}
+
+// Minimum, average, maximum and 95th percentile of a set of latencies
+#[derive(Debug, Clone, Copy)]
+pub struct LatencySummary {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    pub p95: Duration,
+}
+
+impl LatencySummary {
+    // Function to summarize latency samples (None when there are none); p95 uses the
+    // nearest-rank method
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let rank = (sorted.len() * 95).div_ceil(100).max(1);
+        Some(LatencySummary {
+            min: *sorted.first()?,
+            avg: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            max: *sorted.last()?,
+            p95: sorted[rank - 1],
+        })
+    }
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "min {:.1}s, avg {:.1}s, max {:.1}s, p95 {:.1}s",
+            self.min.as_secs_f32(),
+            self.avg.as_secs_f32(),
+            self.max.as_secs_f32(),
+            self.p95.as_secs_f32()
+        )
+    }
+}
+
+impl Session {
+    // Function to create a session around an engine with the default presentation
+    pub fn new(engine: ChatEngine, active: Arc<AtomicBool>) -> Self {
+        Session {
+            engine,
+            typewriter: TypewriterOptions::from_env(),
+            streaming: false,
+            render_markdown: false,
+            output_format: OutputFormat::Text,
+            dry_run: false,
+            verbose: false,
+            last_input: None,
+            last_answer: None,
+            active,
+            output: Box::new(StdoutOutput),
+            started_at: Instant::now(),
+            turn_usage: Vec::new(),
+            turn_latency: Vec::new(),
+            show_latency: std::env::var("SHOW_LATENCY").is_ok_and(|value| value == "1"),
+            scrollback: Scrollback::from_env(),
+            preprocessors: Pipeline::from_env(),
+            preloaded_knowledge: HashMap::new(),
+        }
+    }
+
+    // Function to summarize the answered turns, their tokens and the session so far
+    pub fn stats(&self) -> SessionStats {
+        let mut usage = Usage::default();
+        for turn in &self.turn_usage {
+            usage.add(turn);
+        }
+        SessionStats {
+            turns: self.turn_usage.len(),
+            usage,
+            model: self.engine.model.clone(),
+            knowledge_source: self.engine.knowledge_source(),
+            duration: self.started_at.elapsed(),
+            latencies: self.turn_latency.clone(),
+        }
+    }
+
+    // Function to capture the current settings for a config file (.save-config); the API
+    // key is written as a reference to its environment variable, never in plaintext
+    pub fn current_config(&self) -> AppConfig {
+        let env = |var: &str| std::env::var(var).ok();
+        let mut config = AppConfig {
+            api_base: env("OPEN_AI_SERVICE_URL"),
+            api_key: env("OPEN_AI_SERVICE_KEY").map(|_| API_KEY_REFERENCE.to_string()),
+            api_version: env("OPEN_AI_API_VERSION"),
+            temperature: self.engine.sampling.temperature,
+            max_tokens: self.engine.sampling.max_tokens,
+            typewriter_delay_ms: Some(self.typewriter.delay_ms),
+            knowledge_dir: env("KNOWLEDGE_DIR"),
+            persona: self.engine.persona.map(str::to_string),
+            language: self.engine.language.clone(),
+            ..AppConfig::default()
+        };
+        config.set_model(&llm::provider(), &self.engine.model);
+        config
+    }
+
+    // Function to switch to the given knowledge sources (.knowledge use, the .knowledge
+    // picker), taking the preloaded copy of a single source when there is one and reading
+    // (or fetching) them otherwise; no sources leaves the session without knowledge
+    pub async fn switch_knowledge(&mut self, sources: &[String]) -> Result<(), AppError> {
+        if sources.is_empty() {
+            return self
+                .engine
+                .use_loaded_knowledge(Vec::new(), LoadedKnowledge::default());
+        }
+        let paths = sources
+            .iter()
+            .map(|source| resolve_knowledge_path(source))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let [path] = paths.as_slice() {
+            if let Some(loaded) = self.preloaded_knowledge.get(path) {
+                return self
+                    .engine
+                    .use_loaded_knowledge(paths.clone(), loaded.clone());
+            }
+        }
+        self.engine.fetch_knowledge_urls(&paths, false).await?;
+        self.engine.set_knowledge(&paths)
+    }
+
+    // Function to time a prompt `runs` times in a row (.time), stopping early on Ctrl-C
+    // with the samples taken so far; the answers are discarded
+    pub async fn benchmark(&mut self, runs: usize, input: &str) -> Result<Vec<Duration>, AppError> {
+        let mut samples = Vec::with_capacity(runs);
+        for run in 1..=runs {
+            let spinner = self
+                .output
+                .show_spinner(&format!("Run {}/{}...", run, runs));
+            let timed = tokio::select! {
+                timed = self.engine.time_prompt(input) => timed,
+                _ = interrupt::cancelled(&self.active) => Err(AppError::Interrupted),
+            };
+            spinner.finish_and_clear();
+            match timed {
+                Ok(latency) => samples.push(latency),
+                Err(AppError::Interrupted) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(samples)
+    }
+
+    // Function to print an answer as a JSON line (--format json)
+    pub fn print_json(&mut self, input: &str, output: &str) -> Result<(), serde_json::Error> {
+        ExchangeRecord {
+            input,
+            output,
+            model: &self.engine.model,
+            knowledge_source: self.engine.knowledge_source(),
+        }
+        .print(self.output.as_mut())
+    }
+
+    // Function to warn when an answer still doesn't match --schema after the retry
+    pub fn warn_if_off_schema(&mut self, output: &str) {
+        if let Some(schema) = self.engine.schema {
+            if let Err(e) = schema.validate(output) {
+                let warning = format!(
+                    "Warning: answer does not match the {:?} schema: {}",
+                    schema, e
+                );
+                self.output.write_warning(&warning.yellow().to_string());
+            }
+        }
+    }
+
+    // Function to answer a prompt and record its token usage for .stats; answers served
+    // from the cache count as a turn without tokens. The input goes through the
+    // preprocessors first.
+    pub async fn ask(&mut self, input: &str) -> Result<String, AppError> {
+        let input = &self.preprocessors.process(input);
+        let previous_usage = self.engine.last_usage.take();
+        self.engine.last_latency = None;
+        let result = self.answer(input).await;
+        let usage = self.engine.last_usage;
+        if usage.is_none() {
+            self.engine.last_usage = previous_usage;
+        }
+        if let Ok(answer) = &result {
+            self.turn_usage.push(usage.unwrap_or_default());
+            self.last_answer = Some(answer.clone());
+            self.scrollback.push(input, answer);
+            // Only time spent waiting on the LLM, not the typewriter; cache hits have none
+            if let Some(latency) = self.engine.last_latency {
+                self.turn_latency.push(latency);
+                if self.show_latency && self.output_format == OutputFormat::Text {
+                    let label = format!("({:.1}s)", latency.as_secs_f32());
+                    self.output.write_line(&label.dimmed().to_string());
+                }
+            }
+        }
+        result
+    }
+
+    // Function to answer a prompt interactively with a spinner and typewriter or streamed
+    // output, or as a plain JSON line when --format json is set
+    async fn answer(&mut self, input: &str) -> Result<String, AppError> {
+        self.last_input = Some(input.to_string());
+
+        if self.output_format == OutputFormat::Json {
+            let output = self.engine.ask(input).await?;
+            self.warn_if_off_schema(&output);
+            self.print_json(input, &output)?;
+            return Ok(output);
+        }
+
+        print_user_label(input, self.output.as_mut());
+        if self.verbose {
+            print_knowledge_summary(&self.engine, self.output.as_mut());
+        }
+
+        let spinner = self.output.show_spinner("Asking...");
+
+        let output = if self.streaming {
+            process_with_llm_streaming(
+                input,
+                &mut self.engine,
+                self.active.clone(),
+                spinner,
+                self.output.as_mut(),
+            )
+            .await?
+        } else {
+            process_with_llm(
+                input,
+                &mut self.engine,
+                self.active.clone(),
+                self.typewriter,
+                self.render_markdown,
+                spinner,
+                self.output.as_mut(),
+            )
+            .await?
+        };
+        if self.verbose {
+            if let Some(preamble) = &self.engine.trimmed_preamble {
+                let note = format!("(trimmed preamble: {})", preamble);
+                self.output.write_line(&note.dimmed().to_string());
+            }
+        }
+        self.warn_if_off_schema(&output);
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineConfig;
+    use crate::llm::{LlmBackend, SamplingOptions};
+    use crate::mock::MockLlm;
+    use crate::schema::ResponseSchema;
+    use async_trait::async_trait;
+    use futures::stream::{self, Stream};
+    use langchain_rust::language_models::llm::LLM;
+    use langchain_rust::language_models::{GenerateResult, LLMError};
+    use langchain_rust::schemas::{Message, StreamData};
+    use std::pin::Pin;
+    use std::sync::Mutex;
+
+    // Output keeping everything written, for asserting on what a session shows
+    #[derive(Clone, Default)]
+    struct CaptureOutput {
+        chunks: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Output for CaptureOutput {
+        fn write_chunk(&mut self, text: &str) {
+            self.chunks.lock().unwrap().push(text.to_string());
+        }
+
+        fn write_line(&mut self, text: &str) {
+            self.chunks.lock().unwrap().push(format!("{}\n", text));
+        }
+    }
+
+    // Function to create a session on the mock backend that types instantly into a capture
+    fn mock_session(mock: MockLlm) -> (Session, CaptureOutput) {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let engine = ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(mock))).unwrap();
+        let mut session = Session::new(engine, Arc::new(AtomicBool::new(true)));
+        let output = CaptureOutput::default();
+        session.output = Box::new(output.clone());
+        session.typewriter.delay_ms = 0;
+        (session, output)
+    }
+
+    // Backend that never answers in time, for exercising LLM_TIMEOUT_SECS
+    #[derive(Clone)]
+    struct HangingLlm;
+
+    #[async_trait]
+    impl LLM for HangingLlm {
+        async fn generate(&self, _messages: &[Message]) -> Result<GenerateResult, LLMError> {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Err(LLMError::OtherError("too late".to_string()))
+        }
+
+        async fn stream(
+            &self,
+            _messages: &[Message],
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError>
+        {
+            Ok(Box::pin(stream::empty()))
+        }
+    }
+
+    // Function to create a session whose requests time out after a few milliseconds
+    fn timing_out_session() -> Session {
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        config.request_timeout = Duration::from_millis(50);
+        let engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(HangingLlm))).unwrap();
+        let mut session = Session::new(engine, Arc::new(AtomicBool::new(true)));
+        session.output = Box::new(CaptureOutput::default());
+        session
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_prompt_leaves_no_turn_behind() {
+        for format in [OutputFormat::Text, OutputFormat::Json] {
+            let mut session = timing_out_session();
+            session.output_format = format;
+
+            let result = session.ask("hello").await;
+
+            assert!(matches!(result, Err(AppError::Timeout(_))));
+            assert!(session.engine.history_list.is_empty());
+            assert_eq!(session.stats().turns, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn clearing_history_keeps_the_rest_of_the_session() {
+        let (mut session, _) = mock_session(MockLlm::new());
+        session.ask("hello").await.unwrap();
+        assert_eq!(session.engine.history_list.len(), 2);
+
+        session.engine.clear_history();
+
+        assert!(session.engine.history_list.is_empty());
+        assert_eq!(session.last_answer.as_deref(), Some("Mock answer: hello"));
+        assert_eq!(session.stats().turns, 1);
+    }
+
+    #[tokio::test]
+    async fn retry_sends_the_stored_last_input_again() {
+        let (mut session, _) = mock_session(MockLlm::new());
+        assert_eq!(session.last_input, None);
+        session.ask("hello").await.unwrap();
+
+        // What .retry does: send the stored input again as a new turn
+        let last_input = session.last_input.clone().unwrap();
+        session.ask(&last_input).await.unwrap();
+
+        let contents: Vec<&str> = session
+            .engine
+            .history_list
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            ["hello", "Mock answer: hello", "hello", "Mock answer: hello"]
+        );
+    }
+
+    #[tokio::test]
+    async fn each_turn_prints_its_labels_once() {
+        let (mut session, output) = mock_session(MockLlm::new());
+        session.ask("first").await.unwrap();
+        session.ask("second").await.unwrap();
+
+        let shown = output.chunks.lock().unwrap().concat();
+        assert_eq!(shown.matches("Assistant:").count(), 2);
+        assert_eq!(shown.matches("You:").count(), 2);
+        let first = shown.find("Mock answer: first").unwrap();
+        let second_label = shown.rfind("Assistant:").unwrap();
+        assert!(first < second_label);
+    }
+
+    #[test]
+    fn stats_count_the_turns_and_sum_their_tokens() {
+        let (mut session, _) = mock_session(MockLlm::new());
+        session.turn_usage.push(Usage {
+            prompt_tokens: 100,
+            completion_tokens: 20,
+            total_tokens: 120,
+            estimated: false,
+        });
+        session
+            .turn_usage
+            .push(Usage::estimate(50, "four words of answer"));
+
+        let stats = session.stats();
+
+        assert_eq!(stats.turns, 2);
+        assert_eq!(stats.usage.prompt_tokens, 150);
+        assert_eq!(
+            stats.usage.total_tokens,
+            120 + session.turn_usage[1].total_tokens
+        );
+        assert!(stats.usage.estimated);
+        assert_eq!(stats.model, session.engine.model);
+    }
+
+    #[tokio::test]
+    async fn verbose_output_and_warnings_reach_the_output_in_order() {
+        let (mut session, output) = mock_session(MockLlm::new());
+        session.verbose = true;
+        session.ask("hello").await.unwrap();
+        session.engine.schema = Some(ResponseSchema::Pii);
+        session.warn_if_off_schema("not json");
+
+        let chunks = output.chunks.lock().unwrap().clone();
+        let position = |needle: &str| {
+            chunks
+                .iter()
+                .position(|chunk| chunk.contains(needle))
+                .unwrap_or_else(|| panic!("{:?} not written", needle))
+        };
+        assert!(position("You:") < position("Knowledge:"));
+        assert!(position("Knowledge:") < position("Assistant:"));
+        assert!(position("Assistant:") < position("answer:"));
+        assert!(position("answer:") < position("Warning:"));
+    }
+
+    #[tokio::test]
+    async fn saved_settings_load_back_unchanged() {
+        let (mut session, _) = mock_session(MockLlm::new());
+        session.engine.sampling = SamplingOptions {
+            temperature: Some(0.3),
+            max_tokens: Some(512),
+        };
+        session.engine.language = Some("Korean".to_string());
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("saved.toml");
+
+        let saved = session.current_config();
+        saved.save(&path).unwrap();
+        let loaded = AppConfig::load(&path).unwrap();
+
+        assert_eq!(loaded, saved);
+        assert_eq!(loaded.temperature, Some(0.3));
+        assert_eq!(loaded.max_tokens, Some(512));
+        assert_eq!(loaded.typewriter_delay_ms, Some(0));
+        assert_eq!(loaded.language.as_deref(), Some("Korean"));
+        assert!(loaded
+            .api_key
+            .as_deref()
+            .is_none_or(|key| key == API_KEY_REFERENCE));
+    }
+
+    #[tokio::test]
+    async fn timing_a_prompt_records_a_sample_per_run() {
+        let (mut session, _) = mock_session(MockLlm::new());
+        session.ask("hello").await.unwrap();
+        let history = session.engine.history_list.len();
+
+        let samples = session.benchmark(3, "ping").await.unwrap();
+
+        assert_eq!(samples.len(), 3);
+        // The timed answers are discarded
+        assert_eq!(session.engine.history_list.len(), history);
+    }
+
+    #[tokio::test]
+    async fn picked_sources_are_combined_once_each_and_none_clears() {
+        let (mut session, _) = mock_session(MockLlm::new());
+        let dir = tempfile::TempDir::new().unwrap();
+        let pii = dir.path().join("pii.json");
+        let mq = dir.path().join("mq.json");
+        std::fs::write(&pii, r#"[{"field": "email"}]"#).unwrap();
+        std::fs::write(&mq, r#"[{"topic": "orders.created"}]"#).unwrap();
+        let pii = pii.to_string_lossy().into_owned();
+        let mq = mq.to_string_lossy().into_owned();
+
+        session
+            .switch_knowledge(&[pii.clone(), mq.clone(), pii.clone()])
+            .await
+            .unwrap();
+
+        assert_eq!(session.engine.knowledge_sources, [pii, mq]);
+        assert!(session.engine.knowledge.contains("email"));
+        assert!(session.engine.knowledge.contains("orders.created"));
+
+        session.switch_knowledge(&[]).await.unwrap();
+
+        assert!(session.engine.knowledge_sources.is_empty());
+        assert!(session.engine.knowledge.is_empty());
+    }
+}
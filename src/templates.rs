@@ -0,0 +1,141 @@
+use crate::error::AppError;
+use langchain_rust::prompt::{PromptArgs, PromptFromatter, PromptTemplate, TemplateFormat};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Template file read when TEMPLATES_FILE is not set
+pub const DEFAULT_TEMPLATES_FILE: &str = "templates.toml";
+
+// Named prompt templates (.template), each a string with {input} and/or {name} placeholders
+#[derive(Debug, Default)]
+pub struct TemplateLibrary {
+    templates: BTreeMap<String, String>,
+}
+
+impl TemplateLibrary {
+    // Function to read templates from a TOML file of `name = "template"` pairs; a missing
+    // file gives an empty library
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let templates = toml::from_str(&content).map_err(|e| {
+            AppError::Config(format!("Invalid templates {}: {}", path.display(), e))
+        })?;
+        Ok(TemplateLibrary { templates })
+    }
+
+    // Function to read the templates from TEMPLATES_FILE (default templates.toml)
+    pub fn from_env() -> Result<Self, AppError> {
+        let path =
+            std::env::var("TEMPLATES_FILE").unwrap_or_else(|_| DEFAULT_TEMPLATES_FILE.to_string());
+        Self::load(Path::new(&path))
+    }
+
+    // Function to list the templates by name, in name order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.templates
+            .iter()
+            .map(|(name, template)| (name.as_str(), template.as_str()))
+    }
+
+    // Function to fill in a template: `key=value` arguments set named placeholders and the
+    // remaining words, joined, set {input}
+    pub fn expand(&self, name: &str, args: &str) -> Result<String, String> {
+        let template = self.templates.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.templates.keys().map(String::as_str).collect();
+            format!(
+                "unknown template '{}', expected one of: {}",
+                name,
+                known.join(", ")
+            )
+        })?;
+        let variables = placeholders(template);
+
+        let mut values = PromptArgs::new();
+        let mut input = Vec::new();
+        for word in args.split_whitespace() {
+            match word.split_once('=') {
+                Some((key, value)) if key != "input" && variables.iter().any(|v| v == key) => {
+                    values.insert(key.to_string(), Value::String(value.to_string()));
+                }
+                _ => input.push(word),
+            }
+        }
+        if variables.iter().any(|v| v == "input") {
+            values.insert("input".to_string(), Value::String(input.join(" ")));
+        }
+
+        PromptTemplate::new(template.clone(), variables, TemplateFormat::FString)
+            .format(values)
+            .map_err(|e| format!("template '{}': {}", name, e))
+    }
+}
+
+// Function to find the {name} placeholders of a template, in order and without repeats
+fn placeholders(template: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let name = &rest[..end];
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if valid && !names.iter().any(|known| known == name) {
+            names.push(name.to_string());
+        }
+        rest = &rest[end + 1..];
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Function to load a library from TOML written to a temporary file
+    fn library(toml: &str) -> TemplateLibrary {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("templates.toml");
+        fs::write(&path, toml).unwrap();
+        TemplateLibrary::load(&path).unwrap()
+    }
+
+    #[test]
+    fn a_template_is_filled_with_one_argument() {
+        let library = library(
+            r#"
+classify = "Is the field {input} PII? Answer yes or no."
+translate = "Translate into {lang}: {input}"
+"#,
+        );
+
+        assert_eq!(
+            library.expand("classify", "email").unwrap(),
+            "Is the field email PII? Answer yes or no."
+        );
+        assert_eq!(
+            library
+                .expand("translate", "lang=Korean good morning")
+                .unwrap(),
+            "Translate into Korean: good morning"
+        );
+    }
+
+    #[test]
+    fn an_unknown_template_names_the_known_ones() {
+        let library = library("classify = \"{input}\"\nsummarize = \"{input}\"\n");
+
+        assert_eq!(
+            library.expand("clasify", "email").unwrap_err(),
+            "unknown template 'clasify', expected one of: classify, summarize"
+        );
+    }
+}
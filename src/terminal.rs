@@ -0,0 +1,927 @@
+use crate::data::KnowledgeEntry;
+use crate::engine::{role_label, ChatEngine};
+use crate::error::AppError;
+use crate::highlight::{self, Segment};
+use crate::output::Output;
+use crate::retry::RetryWait;
+use crate::schema::JsonAccumulator;
+use crate::tokens::Usage;
+use crate::{data, interrupt, markdown};
+use colored::Colorize;
+use dialoguer::{theme::ColorfulTheme, MultiSelect, Select};
+use futures::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use langchain_rust::schemas::Message;
+use log::debug;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+// Label printed before each answer
+const ASSISTANT_LABEL: &str = "Assistant:";
+
+// Appended to an answer cut short by Ctrl-C, on screen and in the history
+const INTERRUPTED_MARKER: &str = "[interrupted]";
+
+// Function to echo the prompt with a dim label before it is answered
+pub fn print_user_label(input: &str, output: &mut dyn Output) {
+    output.write_line(&format!("{} {}", "You:".dimmed(), input.dimmed()));
+}
+
+// Function to apply the colour setting once at startup; NO_COLOR (any non-empty value)
+// or `plain` turns colour off for all output
+pub fn init_color(plain: bool) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    if plain || no_color {
+        colored::control::set_override(false);
+    }
+}
+
+// Function to check whether output should be coloured
+pub fn color_enabled() -> bool {
+    colored::control::SHOULD_COLORIZE.should_colorize()
+}
+
+// Whether progress is shown as an animated spinner; off for --no-spinner or when stdout
+// isn't a terminal, where the animation's control characters garble logs
+static SPINNER_ENABLED: AtomicBool = AtomicBool::new(true);
+
+// Function to apply the spinner setting once at startup
+pub fn init_spinner(disabled: bool) {
+    let enabled = !disabled && io::stdout().is_terminal();
+    SPINNER_ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+// Function to check whether progress is shown as an animated spinner
+pub fn spinner_enabled() -> bool {
+    SPINNER_ENABLED.load(Ordering::SeqCst)
+}
+
+// Function to create a spinner (Refactor spinner creation); with the spinner disabled a
+// hidden one is returned and no tick thread is started
+pub fn create_spinner(message: &str) -> ProgressBar {
+    if !spinner_enabled() {
+        return ProgressBar::hidden();
+    }
+    let template = if color_enabled() {
+        "{spinner:.green} {msg}"
+    } else {
+        "{spinner} {msg}"
+    };
+    let spinner = ProgressBar::new_spinner();
+    set_stage(&spinner, message);
+    spinner.set_style(
+        ProgressStyle::with_template(template)
+            .unwrap()
+            .tick_strings(&["|", "/", "-", "\\", "|", "/", "-", "\\"]),
+    );
+    spinner.enable_steady_tick(Duration::from_millis(120));
+    spinner
+}
+
+// Function to update the spinner message for the current stage of a request
+pub fn set_stage(spinner: &ProgressBar, message: &str) {
+    spinner.set_message(format!("{} {}", "💡".blue(), message));
+}
+
+// Function to handle the LLM chain execution and processing (Refactor LLM logic)
+pub async fn process_with_llm(
+    input: &str,
+    engine: &mut ChatEngine,
+    running: Arc<AtomicBool>,
+    typewriter_options: TypewriterOptions,
+    render_markdown: bool,
+    spinner: ProgressBar,
+    output: &mut dyn Output,
+) -> Result<String, AppError> {
+    let on_retry = |wait: &RetryWait| {
+        let reason = if wait.rate_limited {
+            "Rate limited"
+        } else {
+            "Request failed"
+        };
+        set_stage(
+            &spinner,
+            &format!(
+                "{}, retrying in {}s (attempt {}/{})",
+                reason,
+                wait.remaining.as_secs_f32().ceil(),
+                wait.attempt,
+                wait.max_retries
+            ),
+        );
+    };
+    let res = tokio::select! {
+        res = engine.complete_with_progress(input, on_retry) => res,
+        _ = interrupt::cancelled(&running) => Err(AppError::Interrupted),
+    };
+
+    spinner.finish_and_clear();
+
+    let result = res?;
+    if render_markdown && markdown::has_markdown(&result) {
+        output.write_line(&ASSISTANT_LABEL.bold().cyan().to_string());
+        output.write_chunk(&markdown::render_markdown(&result));
+    } else {
+        typewriter(
+            &result,
+            typewriter_options,
+            running,
+            Some(ASSISTANT_LABEL),
+            output,
+        );
+    }
+    Ok(result)
+}
+
+// Function to handle the LLM chain execution, printing chunks as they arrive
+pub async fn process_with_llm_streaming(
+    input: &str,
+    engine: &mut ChatEngine,
+    running: Arc<AtomicBool>,
+    spinner: ProgressBar,
+    output: &mut dyn Output,
+) -> Result<String, AppError> {
+    if let Err(e) = engine.retrieve_knowledge(input).await {
+        spinner.finish_and_clear();
+        return Err(e);
+    }
+    let prompt_tokens = engine.estimate_prompt_tokens(input);
+    let started = Instant::now();
+    let stream = engine.stream(input).await;
+
+    let mut stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            spinner.finish_and_clear();
+            return Err(e);
+        }
+    };
+    // With --schema the JSON is collected behind the spinner and shown once complete,
+    // rather than printed as raw fragments
+    let mut json = engine.schema.map(|_| JsonAccumulator::default());
+    let stage = if json.is_some() {
+        "Receiving JSON..."
+    } else {
+        "Streaming..."
+    };
+    set_stage(&spinner, stage);
+
+    let mut result = String::new();
+    let mut interrupted = false;
+    loop {
+        let chunk = tokio::select! {
+            chunk = stream.next() => chunk,
+            _ = interrupt::cancelled(&running) => {
+                interrupted = true;
+                break;
+            }
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        let data = match chunk {
+            Ok(data) => data,
+            Err(e) => {
+                spinner.finish_and_clear();
+                return Err(e.into());
+            }
+        };
+        result.push_str(&data.content);
+        if let Some(json) = json.as_mut() {
+            json.push(&data.content);
+            if json.is_complete() {
+                set_stage(&spinner, "JSON received");
+            }
+            continue;
+        }
+        // The spinner shares the line with the answer, so it goes once text arrives
+        if !spinner.is_finished() {
+            spinner.finish_and_clear();
+            output.write_chunk(&format!("{} ", ASSISTANT_LABEL.bold().cyan()));
+        }
+        output.write_chunk(&data.content.yellow().to_string());
+    }
+    spinner.finish_and_clear();
+    engine.last_latency = Some(started.elapsed());
+    if let Some(json) = &json {
+        let text = match json.parse() {
+            Some(Ok(value)) => serde_json::to_string_pretty(&value)?,
+            // Left to the schema warning; show what arrived
+            _ => json.text().to_string(),
+        };
+        output.write_chunk(&format!("{} ", ASSISTANT_LABEL.bold().cyan()));
+        output.write_chunk(&text.yellow().to_string());
+    }
+
+    // Streamed chunks carry no usage, so the counts are estimated
+    engine.record_usage(Usage::estimate(prompt_tokens, &result));
+    if interrupted {
+        // Keep what arrived so the conversation still reads coherently, marked as partial
+        output.write_line(&format!(" {}", INTERRUPTED_MARKER.dimmed()));
+        let partial = format!("{}\n{}", result, INTERRUPTED_MARKER);
+        engine.push_turn(input, Message::new_ai_message(partial));
+        return Err(AppError::Interrupted);
+    }
+    output.write_line("");
+    if result.trim().is_empty() {
+        return Err(AppError::EmptyResponse);
+    }
+
+    engine.push_turn(input, Message::new_ai_message(&result));
+    engine.audit(input, &result);
+    Ok(result)
+}
+
+// Function to put text on the system clipboard; headless systems report an error
+pub fn copy_to_clipboard(text: &str) -> Result<(), AppError> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .map_err(|e| AppError::Terminal(format!("Clipboard unavailable: {}", e)))
+}
+
+// Function to warn when entries were left out of the knowledge to fit KNOWLEDGE_MAX_CHARS
+pub fn warn_if_knowledge_truncated(engine: &ChatEngine, output: &mut dyn Output) {
+    if engine.knowledge_omitted > 0 {
+        let warning = format!(
+            "Warning: {} knowledge entries omitted to respect KNOWLEDGE_MAX_CHARS",
+            engine.knowledge_omitted
+        );
+        output.write_warning(&warning.yellow().to_string());
+    }
+}
+
+// Function to print a one-line summary of the active knowledge (--verbose)
+pub fn print_knowledge_summary(engine: &ChatEngine, output: &mut dyn Output) {
+    let summary = match engine.knowledge_source() {
+        Some(source) => format!(
+            "Knowledge: {} ({} chars, {} lines)",
+            source,
+            engine.knowledge.chars().count(),
+            engine.knowledge.lines().count()
+        ),
+        None => "Knowledge: none".to_string(),
+    };
+    output.write_line(&summary.dimmed().to_string());
+}
+
+// Function to list the active knowledge sources with their entry counts (.sources);
+// sources whose files have gone missing are greyed out
+pub fn print_knowledge_sources(engine: &ChatEngine, output: &mut dyn Output) {
+    if engine.knowledge_sources.is_empty() {
+        output.write_line(&"No knowledge loaded".yellow().to_string());
+        return;
+    }
+    for source in &engine.knowledge_sources {
+        if !data::is_url(source) && !Path::new(source).exists() {
+            output.write_line(&format!("{} (missing)", source).dimmed().to_string());
+            continue;
+        }
+        let line = match data::count_entries(source, &engine.fetched_urls) {
+            Ok(count) => format!("{}, {} entries", source, count).cyan(),
+            Err(e) => format!("{} ({})", source, e).red(),
+        };
+        output.write_line(&line.to_string());
+    }
+}
+
+// Function to let the user scroll through knowledge entries and open one in full (.browse);
+// Esc or q returns to the prompt. Read-only.
+pub fn browse_knowledge(
+    entries: &[KnowledgeEntry],
+    output: &mut dyn Output,
+) -> Result<(), AppError> {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let label = entry.label.replace('\n', " ");
+            let label = match label.char_indices().nth(60) {
+                Some((end, _)) => format!("{}...", &label[..end]),
+                None => label,
+            };
+            format!("{}: {}", entry.file, label)
+        })
+        .collect();
+    let mut selected = 0;
+    loop {
+        let choice = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Knowledge entries (Enter to view, Esc to leave)")
+            .items(&items)
+            .default(selected)
+            .max_length(15)
+            .interact_opt()
+            .map_err(|e| AppError::Terminal(e.to_string()))?;
+        let Some(index) = choice else {
+            return Ok(());
+        };
+        selected = index;
+        let entry = &entries[index];
+        output.page(&format!(
+            "===== {} =====\n{}\n",
+            entry.file,
+            serde_json::to_string_pretty(&entry.value)?
+        ));
+    }
+}
+
+// Function to list the knowledge picker's choices: the available sources, then any active
+// source not among them (a URL or a file outside KNOWLEDGE_DIR), each checked when active
+pub fn knowledge_choices(available: &[String], active: &[String]) -> Vec<(String, bool)> {
+    let mut choices: Vec<(String, bool)> = available
+        .iter()
+        .map(|source| (source.clone(), active.contains(source)))
+        .collect();
+    for source in active {
+        if !available.contains(source) {
+            choices.push((source.clone(), true));
+        }
+    }
+    choices
+}
+
+// Function to let the user tick the knowledge sources to combine (.knowledge), with the
+// active ones pre-checked; Esc keeps the current knowledge and returns None
+pub fn pick_knowledge_sources(
+    available: &[String],
+    active: &[String],
+) -> Result<Option<Vec<String>>, AppError> {
+    let choices = knowledge_choices(available, active);
+    let items: Vec<&str> = choices.iter().map(|(source, _)| source.as_str()).collect();
+    let checked: Vec<bool> = choices.iter().map(|(_, checked)| *checked).collect();
+    let picked = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Knowledge sources (Space to toggle, Enter to apply, Esc to cancel)")
+        .items(&items)
+        .defaults(&checked)
+        .max_length(15)
+        .interact_opt()
+        .map_err(|e| AppError::Terminal(e.to_string()))?;
+    Ok(picked.map(|indexes| {
+        indexes
+            .into_iter()
+            .map(|index| choices[index].0.clone())
+            .collect()
+    }))
+}
+
+// Function to print a long text through PAGER (default less) when stdout is a terminal
+// and the text is taller than it; otherwise, or if the pager can't start, print it directly.
+// This is how StdoutOutput pages; other code goes through Output::page.
+pub fn page_text(text: &str) {
+    let rows = std::env::var("LINES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(40);
+    if io::stdout().is_terminal() && text.lines().count() > rows {
+        let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+        let child = Command::new(&pager).arg("-R").stdin(Stdio::piped()).spawn();
+        if let Ok(mut child) = child {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The user quitting the pager early closes the pipe, which is fine
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            let _ = child.wait();
+            return;
+        }
+        debug!("Could not start pager {}, printing directly", pager);
+    }
+    println!("{}", text);
+}
+
+// Function to print knowledge checks as a table of file, status and detail; returns
+// whether every file loaded
+pub fn print_source_checks(checks: &[data::SourceCheck], output: &mut dyn Output) -> bool {
+    let width = checks
+        .iter()
+        .map(|check| check.path.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("FILE".len());
+    output.write_line(&format!(
+        "{:<width$}  {:<6}  DETAIL",
+        "FILE",
+        "STATUS",
+        width = width
+    ));
+    let mut all_ok = true;
+    for check in checks {
+        let path = format!("{:<width$}", check.path, width = width);
+        match &check.result {
+            Ok(count) => output.write_line(&format!(
+                "{}  {}  {} entries",
+                path,
+                format!("{:<6}", "OK").green(),
+                count
+            )),
+            Err(e) => {
+                all_ok = false;
+                output.write_line(&format!("{}  {}  {}", path, "FAILED".red(), e));
+            }
+        }
+    }
+    all_ok
+}
+
+// Function to print assembled messages with role labels (dry run)
+pub fn print_messages(messages: &[Message], output: &mut dyn Output) {
+    for message in messages {
+        let label = format!("[{}]", role_label(&message.message_type));
+        output.write_line(&label.cyan().to_string());
+        output.write_line(message.content.trim_end());
+        output.write_line("");
+    }
+}
+
+// How the typewriter prints: the delay per tick and, when set, how many whole grapheme
+// clusters to print per tick instead of one char
+#[derive(Debug, Clone, Copy)]
+pub struct TypewriterOptions {
+    pub delay_ms: u64,
+    pub graphemes_per_tick: Option<usize>,
+}
+
+impl TypewriterOptions {
+    // Function to read TYPEWRITER_DELAY_MS and TYPEWRITER_GRAPHEMES
+    pub fn from_env() -> Self {
+        TypewriterOptions {
+            delay_ms: typewriter_delay_from_env(),
+            graphemes_per_tick: std::env::var("TYPEWRITER_GRAPHEMES")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|count| *count > 0),
+        }
+    }
+}
+
+// Function to read the typewriter delay from TYPEWRITER_DELAY_MS (0 prints instantly)
+pub fn typewriter_delay_from_env() -> u64 {
+    std::env::var("TYPEWRITER_DELAY_MS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(50)
+}
+
+// Function to split text into the pieces printed per tick: single chars by default, or
+// groups of whole grapheme clusters so combining marks and emoji sequences stay intact
+pub fn typewriter_ticks(text: &str, graphemes_per_tick: Option<usize>) -> Vec<String> {
+    match graphemes_per_tick {
+        Some(count) => text
+            .graphemes(true)
+            .collect::<Vec<_>>()
+            .chunks(count.max(1))
+            .map(|clusters| clusters.concat())
+            .collect(),
+        None => text.chars().map(String::from).collect(),
+    }
+}
+
+// Function to display typing effect, optionally after a role label (Already refactored);
+// with colours on, fenced code blocks are syntax highlighted and printed whole
+pub fn typewriter(
+    text: &str,
+    options: TypewriterOptions,
+    running: Arc<AtomicBool>,
+    label: Option<&str>,
+    output: &mut dyn Output,
+) {
+    // Column the cursor is at, for wrapping prose to the terminal width
+    let mut column = 0;
+    if let Some(label) = label {
+        output.write_chunk(&format!("{} ", label.bold().cyan()));
+        column = label.width() + 1;
+    }
+
+    let segments = if color_enabled() {
+        highlight::split_code_blocks(text)
+    } else {
+        vec![Segment::Prose(text.to_string())]
+    };
+    for segment in segments {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+        match segment {
+            Segment::Prose(prose) => type_prose(&prose, options, &running, output, &mut column),
+            Segment::Code { language, code } => {
+                output.write_chunk(&highlight::render_code_block(&code, &language));
+                column = 0;
+            }
+        }
+    }
+    output.write_line("");
+}
+
+// Function to read the width of the terminal stdout is attached to (None when it isn't one)
+pub fn terminal_width() -> Option<usize> {
+    terminal_size::terminal_size().map(|(width, _)| width.0 as usize)
+}
+
+// Function to lay out one word, with the whitespace after it, starting at `column` on a
+// line `width` columns wide: the word moves to a new line when it doesn't fit, a word
+// longer than a line is broken at the width, and spaces at the end of a line are dropped.
+// Returns the text to print and the column after it.
+pub fn soft_wrap(word: &str, mut column: usize, width: usize) -> (String, usize) {
+    let width = width.max(1);
+    let visible = word.trim_end();
+    let mut laid_out = String::new();
+    if column > 0 && column + visible.width() > width {
+        laid_out.push('\n');
+        column = 0;
+    }
+    for c in visible.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if column > 0 && column + char_width > width {
+            laid_out.push('\n');
+            column = 0;
+        }
+        laid_out.push(c);
+        column += char_width;
+    }
+    for c in word[visible.len()..].chars() {
+        if c == '\n' {
+            laid_out.push('\n');
+            column = 0;
+        } else if column < width {
+            laid_out.push(c);
+            column += 1;
+        }
+    }
+    (laid_out, column)
+}
+
+// Function to lay out prose word by word to fit `width` columns from `column` on; without
+// a width (not a terminal) the text is kept as is
+pub fn wrap_prose(text: &str, column: &mut usize, width: Option<usize>) -> String {
+    let Some(width) = width else {
+        return text.to_string();
+    };
+    text.split_inclusive(char::is_whitespace)
+        .map(|word| {
+            let (laid_out, next) = soft_wrap(word, *column, width);
+            *column = next;
+            laid_out
+        })
+        .collect()
+}
+
+// Function to type out prose in the answer colour, one tick at a time; on a terminal, lines
+// are broken at word boundaries to fit its width, measured again for every word so a
+// resize mid-answer is followed. Without a delay the prose is laid out once and written
+// in one go.
+fn type_prose(
+    text: &str,
+    options: TypewriterOptions,
+    running: &AtomicBool,
+    output: &mut dyn Output,
+    column: &mut usize,
+) {
+    if options.delay_ms == 0 {
+        let laid_out = wrap_prose(text, column, terminal_width());
+        output.write_chunk(&laid_out.yellow().to_string());
+        return;
+    }
+    for word in text.split_inclusive(char::is_whitespace) {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+        let word = match terminal_width() {
+            Some(width) => {
+                let (laid_out, next) = soft_wrap(word, *column, width);
+                *column = next;
+                laid_out
+            }
+            None => word.to_string(),
+        };
+
+        for tick in typewriter_ticks(&word, options.graphemes_per_tick) {
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+            output.write_chunk(&tick.yellow().to_string());
+            thread::sleep(Duration::from_millis(options.delay_ms));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineConfig;
+    use crate::llm::LlmBackend;
+    use crate::retry::RetryPolicy;
+    use async_trait::async_trait;
+    use futures::stream::{self, Stream};
+    use langchain_rust::language_models::llm::LLM;
+    use langchain_rust::language_models::{GenerateResult, LLMError};
+    use langchain_rust::schemas::{MessageType, StreamData};
+    use std::pin::Pin;
+    use std::sync::Mutex;
+
+    // Output keeping everything written, for asserting on what was shown
+    #[derive(Clone, Default)]
+    struct CaptureOutput {
+        chunks: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Output for CaptureOutput {
+        fn write_chunk(&mut self, text: &str) {
+            self.chunks.lock().unwrap().push(text.to_string());
+        }
+
+        fn write_line(&mut self, text: &str) {
+            self.chunks.lock().unwrap().push(format!("{}\n", text));
+        }
+    }
+
+    // Backend that is rate limited on its first call and notes the spinner message
+    // shown when it is called again
+    #[derive(Clone)]
+    struct RateLimitedOnceLlm {
+        spinner: ProgressBar,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LLM for RateLimitedOnceLlm {
+        async fn generate(&self, _messages: &[Message]) -> Result<GenerateResult, LLMError> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(self.spinner.message());
+            if calls.len() == 1 {
+                return Err(LLMError::OtherError("429 Too Many Requests".to_string()));
+            }
+            Ok(GenerateResult {
+                tokens: None,
+                generation: "answer".to_string(),
+            })
+        }
+
+        async fn stream(
+            &self,
+            _messages: &[Message],
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError>
+        {
+            Ok(Box::pin(stream::empty()))
+        }
+    }
+
+    // Backend that streams one chunk and then hangs, pressing Ctrl-C while it waits
+    #[derive(Clone)]
+    struct InterruptedStreamLlm {
+        running: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl LLM for InterruptedStreamLlm {
+        async fn generate(&self, _messages: &[Message]) -> Result<GenerateResult, LLMError> {
+            Err(LLMError::OtherError("streaming only".to_string()))
+        }
+
+        async fn stream(
+            &self,
+            _messages: &[Message],
+        ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError>
+        {
+            let running = self.running.clone();
+            let first = stream::iter(vec![Ok(StreamData::new(
+                serde_json::Value::Null,
+                None,
+                "The first half",
+            ))]);
+            let hang = stream::poll_fn(move |_| {
+                running.store(false, Ordering::SeqCst);
+                std::task::Poll::Pending
+            });
+            Ok(Box::pin(first.chain(hang)))
+        }
+    }
+
+    #[tokio::test]
+    async fn an_interrupted_stream_keeps_the_partial_answer() {
+        let running = Arc::new(AtomicBool::new(true));
+        let llm = InterruptedStreamLlm {
+            running: running.clone(),
+        };
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        let mut engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(llm))).unwrap();
+        let output = CaptureOutput::default();
+
+        let result = process_with_llm_streaming(
+            "hello",
+            &mut engine,
+            running,
+            ProgressBar::hidden(),
+            &mut output.clone(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(AppError::Interrupted)));
+        let last = engine.history_list.last().unwrap();
+        assert!(matches!(last.message_type, MessageType::AIMessage));
+        assert_eq!(last.content, "The first half\n[interrupted]");
+        let shown = output.chunks.lock().unwrap().concat();
+        assert!(shown.contains("The first half"));
+        assert!(shown.contains(INTERRUPTED_MARKER));
+    }
+
+    // Function to count the threads of this process named like the calling one; on Linux a
+    // thread spawned without a name inherits its parent's
+    #[cfg(target_os = "linux")]
+    fn threads_named_like_this_one() -> usize {
+        let name = std::fs::read_to_string("/proc/thread-self/comm").unwrap();
+        std::fs::read_dir("/proc/self/task")
+            .unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("comm")).ok())
+            .filter(|comm| *comm == name)
+            .count()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_disabled_spinner_starts_no_tick_thread() {
+        init_spinner(true);
+        let threads = thread::Builder::new()
+            .name("no-spinner".to_string())
+            .spawn(|| {
+                let spinner = create_spinner("Asking...");
+                let threads = threads_named_like_this_one();
+                spinner.finish_and_clear();
+                threads
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(threads, 1);
+
+        // An animated spinner does start one, so the count above would have seen it
+        let threads = thread::Builder::new()
+            .name("spinner".to_string())
+            .spawn(|| {
+                let spinner = ProgressBar::new_spinner();
+                spinner.enable_steady_tick(Duration::from_millis(100));
+                let threads = threads_named_like_this_one();
+                spinner.finish_and_clear();
+                threads
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(threads, 2);
+    }
+
+    #[tokio::test]
+    async fn the_spinner_message_follows_a_retry() {
+        let spinner = ProgressBar::hidden();
+        set_stage(&spinner, "Asking...");
+        let llm = RateLimitedOnceLlm {
+            spinner: spinner.clone(),
+            calls: Arc::default(),
+        };
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        config.retry_policy = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+        };
+        let mut engine =
+            ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(llm.clone()))).unwrap();
+        let options = TypewriterOptions {
+            delay_ms: 0,
+            graphemes_per_tick: None,
+        };
+
+        let answer = process_with_llm(
+            "hello",
+            &mut engine,
+            Arc::new(AtomicBool::new(true)),
+            options,
+            false,
+            spinner,
+            &mut CaptureOutput::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(answer, "answer");
+        let calls = llm.calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].ends_with("Asking..."));
+        assert!(calls[1].contains("Rate limited, retrying in"));
+        assert!(calls[1].contains("(attempt 1/2)"));
+    }
+
+    #[test]
+    fn grapheme_ticks_never_split_a_cluster() {
+        // Thumbs up with a skin tone, a family joined with ZWJs, and e + combining acute
+        let text = "ok \u{1F44D}\u{1F3FD} \u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467} e\u{301}";
+        let clusters: Vec<&str> = text.graphemes(true).collect();
+
+        for count in 1..=3 {
+            let ticks = typewriter_ticks(text, Some(count));
+            assert_eq!(ticks.concat(), text);
+            for tick in &ticks {
+                let in_tick = tick.graphemes(true).count();
+                assert!(in_tick <= count);
+                // Every cluster in a tick is one of the text's clusters, whole
+                assert!(tick
+                    .graphemes(true)
+                    .all(|cluster| clusters.contains(&cluster)));
+            }
+        }
+        assert!(typewriter_ticks(text, Some(1)).contains(&"\u{1F44D}\u{1F3FD}".to_string()));
+    }
+
+    #[test]
+    fn a_word_longer_than_the_line_breaks_at_the_width() {
+        assert_eq!(
+            soft_wrap("abcdefghij", 0, 4),
+            ("abcd\nefgh\nij".to_string(), 2)
+        );
+        // Not at the start of a line: the word moves down first
+        assert_eq!(
+            soft_wrap("abcdefghij", 3, 4),
+            ("\nabcd\nefgh\nij".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn prose_is_wrapped_at_word_boundaries() {
+        let mut column = 0;
+        let wrapped = wrap_prose("one two three four", &mut column, Some(9));
+        assert_eq!(wrapped, "one two \nthree \nfour");
+        assert_eq!(column, 4);
+
+        let mut column = 0;
+        assert_eq!(wrap_prose("one two", &mut column, None), "one two");
+    }
+
+    #[test]
+    fn without_a_delay_the_prose_is_written_once() {
+        let output = CaptureOutput::default();
+        let options = TypewriterOptions {
+            delay_ms: 0,
+            graphemes_per_tick: None,
+        };
+
+        typewriter(
+            "several words of a plain answer",
+            options,
+            Arc::new(AtomicBool::new(true)),
+            None,
+            &mut output.clone(),
+        );
+
+        let chunks = output.chunks.lock().unwrap().clone();
+        assert_eq!(chunks.len(), 2, "{:?}", chunks);
+        assert!(chunks[0].contains("several words of a plain answer"));
+        assert_eq!(chunks[1], "\n");
+    }
+
+    #[test]
+    fn dry_run_messages_go_to_the_output_with_their_roles() {
+        let output = CaptureOutput::default();
+        let messages = [
+            Message::new_system_message("Be brief."),
+            Message::new_human_message("hello\n"),
+        ];
+
+        print_messages(&messages, &mut output.clone());
+
+        let shown = output.chunks.lock().unwrap().concat();
+        let expected = format!(
+            "{}\nBe brief.\n\n{}\nhello\n\n",
+            "[system]".cyan(),
+            "[user]".cyan()
+        );
+        assert_eq!(shown, expected);
+    }
+
+    #[test]
+    fn active_sources_are_checked_and_kept_in_the_choices() {
+        let available = [
+            "dataset/mq.json".to_string(),
+            "dataset/pii.json".to_string(),
+        ];
+        let active = [
+            "dataset/pii.json".to_string(),
+            "https://example.com/extra.json".to_string(),
+        ];
+
+        let choices = knowledge_choices(&available, &active);
+
+        assert_eq!(
+            choices,
+            [
+                ("dataset/mq.json".to_string(), false),
+                ("dataset/pii.json".to_string(), true),
+                ("https://example.com/extra.json".to_string(), true),
+            ]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use langchain_rust::language_models::TokenUsage;
+use langchain_rust::schemas::Message;
+use log::warn;
+use std::fmt;
+
+// Token counts for one exchange or a running total; `estimated` is set when any part
+// came from the local estimator because the backend returned no usage
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+    pub estimated: bool,
+}
+
+impl Usage {
+    // Function to take the counts reported by the backend
+    pub fn from_token_usage(tokens: &TokenUsage) -> Self {
+        Usage {
+            prompt_tokens: tokens.prompt_tokens as usize,
+            completion_tokens: tokens.completion_tokens as usize,
+            total_tokens: tokens.total_tokens as usize,
+            estimated: false,
+        }
+    }
+
+    // Function to estimate the counts locally from the prompt estimate and the answer
+    pub fn estimate(prompt_tokens: usize, output: &str) -> Self {
+        let completion_tokens = estimate_tokens(output);
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            estimated: true,
+        }
+    }
+
+    // Function to add another exchange to a running total
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.estimated |= other.estimated;
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "prompt {}, completion {}, total {}",
+            self.prompt_tokens, self.completion_tokens, self.total_tokens
+        )?;
+        if self.estimated {
+            write!(f, " (estimated)")?;
+        }
+        Ok(())
+    }
+}
+
+// Function to estimate the token count of a text (roughly 4 characters per token)
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+// Function to estimate the size of a request: system prompt, knowledge, history and input
+pub fn estimate_prompt_tokens(
+    system_prompt: &str,
+    knowledge: &str,
+    history: &[Message],
+    input: &str,
+) -> usize {
+    let history_tokens: usize = history
+        .iter()
+        .map(|message| estimate_tokens(&message.content))
+        .sum();
+    estimate_tokens(system_prompt)
+        + estimate_tokens(knowledge)
+        + history_tokens
+        + estimate_tokens(input)
+}
+
+// Context windows of known models and Azure deployment names, matched by prefix (more
+// specific names first)
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-35-turbo-16k", 16_384),
+    ("gpt-35-turbo", 4_096),
+    ("gpt-3.5-turbo", 16_385),
+];
+
+// Window assumed for models missing from CONTEXT_WINDOWS
+const DEFAULT_CONTEXT_WINDOW: usize = 4_096;
+
+// Tokens kept free for the answer when LLM_MAX_TOKENS isn't set
+const DEFAULT_COMPLETION_RESERVE: usize = 1_024;
+
+// Function to look up the context window of a model by name
+pub fn context_window(model: &str) -> Option<usize> {
+    let model = model.to_lowercase();
+    CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, window)| *window)
+}
+
+// Function to pick the prompt token threshold: MAX_PROMPT_TOKENS when set, otherwise the
+// model's context window less the room reserved for the completion (max_tokens), but
+// never under half the window
+pub fn max_prompt_tokens_for(model: &str, max_tokens: Option<u32>) -> usize {
+    if let Some(max) = std::env::var("MAX_PROMPT_TOKENS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+    {
+        return max;
+    }
+    window_budget(model, max_tokens)
+}
+
+// Function to size the prompt budget from the model's context window, less the completion
+// reserve
+fn window_budget(model: &str, max_tokens: Option<u32>) -> usize {
+    let window = context_window(model).unwrap_or_else(|| {
+        warn!(
+            "Unknown context window for model {}, assuming {} tokens (set MAX_PROMPT_TOKENS)",
+            model, DEFAULT_CONTEXT_WINDOW
+        );
+        DEFAULT_CONTEXT_WINDOW
+    });
+    let reserve = max_tokens.map_or(DEFAULT_COMPLETION_RESERVE, |max| max as usize);
+    window.saturating_sub(reserve).max(window / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Function to check an estimate lies within `tolerance` tokens of the expected count
+    fn assert_close(actual: usize, expected: usize, tolerance: usize) {
+        assert!(
+            actual.abs_diff(expected) <= tolerance,
+            "estimated {} tokens, expected {} ± {}",
+            actual,
+            expected,
+            tolerance
+        );
+    }
+
+    #[test]
+    fn estimates_known_strings_within_tolerance() {
+        assert_eq!(estimate_tokens(""), 0);
+        // Counts from the cl100k tokenizer
+        assert_close(estimate_tokens("Hello, world!"), 4, 1);
+        assert_close(
+            estimate_tokens("The quick brown fox jumps over the lazy dog."),
+            10,
+            2,
+        );
+        assert_close(
+            estimate_tokens("Personally identifiable information includes names, e-mail addresses and phone numbers."),
+            15,
+            8,
+        );
+    }
+
+    #[test]
+    fn prompt_estimate_sums_every_part() {
+        let history = vec![
+            Message::new_human_message("abcd"),
+            Message::new_ai_message("abcdefgh"),
+        ];
+        assert_eq!(
+            estimate_prompt_tokens("abcd", "abcdabcd", &history, "ab"),
+            1 + 2 + 1 + 2 + 1
+        );
+    }
+
+    #[test]
+    fn the_window_is_chosen_by_model_name() {
+        let cases = [
+            ("gpt-4", Some(8_192)),
+            ("gpt-4-0613", Some(8_192)),
+            ("gpt-4-32k", Some(32_768)),
+            ("GPT-4o", Some(128_000)),
+            ("gpt-4o-mini", Some(128_000)),
+            ("gpt-35-turbo", Some(4_096)),
+            ("gpt-35-turbo-16k", Some(16_384)),
+            ("llama3", None),
+        ];
+        for (model, window) in cases {
+            assert_eq!(context_window(model), window, "{}", model);
+        }
+    }
+
+    #[test]
+    fn the_budget_reserves_room_for_the_completion() {
+        assert_eq!(window_budget("gpt-4", None), 8_192 - 1_024);
+        assert_eq!(window_budget("gpt-4o", Some(4_000)), 128_000 - 4_000);
+        // Unknown models get the conservative default
+        assert_eq!(window_budget("my-deployment", None), 4_096 - 1_024);
+        // A huge max_tokens still leaves half the window for the prompt
+        assert_eq!(window_budget("gpt-4", Some(8_000)), 4_096);
+    }
+}
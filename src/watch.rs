@@ -0,0 +1,111 @@
+use crate::data;
+use log::{debug, error};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Quiet period after the last change before a reload, so partial writes settle
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+// Watches the knowledge sources and reports when they changed on disk (--watch-knowledge)
+pub struct KnowledgeWatcher {
+    _watcher: RecommendedWatcher,
+    last_change: Arc<Mutex<Option<Instant>>>,
+}
+
+impl KnowledgeWatcher {
+    // Function to start watching the given knowledge files or directories; files are
+    // watched through their parent directory so editors that replace the file are seen
+    pub fn new(sources: &[String]) -> notify::Result<Self> {
+        // URL sources have nothing on disk to watch
+        let targets: Vec<PathBuf> = sources
+            .iter()
+            .filter(|source| !data::is_url(source))
+            .map(|source| Path::new(source).canonicalize())
+            .collect::<Result<_, _>>()?;
+
+        let last_change = Arc::new(Mutex::new(None));
+        let handler_last_change = last_change.clone();
+        let handler_targets = targets.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+                Ok(event) => {
+                    let relevant = matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    ) && event.paths.iter().any(|path| {
+                        handler_targets
+                            .iter()
+                            .any(|target| path == target || path.parent() == Some(target))
+                    });
+                    if relevant {
+                        debug!("Knowledge changed: {:?}", event.paths);
+                        *handler_last_change.lock().unwrap() = Some(Instant::now());
+                    }
+                }
+                Err(e) => error!("Knowledge watch error: {}", e),
+            })?;
+
+        for target in &targets {
+            let watched = if target.is_dir() {
+                target.as_path()
+            } else {
+                target.parent().unwrap_or(target)
+            };
+            watcher.watch(watched, RecursiveMode::NonRecursive)?;
+            debug!("Watching {} for knowledge changes", watched.display());
+        }
+
+        Ok(KnowledgeWatcher {
+            _watcher: watcher,
+            last_change,
+        })
+    }
+
+    // Function to check for a change, waiting out the debounce window if the last
+    // change is very recent; returns true once per burst of changes
+    pub fn take_change(&self) -> bool {
+        let Some(changed_at) = *self.last_change.lock().unwrap() else {
+            return false;
+        };
+        let elapsed = changed_at.elapsed();
+        if elapsed < DEBOUNCE {
+            std::thread::sleep(DEBOUNCE - elapsed);
+            return self.take_change();
+        }
+        self.last_change.lock().unwrap().take().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn modifying_a_watched_file_is_reported_once() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("pii.json");
+        fs::write(&path, r#"["email"]"#).unwrap();
+        let source = path.to_string_lossy().to_string();
+        let watcher = KnowledgeWatcher::new(std::slice::from_ref(&source)).unwrap();
+        assert!(!watcher.take_change());
+
+        fs::write(&path, r#"["email", "phone"]"#).unwrap();
+
+        // Events arrive on the watcher's own thread, so give them a moment
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut changed = false;
+        while !changed && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+            changed = watcher.take_change();
+        }
+        assert!(changed);
+        assert!(!watcher.take_change());
+
+        let reloaded = data::load_combined_knowledge(&[source], &data::FetchedUrls::new()).unwrap();
+        assert!(reloaded.text.contains("phone"));
+    }
+}
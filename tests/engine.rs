@@ -0,0 +1,97 @@
+use aichat_cli::llm::LlmBackend;
+use aichat_cli::{ChatEngine, EngineConfig};
+use async_trait::async_trait;
+use futures::stream::{self, Stream};
+use langchain_rust::language_models::llm::LLM;
+use langchain_rust::language_models::{GenerateResult, LLMError};
+use langchain_rust::schemas::{Message, MessageType, StreamData};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+// Stub LLM answering every prompt with a fixed text and keeping the messages it was sent
+#[derive(Clone, Default)]
+struct StubLlm {
+    received: Arc<Mutex<Vec<Message>>>,
+}
+
+#[async_trait]
+impl LLM for StubLlm {
+    async fn generate(&self, messages: &[Message]) -> Result<GenerateResult, LLMError> {
+        *self.received.lock().unwrap() = messages.to_vec();
+        Ok(GenerateResult {
+            tokens: None,
+            generation: "stub answer".to_string(),
+        })
+    }
+
+    async fn stream(
+        &self,
+        _messages: &[Message],
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamData, LLMError>> + Send>>, LLMError> {
+        Ok(Box::pin(stream::empty()))
+    }
+}
+
+#[tokio::test]
+async fn ask_drives_the_backend_and_records_the_turn() {
+    let stub = StubLlm::default();
+    let mut config = EngineConfig::from_env();
+    config.cache = None;
+    config.system_prompt = "You are a test assistant.".to_string();
+    let mut engine =
+        ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(stub.clone()))).unwrap();
+
+    let answer = engine.ask("hello").await.unwrap();
+
+    assert_eq!(answer, "stub answer");
+    let received = stub.received.lock().unwrap().clone();
+    assert!(matches!(
+        received.first().map(|m| &m.message_type),
+        Some(MessageType::SystemMessage)
+    ));
+    assert_eq!(
+        received.first().unwrap().content,
+        "You are a test assistant."
+    );
+    assert_eq!(received.last().unwrap().content, "hello");
+
+    let history: Vec<_> = engine
+        .history_list
+        .iter()
+        .map(|m| (m.message_type.clone(), m.content.as_str()))
+        .collect();
+    assert_eq!(history.len(), 2);
+    assert!(matches!(history[0].0, MessageType::HumanMessage));
+    assert_eq!(history[0].1, "hello");
+    assert!(matches!(history[1].0, MessageType::AIMessage));
+    assert_eq!(history[1].1, "stub answer");
+}
+
+// Function to reduce messages to their role and text, for comparing prompts
+fn rendered(messages: &[Message]) -> Vec<(String, String)> {
+    messages
+        .iter()
+        .map(|m| (format!("{:?}", m.message_type), m.content.clone()))
+        .collect()
+}
+
+#[tokio::test]
+async fn the_dry_run_prompt_is_the_prompt_sent() {
+    let stub = StubLlm::default();
+    let mut config = EngineConfig::from_env();
+    config.cache = None;
+    let mut engine =
+        ChatEngine::with_backend(config, LlmBackend::Custom(Box::new(stub.clone()))).unwrap();
+    engine.ask("first question").await.unwrap();
+
+    let dry_run = engine.build_messages("second question");
+    engine.ask("second question").await.unwrap();
+
+    let received = stub.received.lock().unwrap().clone();
+    assert_eq!(rendered(&received), rendered(&dry_run));
+    let asked = received
+        .iter()
+        .filter(|m| m.content == "second question")
+        .count();
+    assert_eq!(asked, 1);
+}
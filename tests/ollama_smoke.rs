@@ -0,0 +1,22 @@
+// Smoke test against a real local Ollama server; run with
+// `cargo test --features ollama-smoke --test ollama_smoke` after `ollama pull <model>`
+#![cfg(feature = "ollama-smoke")]
+
+use aichat_cli::llm::{self, LlmBackend, SamplingOptions};
+use aichat_cli::{ChatEngine, EngineConfig};
+
+#[tokio::test]
+async fn ollama_answers_a_prompt() {
+    let model = std::env::var("OLLAMA_MODEL").unwrap_or_else(|_| "llama3".to_string());
+    let ollama = llm::create_ollama(&model, SamplingOptions::default())
+        .await
+        .expect("Ollama should be reachable at OLLAMA_HOST");
+    let mut config = EngineConfig::from_env();
+    config.cache = None;
+    let mut engine = ChatEngine::with_backend(config, LlmBackend::OpenAI(ollama)).unwrap();
+
+    let answer = engine.ask("Reply with the single word: pong").await.unwrap();
+
+    assert!(!answer.trim().is_empty());
+    assert_eq!(engine.history_list.len(), 2);
+}
@@ -0,0 +1,53 @@
+// End-to-end test of POST /chat against the mock backend; run with
+// `cargo test --features server --test server`
+#![cfg(feature = "server")]
+
+use aichat_cli::server::router;
+use aichat_cli::EngineConfig;
+
+// Function to send one chat request and collect the SSE body
+async fn post_chat(addr: std::net::SocketAddr, session_id: &str, input: &str) -> String {
+    reqwest::Client::new()
+        .post(format!("http://{}/chat", addr))
+        .header("content-type", "application/json")
+        .header("x-session-id", session_id)
+        .body(serde_json::json!({ "input": input }).to_string())
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap()
+        .text()
+        .await
+        .unwrap()
+}
+
+// Function to join the data lines of an SSE body back into the answer
+fn answer_of(body: &str) -> String {
+    body.lines()
+        .filter_map(|line| line.strip_prefix("data: ").or(line.strip_prefix("data:")))
+        .collect()
+}
+
+#[test]
+fn chat_streams_the_mock_answer_as_events() {
+    // Like main, the environment is settled before the runtime starts
+    std::env::set_var("LLM_PROVIDER", "mock");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut config = EngineConfig::from_env();
+        config.cache = None;
+        tokio::spawn(async move { axum::serve(listener, router(config)).await.unwrap() });
+
+        let body = post_chat(addr, "test", "hello").await;
+        assert_eq!(answer_of(&body), "Mock answer: hello");
+        assert!(body.contains("event: done"));
+
+        // A second request in the same session is answered too
+        let body = post_chat(addr, "test", "again").await;
+        assert_eq!(answer_of(&body), "Mock answer: again");
+    });
+}